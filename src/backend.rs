@@ -0,0 +1,78 @@
+use crate::theme::ColorDepth;
+#[cfg(test)]
+use std::cell::Cell;
+use std::io::stdout;
+use termion::input::MouseTerminal;
+use termion::raw::{IntoRawMode, RawTerminal};
+
+/// Fallback dimensions used when the terminal size cannot be queried
+const DEFAULT_SIZE: (usize, usize) = (80, 24);
+
+/// A backend supplies the view with the dimensions of the surface it draws on.
+pub trait Backend {
+    /// Returns the current size of the drawing surface as (columns, rows)
+    fn size(&self) -> (usize, usize);
+
+    /// Returns how many colors the surface can display
+    fn color_depth(&self) -> ColorDepth;
+}
+
+/// Backend for a real terminal. Puts the terminal into raw mode with mouse
+/// reporting enabled for as long as it lives.
+pub struct TermionBackend {
+    _stdout: MouseTerminal<RawTerminal<std::io::Stdout>>,
+}
+
+impl TermionBackend {
+    pub fn new() -> TermionBackend {
+        TermionBackend {
+            _stdout: MouseTerminal::from(stdout().into_raw_mode().unwrap()),
+        }
+    }
+}
+
+impl Backend for TermionBackend {
+    fn size(&self) -> (usize, usize) {
+        match termion::terminal_size() {
+            Ok((cols, rows)) => (cols as usize, rows as usize),
+            Err(_) => DEFAULT_SIZE,
+        }
+    }
+
+    fn color_depth(&self) -> ColorDepth {
+        ColorDepth::detect()
+    }
+}
+
+/// Backend with no terminal attached. Its size is set by hand, which lets
+/// tests simulate the terminal being resized.
+#[cfg(test)]
+pub struct HeadlessBackend {
+    size: Cell<(usize, usize)>,
+}
+
+#[cfg(test)]
+impl HeadlessBackend {
+    pub fn new(cols: usize, rows: usize) -> HeadlessBackend {
+        HeadlessBackend {
+            size: Cell::new((cols, rows)),
+        }
+    }
+
+    /// Changes the reported size. The view picks it up on its next `update_size`,
+    /// just as it would after a SIGWINCH on a real terminal.
+    pub fn resize(&self, cols: usize, rows: usize) {
+        self.size.set((cols, rows));
+    }
+}
+
+#[cfg(test)]
+impl Backend for HeadlessBackend {
+    fn size(&self) -> (usize, usize) {
+        self.size.get()
+    }
+
+    fn color_depth(&self) -> ColorDepth {
+        ColorDepth::TrueColor
+    }
+}
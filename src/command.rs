@@ -1,5 +1,6 @@
 use crate::model::Model;
 
+#[derive(Debug, PartialEq)]
 pub enum Command {
     InsertNewline {
         location: (usize, usize)
@@ -147,6 +148,17 @@ impl CommandState {
         self.change_count += 1;
     }
 
+    /// Makes the last two undo steps one, for a change that has to see the
+    /// result of its first part before it can make the rest
+    pub fn join_last_steps(&mut self) {
+        if self.undo_steps.len() < 2 {
+            return;
+        }
+        let last = self.undo_steps.pop().unwrap();
+        *self.undo_steps.last_mut().unwrap() += last;
+        self.change_count -= 1;
+    }
+
     
     fn try_merge_char_cmd(&mut self, new_c: char, new_location: (usize, usize), model: &mut Model) -> bool {
         if !new_c.is_alphabetic() {
@@ -0,0 +1,281 @@
+use crate::command::Command;
+use crate::indent::leading_whitespace;
+use serde::{Deserialize, Serialize};
+
+/// How a language writes comments: the token starting a comment that runs to
+/// the end of the line, and the tokens opening and closing a block comment
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommentTokens {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<(String, String)>,
+}
+
+/// Commands commenting out `rows`, the first of which is row `first`, or
+/// uncommenting them when every non-blank one is commented already. Line
+/// comments are used where the language has them, and otherwise each row is
+/// wrapped in a block comment of its own. New tokens line up at the
+/// indentation the rows share, and blank rows are left alone. Returns None
+/// if the language has no comments.
+pub fn toggle_lines(rows: &[&str], first: usize, tokens: &CommentTokens) -> Option<Vec<Command>> {
+    let (open, close) = match (&tokens.line, &tokens.block) {
+        (Some(line), _) => (line.as_str(), None),
+        (None, Some((open, close))) => (open.as_str(), Some(close.as_str())),
+        (None, None) => return None,
+    };
+    let nonblank: Vec<(usize, &str)> = rows
+        .iter()
+        .enumerate()
+        .filter(|(_, row)| !row.trim().is_empty())
+        .map(|(idx, row)| (first + idx, *row))
+        .collect();
+    let column = shared_indent(&nonblank);
+
+    let mut cmds = vec![];
+    if nonblank
+        .iter()
+        .all(|(_, row)| is_commented(row, open, close))
+    {
+        for (y, row) in nonblank {
+            cmds.extend(uncomment((row, y), (row, y), open, close));
+        }
+    } else {
+        for (y, row) in nonblank {
+            cmds.extend(comment((row, y), (row, y), column, open, close));
+        }
+    }
+    Some(cmds)
+}
+
+/// Commands wrapping `rows` in a single block comment, opening at the
+/// indentation the rows share, or taking away the block comment they are
+/// wrapped in. Returns None if the language has no block comments.
+pub fn toggle_block(rows: &[&str], first: usize, tokens: &CommentTokens) -> Option<Vec<Command>> {
+    let (open, close) = tokens.block.as_ref()?;
+    let nonblank: Vec<(usize, &str)> = rows
+        .iter()
+        .enumerate()
+        .filter(|(_, row)| !row.trim().is_empty())
+        .map(|(idx, row)| (first + idx, *row))
+        .collect();
+    let (start, end) = match (nonblank.first(), nonblank.last()) {
+        (Some(start), Some(end)) => (*start, *end),
+        _ => return Some(vec![]),
+    };
+    let column = shared_indent(&nonblank);
+
+    let wrapped = match start.0 == end.0 {
+        true => is_commented(start.1, open, Some(close)),
+        false => {
+            start.1.trim_start().starts_with(open.as_str())
+                && end.1.trim_end().ends_with(close.as_str())
+        }
+    };
+    let (start, end) = ((start.1, start.0), (end.1, end.0));
+    match wrapped {
+        true => Some(uncomment(start, end, open, Some(close))),
+        false => Some(comment(start, end, column, open, Some(close))),
+    }
+}
+
+/// Length of the indentation every row starts with. Where some rows are
+/// indented with tabs and others with spaces this is only what they have in
+/// common, so that the tokens still line up.
+fn shared_indent(rows: &[(usize, &str)]) -> usize {
+    let mut indents = rows.iter().map(|(_, row)| leading_whitespace(row));
+    let first = indents.next().unwrap_or_default();
+    indents.fold(first.len(), |len, indent| {
+        first
+            .bytes()
+            .zip(indent.bytes())
+            .take(len)
+            .take_while(|(a, b)| a == b)
+            .count()
+    })
+}
+
+fn is_commented(row: &str, open: &str, close: Option<&str>) -> bool {
+    let text = row.trim();
+    text.starts_with(open)
+        && close.is_none_or(|close| text.len() >= open.len() + close.len() && text.ends_with(close))
+}
+
+/// Commands putting `open` and a space at `column` of the `start` row, and a
+/// space and `close` at the end of the `end` row. Rows are given as their
+/// text and index. The end goes first so that the column of the start stays
+/// put when both are on the same row.
+fn comment(
+    start: (&str, usize),
+    end: (&str, usize),
+    column: usize,
+    open: &str,
+    close: Option<&str>,
+) -> Vec<Command> {
+    let mut cmds = vec![];
+    if let Some(close) = close {
+        cmds.push(Command::InsertString {
+            location: (end.0.len(), end.1),
+            contents: format!(" {}", close),
+        });
+    }
+    cmds.push(Command::InsertString {
+        location: (column, start.1),
+        contents: format!("{} ", open),
+    });
+    cmds
+}
+
+/// Commands taking away the `open` token the text of the `start` row starts
+/// with and the `close` token the `end` row ends with, along with the space
+/// between each token and the text
+fn uncomment(
+    start: (&str, usize),
+    end: (&str, usize),
+    open: &str,
+    close: Option<&str>,
+) -> Vec<Command> {
+    let open_start = leading_whitespace(start.0).len();
+    let mut open_end = open_start + open.len();
+    if start.0[open_end..].starts_with(' ') {
+        open_end += 1;
+    }
+
+    let mut cmds = vec![];
+    if let Some(close) = close {
+        let close_end = end.0.trim_end().len();
+        let mut close_start = close_end - close.len();
+        // On a single row, the space may already go with the open token
+        let limit = if start.1 == end.1 { open_end } else { 0 };
+        if close_start > limit && end.0[..close_start].ends_with(' ') {
+            close_start -= 1;
+        }
+        cmds.push(Command::DeleteString {
+            start: (close_start, end.1),
+            end: (close_end, end.1),
+        });
+    }
+    cmds.push(Command::DeleteString {
+        start: (open_start, start.1),
+        end: (open_end, start.1),
+    });
+    cmds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: Option<&str>, block: Option<(&str, &str)>) -> CommentTokens {
+        CommentTokens {
+            line: line.map(String::from),
+            block: block.map(|(open, close)| (open.to_string(), close.to_string())),
+        }
+    }
+
+    fn c() -> CommentTokens {
+        tokens(Some("//"), Some(("/*", "*/")))
+    }
+
+    /// Runs the commands on rows numbered from 0, as the commands only ever
+    /// change text within a row
+    fn apply(rows: &[&str], cmds: Vec<Command>) -> Vec<String> {
+        let mut rows: Vec<String> = rows.iter().map(|row| row.to_string()).collect();
+        for cmd in cmds {
+            match cmd {
+                Command::InsertString { location: (x, y), contents } => {
+                    rows[y].insert_str(x, &contents)
+                }
+                Command::DeleteString { start: (x, y), end: (end, _) } => {
+                    rows[y].replace_range(x..end, "")
+                }
+                _ => unreachable!(),
+            }
+        }
+        rows
+    }
+
+    /// Toggles the rows with `toggle`, checks the result, and checks that
+    /// toggling again brings the rows back
+    fn round_trip<F>(rows: &[&str], expected: &[&str], toggle: F)
+    where
+        F: Fn(&[&str]) -> Option<Vec<Command>>,
+    {
+        let toggled = apply(rows, toggle(rows).unwrap());
+        assert_eq!(toggled, expected);
+        let toggled: Vec<&str> = toggled.iter().map(String::as_str).collect();
+        assert_eq!(apply(&toggled, toggle(&toggled).unwrap()), rows);
+    }
+
+    #[test]
+    fn lines_are_commented_from_their_row_on() {
+        let cmds = toggle_lines(&["  a", "    b"], 3, &c()).unwrap();
+        let expected = vec![
+            Command::InsertString { location: (2, 3), contents: String::from("// ") },
+            Command::InsertString { location: (2, 4), contents: String::from("// ") },
+        ];
+        assert_eq!(cmds, expected);
+        let cmds = toggle_lines(&["  // a"], 7, &c()).unwrap();
+        assert_eq!(cmds, vec![Command::DeleteString { start: (2, 7), end: (5, 7) }]);
+    }
+
+    #[test]
+    fn line_comments_round_trip() {
+        let toggle = |rows: &[&str]| toggle_lines(rows, 0, &c());
+        round_trip(&["if x {", "    y();", "}"], &["// if x {", "//     y();", "// }"], toggle);
+        round_trip(&["    a", "      b"], &["    // a", "    //   b"], toggle);
+    }
+
+    #[test]
+    fn mixed_indentation_stays_lined_up() {
+        let toggle = |rows: &[&str]| toggle_lines(rows, 0, &c());
+        round_trip(&["\t\ta", "\t    b"], &["\t// \ta", "\t//     b"], toggle);
+        round_trip(&["    a", "\tb"], &["//     a", "// \tb"], toggle);
+    }
+
+    #[test]
+    fn blank_lines_are_left_alone() {
+        let toggle = |rows: &[&str]| toggle_lines(rows, 0, &c());
+        round_trip(&["  a", "", "  ", "  b"], &["  // a", "", "  ", "  // b"], toggle);
+        assert_eq!(toggle_lines(&["", " "], 0, &c()).map(|cmds| cmds.len()), Some(0));
+    }
+
+    #[test]
+    fn rows_not_all_commented_get_commented() {
+        let rows = ["// a", "b"];
+        let toggled = apply(&rows, toggle_lines(&rows, 0, &c()).unwrap());
+        assert_eq!(toggled, vec!["// // a", "// b"]);
+        // A comment token without a space after it still counts
+        let rows = ["//a", "  //b"];
+        assert_eq!(apply(&rows, toggle_lines(&rows, 0, &c()).unwrap()), vec!["a", "  b"]);
+    }
+
+    #[test]
+    fn languages_with_only_block_comments_wrap_each_line() {
+        let css = tokens(None, Some(("/*", "*/")));
+        let toggle = |rows: &[&str]| toggle_lines(rows, 0, &css);
+        let commented = ["/* a { */", "/*   color: red; */", "/* } */"];
+        round_trip(&["a {", "  color: red;", "}"], &commented, toggle);
+        // A row that only starts a block comment isn't commented
+        let rows = ["/* a", "b */"];
+        assert_eq!(apply(&rows, toggle(&rows).unwrap()), vec!["/* /* a */", "/* b */ */"]);
+    }
+
+    #[test]
+    fn block_comments_wrap_every_row_in_one() {
+        let toggle = |rows: &[&str]| toggle_block(rows, 0, &c());
+        round_trip(&["  a", "", "    b", ""], &["  /* a", "", "    b */", ""], toggle);
+        round_trip(&["\tx = 1;"], &["\t/* x = 1; */"], toggle);
+        round_trip(&["/* a", "b"], &["/* /* a", "b */"], toggle);
+        assert_eq!(toggle_block(&["", ""], 0, &c()).map(|cmds| cmds.len()), Some(0));
+    }
+
+    #[test]
+    fn languages_without_comments() {
+        assert_eq!(toggle_lines(&["a"], 0, &tokens(None, None)), None);
+        assert_eq!(toggle_block(&["a"], 0, &tokens(Some("#"), None)), None);
+        let shell = tokens(Some("#"), None);
+        round_trip(&["echo"], &["# echo"], |rows| toggle_lines(rows, 0, &shell));
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+
+/// Words offered in insert mode to complete the word before the cursor. They
+/// only come from the current buffer, as the editor has no other buffers open.
+pub struct Completion {
+    // Where the word being completed starts, as (x, y)
+    pub start: (usize, usize),
+    // Words that go on from what has been typed, closest to the cursor first
+    pub matches: Vec<String>,
+    pub selected: usize,
+    // Every word offered when completion started, which the matches are
+    // narrowed down from as typing goes on
+    words: Vec<String>,
+}
+
+impl Completion {
+    pub fn new(start: (usize, usize), words: Vec<String>) -> Completion {
+        Completion {
+            start,
+            matches: vec![],
+            selected: 0,
+            words,
+        }
+    }
+
+    /// Keeps the words that start with `typed` and are longer than it. The
+    /// selected word stays selected if it still matches.
+    pub fn filter(&mut self, typed: &str) {
+        let selected = self.matches.get(self.selected).cloned();
+        self.matches = self
+            .words
+            .iter()
+            .filter(|word| word.len() > typed.len() && word.starts_with(typed))
+            .cloned()
+            .collect();
+        self.selected = selected
+            .and_then(|selected| self.matches.iter().position(|word| *word == selected))
+            .unwrap_or(0);
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.matches.is_empty() {
+            self.selected = (self.selected + 1) % self.matches.len();
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        if !self.matches.is_empty() {
+            self.selected = (self.selected + self.matches.len() - 1) % self.matches.len();
+        }
+    }
+
+    pub fn selected_word(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(String::as_str)
+    }
+}
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Byte index of the start of the word that ends at byte `x` of a row
+pub fn word_start(row: &str, x: usize) -> usize {
+    row[..x]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word_char(*c))
+        .last()
+        .map_or(x, |(idx, _)| idx)
+}
+
+/// Every word of `rows` once, ordered by how close it is to the cursor at
+/// `(x, y)`. Rows nearer the cursor row come first, the row above before the
+/// row below, and words on the cursor row are ordered by their distance from
+/// the cursor. The word the cursor is in is left out.
+pub fn words_near(rows: &[&str], (x, y): (usize, usize)) -> Vec<String> {
+    let mut order = vec![];
+    if y < rows.len() {
+        let row = rows[y];
+        let current = word_start(row, x.min(row.len()));
+        let mut words: Vec<(usize, &str)> = words_of(row)
+            .into_iter()
+            .filter(|(start, _)| *start != current)
+            .map(|(start, word)| {
+                (
+                    start.abs_diff(x).min((start + word.len()).abs_diff(x)),
+                    word,
+                )
+            })
+            .collect();
+        words.sort_by_key(|(distance, _)| *distance);
+        order.extend(words.into_iter().map(|(_, word)| word));
+    }
+    for distance in 1..=y.max(rows.len().saturating_sub(y)) {
+        if let Some(above) = y.checked_sub(distance) {
+            order.extend(words_of(rows[above]).into_iter().map(|(_, word)| word));
+        }
+        if let Some(below) = rows.get(y + distance) {
+            order.extend(words_of(below).into_iter().map(|(_, word)| word));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    order
+        .into_iter()
+        .filter(|word| seen.insert(*word))
+        .map(String::from)
+        .collect()
+}
+
+/// The words of a row with the byte index each one starts at
+fn words_of(row: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    let end = std::iter::once((row.len(), ' '));
+    for (idx, c) in row.char_indices().chain(end) {
+        match (is_word_char(c), start) {
+            (true, None) => start = Some(idx),
+            (false, Some(from)) => {
+                words.push((from, &row[from..idx]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
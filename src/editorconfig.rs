@@ -0,0 +1,354 @@
+use crate::options::{absolute_path, Charset, LineEnding, OptionSet};
+use fancy_regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const EDITORCONFIG_FILE: &str = ".editorconfig";
+
+/// Finds the `.editorconfig` files that apply to `filename` and turns the
+/// properties of their matching sections into options. Files are read from
+/// the file's own directory upwards until one declares `root = true`. Closer
+/// files win over ones further up, and later sections over earlier ones.
+pub fn load(filename: &Path) -> io::Result<OptionSet> {
+    let path = match absolute_path(filename) {
+        Some(path) => path,
+        None => return Ok(OptionSet::default()),
+    };
+    let path_str = path.to_string_lossy().replace('\\', "/");
+
+    // Files nearest to the root come first so that closer ones override them
+    let mut files = vec![];
+    for dir in path.ancestors().skip(1) {
+        let config = dir.join(EDITORCONFIG_FILE);
+        if !config.is_file() {
+            continue;
+        }
+        let text = fs::read_to_string(&config)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", config.display(), err)))?;
+        let parsed = parse(&text);
+        let root = parsed.root;
+        files.push((dir.to_string_lossy().replace('\\', "/"), parsed));
+        if root {
+            break;
+        }
+    }
+
+    let mut properties = HashMap::new();
+    for (dir, file) in files.iter().rev() {
+        for (glob, section) in file.sections.iter() {
+            if !glob_matches(dir, glob, &path_str) {
+                continue;
+            }
+            for (key, value) in section.iter() {
+                properties.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(options_for(&properties))
+}
+
+struct EditorConfigFile {
+    root: bool,
+    // Section globs with their properties, in file order
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+/// Parses the INI format of an `.editorconfig` file. Lines that don't parse
+/// are skipped, as the format asks for.
+fn parse(text: &str) -> EditorConfigFile {
+    let mut file = EditorConfigFile {
+        root: false,
+        sections: vec![],
+    };
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(glob) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            file.sections.push((glob.to_string(), vec![]));
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), value.trim().to_lowercase()),
+            None => continue,
+        };
+        match file.sections.last_mut() {
+            Some((_, properties)) => properties.push((key, value)),
+            // Only `root` is allowed before the first section
+            None if key == "root" => file.root = value == "true",
+            None => {}
+        }
+    }
+    file
+}
+
+/// Whether a section glob from the `.editorconfig` in `dir` matches `path`.
+/// Globs without a slash match the file name in any directory below `dir`.
+fn glob_matches(dir: &str, glob: &str, path: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    let pattern = match glob.strip_prefix('/') {
+        Some(anchored) => format!("^{}/{}$", escape(dir), glob_to_regex(anchored)),
+        None if glob.contains('/') => format!("^{}/{}$", escape(dir), glob_to_regex(glob)),
+        None => format!("^{}/(?:.*/)?{}$", escape(dir), glob_to_regex(glob)),
+    };
+    Regex::new(&pattern)
+        .ok()
+        .and_then(|re| re.is_match(path).ok())
+        .unwrap_or(false)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Translates editorconfig's glob syntax: `*`, `**`, `?`, `[abc]`, `[!abc]`,
+/// `{a,b}` and `{1..10}`
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::new();
+    let mut braces = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                out.push_str(".*");
+                i += 1;
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+                Some(len) => {
+                    let class: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(negated) => format!("^{}", negated),
+                        None => class,
+                    };
+                    out.push_str(&format!("[{}]", class.replace('\\', "\\\\")));
+                    i += len + 1;
+                }
+                None => out.push_str("\\["),
+            },
+            '{' => {
+                let close = chars[i + 1..].iter().position(|c| *c == '}');
+                let inner: Option<String> =
+                    close.map(|len| chars[i + 1..i + 1 + len].iter().collect());
+                match inner.as_deref().and_then(number_range) {
+                    Some(range) => {
+                        out.push_str(&range);
+                        i += close.unwrap_or(0) + 1;
+                    }
+                    None if inner.as_deref().is_some_and(|inner| inner.contains(',')) => {
+                        braces += 1;
+                        out.push_str("(?:");
+                    }
+                    None => out.push_str("\\{"),
+                }
+            }
+            ',' if braces > 0 => out.push('|'),
+            '}' if braces > 0 => {
+                braces -= 1;
+                out.push(')');
+            }
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                out.push_str(&escape(&chars[i].to_string()));
+            }
+            c => out.push_str(&escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// A `{start..end}` range as an alternation of its numbers, for small ranges
+fn number_range(inner: &str) -> Option<String> {
+    let (start, end) = inner.split_once("..")?;
+    let (start, end): (i64, i64) = (start.parse().ok()?, end.parse().ok()?);
+    let (start, end) = (start.min(end), start.max(end));
+    if end - start > 1000 {
+        return Some(String::from("[+-]?\\d+"));
+    }
+    let numbers: Vec<String> = (start..=end).map(|n| n.to_string()).collect();
+    Some(format!("(?:{})", numbers.join("|")))
+}
+
+/// Turns editorconfig properties into options. Unknown properties and
+/// values, and properties set to `unset`, are ignored.
+fn options_for(properties: &HashMap<String, String>) -> OptionSet {
+    let get = |key: &str| {
+        properties
+            .get(key)
+            .map(String::as_str)
+            .filter(|v| *v != "unset")
+    };
+    let number = |key: &str| {
+        get(key)
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+    };
+    let boolean = |key: &str| match get(key) {
+        Some("true") => Some(true),
+        Some("false") => Some(false),
+        _ => None,
+    };
+
+    OptionSet {
+        expandtab: match get("indent_style") {
+            Some("space") => Some(true),
+            Some("tab") => Some(false),
+            _ => None,
+        },
+        // An indent_size of "tab" means indenting by tab_width
+        tab_width: number("indent_size").or_else(|| number("tab_width")),
+        end_of_line: get("end_of_line").and_then(LineEnding::from_name),
+        charset: get("charset").and_then(Charset::from_name),
+        trim_trailing_whitespace: boolean("trim_trailing_whitespace"),
+        insert_final_newline: boolean("insert_final_newline"),
+        max_line_length: match get("max_line_length") {
+            Some("off") => Some(0),
+            _ => number("max_line_length"),
+        },
+        ..OptionSet::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_matches("/project", glob, path)
+    }
+
+    #[test]
+    fn stars_stay_within_a_directory_and_double_stars_dont() {
+        assert!(matches("*", "/project/a.c"));
+        assert!(matches("*", "/project/src/deep/a.c"));
+        assert!(matches("*.c", "/project/src/a.c"));
+        assert!(!matches("*.c", "/project/a.cc"));
+        assert!(matches("src/*.c", "/project/src/a.c"));
+        assert!(!matches("src/*.c", "/project/src/x/a.c"));
+        assert!(matches("src/**.c", "/project/src/x/a.c"));
+        assert!(matches("**/test/*", "/project/a/b/test/t.rs"));
+        assert!(!matches("*", "/elsewhere/a.c"));
+    }
+
+    #[test]
+    fn alternatives_classes_and_ranges() {
+        assert!(matches("*.{c,h}", "/project/a.h"));
+        assert!(matches("*.{c,h}", "/project/a.c"));
+        assert!(!matches("*.{c,h}", "/project/a.cc"));
+        assert!(matches("{Makefile,*.mk}", "/project/rules.mk"));
+        assert!(matches("file[0-9].txt", "/project/file3.txt"));
+        assert!(!matches("file[!0-9].txt", "/project/file3.txt"));
+        assert!(matches("v{1..12}.txt", "/project/v12.txt"));
+        assert!(!matches("v{1..12}.txt", "/project/v13.txt"));
+        assert!(matches("{single}.txt", "/project/{single}.txt"));
+        assert!(matches("a+b?.txt", "/project/a+bc.txt"));
+    }
+
+    #[test]
+    fn a_leading_slash_anchors_to_the_directory() {
+        assert!(matches("/top.c", "/project/top.c"));
+        assert!(!matches("/top.c", "/project/src/top.c"));
+        assert!(matches("top.c", "/project/src/top.c"));
+    }
+
+    #[test]
+    fn parsing_skips_what_it_doesnt_understand() {
+        let file = parse("root = TRUE\n; comment\n[*.c]\nIndent_Size = 4\nnonsense\n# more\n[*]\n");
+        assert!(file.root);
+        let sections: Vec<_> = file
+            .sections
+            .iter()
+            .map(|(glob, properties)| (glob.as_str(), properties.len()))
+            .collect();
+        assert_eq!(sections, vec![("*.c", 1), ("*", 0)]);
+        assert_eq!(file.sections[0].1[0], (String::from("indent_size"), String::from("4")));
+        assert!(!parse("[*]\nroot = true\n").root);
+    }
+
+    /// A directory of its own to lay out `.editorconfig` files in
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str) -> Tree {
+            let dir = format!("ginkgo-editorconfig-{}-{}", std::process::id(), name);
+            let dir = std::env::temp_dir().join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            Tree(dir)
+        }
+
+        fn write(&self, path: &str, text: &str) {
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+
+        fn load(&self, path: &str) -> OptionSet {
+            load(&self.0.join(path)).unwrap()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn nearer_files_and_later_sections_win() {
+        let tree = Tree::new("nearer");
+        tree.write(
+            ".editorconfig",
+            "root = true\n[*]\nindent_style = space\nindent_size = 2\n[*.c]\nindent_size = 8\n",
+        );
+        tree.write("sub/.editorconfig", "[*.c]\nindent_style = tab\nindent_size = 3\n");
+
+        let options = tree.load("sub/x.c");
+        assert_eq!((options.expandtab, options.tab_width), (Some(false), Some(3)));
+        let options = tree.load("x.c");
+        assert_eq!((options.expandtab, options.tab_width), (Some(true), Some(8)));
+        let options = tree.load("sub/x.py");
+        assert_eq!((options.expandtab, options.tab_width), (Some(true), Some(2)));
+    }
+
+    #[test]
+    fn root_stops_the_search_upwards() {
+        let tree = Tree::new("root");
+        tree.write(".editorconfig", "[*]\nmax_line_length = 77\nindent_size = 5\n");
+        tree.write("project/.editorconfig", "root = true\n[*]\nindent_size = 4\n");
+        tree.write("other/.editorconfig", "[*]\nindent_size = 4\n");
+
+        let options = tree.load("project/a.txt");
+        assert_eq!((options.tab_width, options.max_line_length), (Some(4), None));
+        let options = tree.load("other/a.txt");
+        assert_eq!((options.tab_width, options.max_line_length), (Some(4), Some(77)));
+    }
+
+    #[test]
+    fn unset_and_unknown_values_are_ignored() {
+        let tree = Tree::new("unset");
+        tree.write(".editorconfig", "root = true\n[*]\nindent_style = space\nindent_size = 3\n");
+        tree.write(
+            "a/.editorconfig",
+            "[*]\nindent_style = unset\nindent_size = tab\ntab_width = 6\ncharset = ebcdic\n",
+        );
+
+        let options = tree.load("a/b.txt");
+        assert_eq!(options.expandtab, None);
+        assert_eq!(options.tab_width, Some(6));
+        assert!(options.charset.is_none());
+    }
+}
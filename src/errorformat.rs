@@ -0,0 +1,234 @@
+use crate::model::SignKind;
+use crate::quickfix::Entry;
+use fancy_regex::Regex;
+use std::path::{Path, PathBuf};
+
+/// Formats of the messages gcc, clang and rustc print, as in the
+/// `error_format` option
+pub const DEFAULT_FORMAT: &str = "%f:%l:%c: fatal %t: %m,\
+                                  %f:%l:%c: %t: %m,\
+                                  %f:%l: %t: %m,\
+                                  %t[%*]: %m,\
+                                  %t: %m,\
+                                  %*--> %f:%l:%c";
+
+/// Patterns for picking compiler messages out of build output, after vim's
+/// 'errorformat'. The patterns are separated by commas, and in each one
+/// `%f` stands for a file name, `%l` for a line number, `%c` for a column,
+/// `%t` for a word such as "error" or "warning" giving the kind of message,
+/// `%m` for the message, `%*` for any text and `%%` for a percent sign. A
+/// comma in a pattern is escaped with a backslash. Each line of output is
+/// matched with the first pattern that matches all of it.
+pub struct ErrorFormat {
+    patterns: Vec<Regex>,
+}
+
+impl ErrorFormat {
+    pub fn parse(format: &str) -> Result<ErrorFormat, String> {
+        let mut patterns = vec![];
+        let mut pattern = String::from("^");
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '%' => match chars.next() {
+                    Some('f') => pattern.push_str(r"(?P<file>[^:]+)"),
+                    Some('l') => pattern.push_str(r"(?P<line>\d+)"),
+                    Some('c') => pattern.push_str(r"(?P<col>\d+)"),
+                    Some('t') => pattern.push_str(r"(?P<kind>[A-Za-z]+)"),
+                    Some('m') => pattern.push_str(r"(?P<message>.*)"),
+                    Some('*') => pattern.push_str(".*?"),
+                    Some('%') => pattern.push('%'),
+                    Some(other) => return Err(format!("Unknown item in error format: %{}", other)),
+                    None => return Err(String::from("Error format ends with %")),
+                },
+                '\\' if chars.clone().next() == Some(',') => {
+                    chars.next();
+                    pattern.push(',');
+                }
+                ',' => patterns.push(Self::compile(&mut pattern)?),
+                c => pattern.push_str(&fancy_regex::escape(&c.to_string())),
+            }
+        }
+        patterns.push(Self::compile(&mut pattern)?);
+        Ok(ErrorFormat { patterns })
+    }
+
+    fn compile(pattern: &mut String) -> Result<Regex, String> {
+        pattern.push('$');
+        let regex = Regex::new(pattern).map_err(|err| format!("Invalid error format: {}", err));
+        *pattern = String::from("^");
+        regex
+    }
+}
+
+/// Reads compiler messages out of build output one line at a time.
+/// Patterns with a message but no file, like rustc's `error[E0425]: ...`,
+/// hold on to the message for a pattern with a file but no message on the
+/// line right after it, like rustc's ` --> src/main.rs:2:5`.
+pub struct Parser {
+    format: ErrorFormat,
+    // Directory the build ran in, which file names are relative to
+    dir: PathBuf,
+    // Kind and text of a message still waiting for the place it is about
+    pending: Option<(String, String)>,
+}
+
+impl Parser {
+    pub fn new(format: ErrorFormat, dir: PathBuf) -> Parser {
+        Parser {
+            format,
+            dir,
+            pending: None,
+        }
+    }
+
+    /// The message a line of output gives, if it gives a whole one
+    pub fn line(&mut self, line: &str) -> Option<Entry> {
+        let pending = self.pending.take();
+        let captures = self
+            .format
+            .patterns
+            .iter()
+            .find_map(|pattern| pattern.captures(line).ok().flatten())?;
+        let group = |name: &str| captures.name(name).map(|found| found.as_str());
+
+        let kind = group("kind").unwrap_or("error");
+        let message = group("message").map(|message| (kind.to_string(), message.to_string()));
+        let file = match group("file") {
+            Some(file) => file.trim(),
+            None => {
+                self.pending = message;
+                return None;
+            }
+        };
+        let (kind, message) = message.or(pending)?;
+        let number = |name: &str| group(name)?.parse::<usize>().ok()?.checked_sub(1);
+        Some(Entry {
+            path: self.dir.join(Path::new(file)),
+            line: number("line")?,
+            col: number("col").unwrap_or(0),
+            text: format!("{}: {}", kind, message.trim()),
+            kind: Some(sign_kind(&kind)),
+        })
+    }
+}
+
+/// Kind of sign for a message, going by the first letter of its kind
+fn sign_kind(kind: &str) -> SignKind {
+    match kind.chars().next().map(|c| c.to_ascii_lowercase()) {
+        Some('e') => SignKind::Error,
+        Some('w') => SignKind::Warning,
+        _ => SignKind::Info,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(output: &str) -> Vec<(PathBuf, usize, usize, String, SignKind)> {
+        let format = ErrorFormat::parse(DEFAULT_FORMAT).unwrap();
+        let mut parser = Parser::new(format, PathBuf::from("/project"));
+        output
+            .lines()
+            .filter_map(|line| parser.line(line))
+            .map(|entry| (entry.path, entry.line, entry.col, entry.text, entry.kind.unwrap()))
+            .collect()
+    }
+
+    fn entry(path: &str, line: usize, col: usize, text: &str, kind: SignKind) -> (PathBuf, usize, usize, String, SignKind) {
+        (PathBuf::from(path), line, col, String::from(text), kind)
+    }
+
+    #[test]
+    fn gcc() {
+        let output = "\
+src/a.c: In function 'main':
+src/a.c:3:5: error: 'x' undeclared (first use in this function)
+    3 |     x = 1;
+      |     ^
+src/a.c:2:9: warning: unused variable 'y' [-Wunused-variable]
+src/b.c:1:10: fatal error: missing.h: No such file or directory
+compilation terminated.
+";
+        assert_eq!(
+            parse(output),
+            vec![
+                entry("/project/src/a.c", 2, 4, "error: 'x' undeclared (first use in this function)", SignKind::Error),
+                entry("/project/src/a.c", 1, 8, "warning: unused variable 'y' [-Wunused-variable]", SignKind::Warning),
+                entry("/project/src/b.c", 0, 9, "error: missing.h: No such file or directory", SignKind::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn clang() {
+        let output = "\
+main.c:4:12: warning: implicit conversion loses integer precision [-Wshorten-64-to-32]
+main.c:7:3: note: previous definition is here
+main.c:9: error: expected ';' after expression
+1 warning and 1 error generated.
+";
+        assert_eq!(
+            parse(output),
+            vec![
+                entry(
+                    "/project/main.c",
+                    3,
+                    11,
+                    "warning: implicit conversion loses integer precision [-Wshorten-64-to-32]",
+                    SignKind::Warning
+                ),
+                entry("/project/main.c", 6, 2, "note: previous definition is here", SignKind::Info),
+                entry("/project/main.c", 8, 0, "error: expected ';' after expression", SignKind::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn rustc_gives_the_place_on_the_line_after_the_message() {
+        let output = "\
+   Compiling demo v0.1.0 (/project)
+error[E0425]: cannot find value `x` in this scope
+ --> src/main.rs:2:5
+  |
+2 |     x
+  |     ^ not found in this scope
+
+warning: unused variable: `y`
+   --> src/lib.rs:10:9
+error: aborting due to 1 previous error; 1 warning emitted
+";
+        assert_eq!(
+            parse(output),
+            vec![
+                entry("/project/src/main.rs", 1, 4, "error: cannot find value `x` in this scope", SignKind::Error),
+                entry("/project/src/lib.rs", 9, 8, "warning: unused variable: `y`", SignKind::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_message_only_waits_for_the_very_next_line() {
+        let output = "\
+error[E0425]: cannot find value `x` in this scope
+  |
+ --> src/main.rs:2:5
+";
+        assert_eq!(parse(output), vec![]);
+    }
+
+    #[test]
+    fn custom_formats() {
+        let format = ErrorFormat::parse(r"%f(%l\,%c) %t: %m,%%%f@%l").unwrap();
+        let mut parser = Parser::new(format, PathBuf::from("."));
+        let found = parser.line("a.cs(3,7) warning: unreachable code").unwrap();
+        assert_eq!((found.line, found.col), (2, 6));
+        assert_eq!(found.text, "warning: unreachable code");
+        // A place without a message of its own or one just before it is no message
+        assert!(parser.line("%a.txt@4").is_none());
+
+        assert_eq!(ErrorFormat::parse("%f:%x").err().as_deref(), Some("Unknown item in error format: %x"));
+        assert_eq!(ErrorFormat::parse("%f:%").err().as_deref(), Some("Error format ends with %"));
+    }
+}
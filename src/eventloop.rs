@@ -0,0 +1,226 @@
+use crate::terminalcontroller::TerminalController;
+use std::cell::RefCell;
+use std::io;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::event::Event;
+use termion::input::TermRead;
+
+/// Write end of the self-pipe used to forward SIGWINCH out of the signal handler
+static RESIZE_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    let fd = RESIZE_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // Only async-signal-safe calls are allowed here. A full pipe just means
+        // a resize is already pending, so the result can be ignored.
+        unsafe {
+            libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+/// Work to run on the main thread once a background job has finished.
+/// The job itself runs on its own thread and must not touch the editor state.
+pub type JobCompletion = Box<dyn FnOnce(&mut TerminalController) + Send>;
+
+/// Delivers completions from a background thread that works in steps, one
+/// completion for each step. Sending fails once the editor has exited.
+pub struct JobSender(Sender<EditorEvent>);
+
+impl JobSender {
+    pub fn send(&self, completion: JobCompletion) -> bool {
+        self.0.send(EditorEvent::Job(completion)).is_ok()
+    }
+}
+
+/// Named timers. Scheduling a timer that is already pending replaces it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timer {
+    StatusExpiry,
+    Autosave,
+    // Gives up waiting for the rest of a key sequence
+    KeySequence,
+}
+
+/// Everything that can wake the editor up
+pub enum EditorEvent {
+    Input(Event),
+    Resize,
+    Timer(Timer),
+    Job(JobCompletion),
+}
+
+struct ScheduledTimer {
+    timer: Timer,
+    deadline: Instant,
+    interval: Option<Duration>,
+}
+
+/// Multiplexes terminal input, signals, timers and background jobs into a
+/// single stream of `EditorEvent`s. Input and signals are read on their own
+/// threads so the main thread only ever blocks on the channel.
+pub struct EventLoop {
+    sender: Sender<EditorEvent>,
+    receiver: Receiver<EditorEvent>,
+    timers: RefCell<Vec<ScheduledTimer>>,
+}
+
+impl EventLoop {
+    pub fn new() -> io::Result<EventLoop> {
+        let (sender, receiver) = channel();
+        EventLoop::spawn_input_reader(sender.clone())?;
+        EventLoop::spawn_resize_listener(sender.clone())?;
+        Ok(EventLoop {
+            sender,
+            receiver,
+            timers: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// An event loop with no terminal attached, for tests. It only sees the
+    /// timers and jobs it is given.
+    #[cfg(test)]
+    pub fn headless() -> EventLoop {
+        let (sender, receiver) = channel();
+        EventLoop {
+            sender,
+            receiver,
+            timers: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn spawn_input_reader(sender: Sender<EditorEvent>) -> io::Result<()> {
+        let tty = termion::get_tty()?;
+        thread::spawn(move || {
+            for evt in tty.events() {
+                let evt = match evt {
+                    Ok(evt) => evt,
+                    Err(_) => return,
+                };
+                if sender.send(EditorEvent::Input(evt)).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn spawn_resize_listener(sender: Sender<EditorEvent>) -> io::Result<()> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Only the write end is non-blocking; the listener thread blocks on the read end
+            let flags = libc::fcntl(fds[1], libc::F_GETFL);
+            libc::fcntl(fds[1], libc::F_SETFL, flags | libc::O_NONBLOCK);
+            RESIZE_PIPE.store(fds[1], Ordering::Relaxed);
+            let handler = on_sigwinch as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            if libc::signal(libc::SIGWINCH, handler) == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let read_fd = fds[0];
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let n = unsafe {
+                    libc::read(read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+                };
+                if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Several queued signals collapse into a single resize
+                if n <= 0 || sender.send(EditorEvent::Resize).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Fires `timer` once after `after` has elapsed, or every `after` if `repeat` is set.
+    pub fn schedule(&self, timer: Timer, after: Duration, repeat: bool) {
+        self.cancel(timer);
+        self.timers.borrow_mut().push(ScheduledTimer {
+            timer,
+            deadline: Instant::now() + after,
+            interval: if repeat { Some(after) } else { None },
+        });
+    }
+
+    pub fn cancel(&self, timer: Timer) {
+        self.timers.borrow_mut().retain(|t| t.timer != timer);
+    }
+
+    /// Runs `work` on a background thread. The completion it returns is
+    /// delivered back to the controller as an `EditorEvent::Job`.
+    pub fn spawn_job<F>(&self, work: F)
+    where
+        F: FnOnce() -> JobCompletion + Send + 'static,
+    {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let _ = sender.send(EditorEvent::Job(work()));
+        });
+    }
+
+    /// A sender for background threads that hand back their work bit by
+    /// bit, as it is done
+    pub fn job_sender(&self) -> JobSender {
+        JobSender(self.sender.clone())
+    }
+
+    /// Pops the timer with the earliest deadline if it is due, rescheduling
+    /// repeating timers
+    fn take_due_timer(&self) -> Option<Timer> {
+        let mut timers = self.timers.borrow_mut();
+        let now = Instant::now();
+        let idx = timers
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.deadline <= now)
+            .min_by_key(|(_, t)| t.deadline)
+            .map(|(i, _)| i)?;
+
+        let timer = timers[idx].timer;
+        match timers[idx].interval {
+            Some(interval) => timers[idx].deadline = now + interval,
+            None => {
+                timers.remove(idx);
+            }
+        }
+        Some(timer)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.borrow().iter().map(|t| t.deadline).min()
+    }
+
+    /// Blocks until the next event is available
+    pub fn next_event(&self) -> io::Result<EditorEvent> {
+        loop {
+            if let Some(timer) = self.take_due_timer() {
+                return Ok(EditorEvent::Timer(timer));
+            }
+
+            let received = match self.next_deadline() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.receiver.recv_timeout(timeout) {
+                        Ok(evt) => Ok(evt),
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => Err(()),
+                    }
+                }
+                None => self.receiver.recv().map_err(|_| ()),
+            };
+
+            return received.map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+}
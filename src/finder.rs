@@ -0,0 +1,282 @@
+use crate::fuzzy;
+use crate::gitignore::Gitignore;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Files found before the walk gives up, so that a huge tree can't use up
+// all the memory
+const MAX_FILES: usize = 200_000;
+
+// Bytes of a file read for its preview
+const PREVIEW_BYTES: u64 = 64 * 1024;
+
+// How often the walk hands over what it found, and how many files it hands
+// over at most in one go
+const BATCH_INTERVAL: Duration = Duration::from_millis(50);
+const BATCH_SIZE: usize = 5000;
+
+/// The files of a project, narrowed down and ranked by a fuzzy query
+pub struct Finder {
+    pub root: PathBuf,
+    // Paths found so far, relative to the root
+    files: Vec<String>,
+    query: String,
+    // Indices into `files` of the matching paths with their scores, best
+    // match first
+    matches: Vec<(i64, usize)>,
+    pub selected: usize,
+    // Whether the walk has found every file
+    pub done: bool,
+    // Set to stop the walk early
+    cancel: Arc<AtomicBool>,
+}
+
+impl Finder {
+    pub fn new(root: PathBuf) -> Finder {
+        Finder {
+            root,
+            files: vec![],
+            query: String::new(),
+            matches: vec![],
+            selected: 0,
+            done: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Flag that stops the walk for this finder once set
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn num_matches(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// Takes in more of the files found by the walk
+    pub fn add_files(&mut self, files: Vec<String>) {
+        let first = self.files.len();
+        self.files.extend(files);
+        let selected = self.matches.get(self.selected).map(|(_, idx)| *idx);
+        let found = self.score(first..self.files.len());
+        self.matches.extend(found);
+        self.sort();
+        // Keep the same file selected while more come in
+        self.selected = selected
+            .and_then(|selected| self.matches.iter().position(|(_, idx)| *idx == selected))
+            .unwrap_or(0);
+    }
+
+    pub fn set_query(&mut self, query: &str) {
+        // Typing on only narrows the matches down
+        let narrowing = query.starts_with(&self.query);
+        self.query = query.to_string();
+        self.matches = match narrowing {
+            true => self.score(self.matches.iter().map(|(_, idx)| *idx)),
+            false => self.score(0..self.files.len()),
+        };
+        self.sort();
+        self.selected = 0;
+    }
+
+    /// The files among `candidates` that the query matches, with their scores
+    fn score(&self, candidates: impl Iterator<Item = usize>) -> Vec<(i64, usize)> {
+        match self.query.trim().is_empty() {
+            true => candidates.map(|idx| (0, idx)).collect(),
+            false => candidates
+                .filter_map(|idx| Some((fuzzy::score(&self.query, &self.files[idx])?, idx)))
+                .collect(),
+        }
+    }
+
+    /// Orders the matches by score, then shortest first. Without a query,
+    /// files keep the order they were found in.
+    fn sort(&mut self) {
+        if self.query.trim().is_empty() {
+            self.matches.sort_unstable_by_key(|(_, idx)| *idx);
+            return;
+        }
+        let files = &self.files;
+        self.matches.sort_by(|(score_a, a), (score_b, b)| {
+            score_b
+                .cmp(score_a)
+                .then(files[*a].len().cmp(&files[*b].len()))
+                .then(files[*a].cmp(&files[*b]))
+        });
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.matches.is_empty() {
+            self.selected = (self.selected + 1) % self.matches.len();
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        if !self.matches.is_empty() {
+            self.selected = (self.selected + self.matches.len() - 1) % self.matches.len();
+        }
+    }
+
+    pub fn selected_file(&self) -> Option<&str> {
+        let (_, idx) = self.matches.get(self.selected)?;
+        Some(&self.files[*idx])
+    }
+
+    /// The best `limit` matches, each with the indices of the characters
+    /// the query matched
+    pub fn top_matches(&self, limit: usize) -> Vec<(&str, Vec<usize>)> {
+        self.matches
+            .iter()
+            .take(limit)
+            .map(|(_, idx)| {
+                let file = self.files[*idx].as_str();
+                let positions = fuzzy::match_positions(&self.query, file)
+                    .map(|(_, positions)| positions)
+                    .unwrap_or_default();
+                (file, positions)
+            })
+            .collect()
+    }
+}
+
+impl Drop for Finder {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The first `max_lines` lines of a file for a preview, with tabs expanded
+/// to `tab_width` columns. Only the start of the file is read, and files
+/// that look binary aren't shown.
+pub fn preview(path: &Path, max_lines: usize, tab_width: usize) -> Vec<String> {
+    let mut bytes = vec![];
+    let read = File::open(path).and_then(|file| file.take(PREVIEW_BYTES).read_to_end(&mut bytes));
+    if let Err(err) = read {
+        return vec![err.to_string()];
+    }
+    if bytes.contains(&0) {
+        return vec![String::from("(binary file)")];
+    }
+    String::from_utf8_lossy(&bytes)
+        .lines()
+        .take(max_lines)
+        .map(|line| {
+            let mut expanded = String::new();
+            for c in line.chars() {
+                match c {
+                    '\t' => {
+                        let width = expanded.chars().count();
+                        expanded.push_str(&" ".repeat(tab_width - width % tab_width));
+                    }
+                    c => expanded.push(c),
+                }
+            }
+            expanded
+        })
+        .collect()
+}
+
+/// The directory holding `.git` that `dir` is in, or `dir` itself outside of
+/// a repository
+pub fn project_root(dir: &Path) -> PathBuf {
+    dir.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or(dir)
+        .to_path_buf()
+}
+
+/// Walks the files under `root` on a background thread, handing them to
+/// `found` in batches as paths relative to `root`, along with whether the
+/// walk is done. Shallower files come first. Whatever `.gitignore` files and
+/// `.git/info/exclude` ignore is left out, as is `.git` itself, and links to
+/// directories aren't followed. The walk stops early once `cancel` is set or
+/// `found` returns false.
+pub fn walk<F>(root: PathBuf, cancel: Arc<AtomicBool>, mut found: F)
+where
+    F: FnMut(Vec<String>, bool) -> bool + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut batch = vec![];
+        let mut sent = Instant::now();
+        let mut count = 0;
+        let exclude = Gitignore::load(&root.join(".git/info/exclude"), "");
+        let mut queue =
+            VecDeque::from([(String::new(), exclude.into_iter().map(Arc::new).collect())]);
+
+        while let Some((dir, ignores)) = queue.pop_front() {
+            if cancel.load(Ordering::Relaxed) {
+                return;
+            }
+            let path = root.join(&dir);
+            let mut ignores: Vec<Arc<Gitignore>> = ignores;
+            if let Some(ignore) = Gitignore::load(&path.join(".gitignore"), &dir) {
+                ignores.push(Arc::new(ignore));
+            }
+            let mut entries: Vec<(String, bool)> = match fs::read_dir(&path) {
+                Ok(entries) => entries
+                    .filter_map(|entry| {
+                        let entry = entry.ok()?;
+                        let file_type = entry.file_type().ok()?;
+                        let is_file = match file_type.is_symlink() {
+                            true => entry.path().is_file(),
+                            false => file_type.is_file(),
+                        };
+                        if !is_file && !file_type.is_dir() {
+                            return None;
+                        }
+                        Some((entry.file_name().to_string_lossy().into_owned(), !is_file))
+                    })
+                    .collect(),
+                Err(_) => continue,
+            };
+            entries.sort();
+
+            for (name, is_dir) in entries {
+                let rel = match dir.is_empty() {
+                    true => name.clone(),
+                    false => format!("{}/{}", dir, name),
+                };
+                // Deeper ignore files win over the ones further up
+                let ignored = ignores
+                    .iter()
+                    .rev()
+                    .find_map(|ignore| ignore.matched(&rel, is_dir))
+                    .unwrap_or(false);
+                if ignored || (is_dir && name == ".git") {
+                    continue;
+                }
+                if is_dir {
+                    queue.push_back((rel, ignores.clone()));
+                    continue;
+                }
+                batch.push(rel);
+                count += 1;
+                if count >= MAX_FILES {
+                    found(batch, true);
+                    return;
+                }
+                if batch.len() >= BATCH_SIZE || sent.elapsed() >= BATCH_INTERVAL {
+                    if !found(std::mem::take(&mut batch), false) {
+                        return;
+                    }
+                    sent = Instant::now();
+                }
+            }
+        }
+        found(batch, true);
+    });
+}
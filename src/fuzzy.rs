@@ -0,0 +1,243 @@
+// Scores for fuzzy matching, after those of fzy. Gaps before, between and
+// after the matched characters cost a little for each character skipped,
+// and a character matched right after the one before it or at the start of
+// a word earns a bonus.
+const SCORE_GAP_LEADING: i64 = -5;
+const SCORE_GAP_TRAILING: i64 = -5;
+const SCORE_GAP_INNER: i64 = -10;
+const SCORE_CONSECUTIVE: i64 = 1000;
+const BONUS_SLASH: i64 = 900;
+const BONUS_WORD: i64 = 800;
+const BONUS_CAPITAL: i64 = 700;
+const BONUS_DOT: i64 = 600;
+
+// Score of what can't be matched at all
+const SCORE_MIN: i64 = i64::MIN;
+
+/// How well `pattern` matches `text` when its characters are found in
+/// `text` in order, though not necessarily next to each other. Returns None
+/// if they aren't all found, and otherwise a score that is higher for
+/// matches that are closer together and start words or path components.
+/// Case is ignored unless the pattern has capital letters in it.
+pub fn score(pattern: &str, text: &str) -> Option<i64> {
+    let (pattern, chars) = prepare(pattern, text)?;
+    let (m, n) = (pattern.len(), chars.len());
+    let bonus = bonuses(text);
+
+    // Best score with the current pattern character matched at each text
+    // position, and best score with it matched there or anywhere before
+    let mut prev_d = vec![SCORE_MIN; n];
+    let mut prev_m = vec![SCORE_MIN; n];
+    for (i, p) in pattern.iter().enumerate() {
+        let mut d = vec![SCORE_MIN; n];
+        let mut best = vec![SCORE_MIN; n];
+        step(
+            i,
+            m,
+            *p,
+            &chars,
+            &bonus,
+            (&prev_d, &prev_m),
+            (&mut d, &mut best),
+        );
+        prev_d = d;
+        prev_m = best;
+    }
+    Some(prev_m[n - 1])
+}
+
+/// The score of a match as with `score`, along with the indices of the
+/// characters of `text` the pattern was matched to in the best match
+pub fn match_positions(pattern: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let (pattern, chars) = prepare(pattern, text)?;
+    let (m, n) = (pattern.len(), chars.len());
+    let bonus = bonuses(text);
+
+    let mut d = vec![vec![SCORE_MIN; n]; m];
+    let mut best = vec![vec![SCORE_MIN; n]; m];
+    for (i, p) in pattern.iter().enumerate() {
+        let (done_d, rest_d) = d.split_at_mut(i);
+        let (done_m, rest_m) = best.split_at_mut(i);
+        let prev: (&[i64], &[i64]) = match i {
+            0 => (&[], &[]),
+            _ => (&done_d[i - 1], &done_m[i - 1]),
+        };
+        step(
+            i,
+            m,
+            *p,
+            &chars,
+            &bonus,
+            prev,
+            (&mut rest_d[0], &mut rest_m[0]),
+        );
+    }
+
+    // Walk back through the matrices, preferring consecutive matches
+    let mut positions = vec![0; m];
+    let mut must_match = false;
+    let mut j = n;
+    for i in (0..m).rev() {
+        while j > 0 {
+            j -= 1;
+            if d[i][j] != SCORE_MIN && (must_match || d[i][j] == best[i][j]) {
+                // Keep to a consecutive run if that's how this one was scored
+                must_match = i > 0
+                    && j > 0
+                    && d[i - 1][j - 1] != SCORE_MIN
+                    && best[i][j] == d[i - 1][j - 1] + SCORE_CONSECUTIVE;
+                positions[i] = j;
+                break;
+            }
+        }
+    }
+    Some((best[m - 1][n - 1], positions))
+}
+
+/// Characters to match, folded to lower case for a case-insensitive match,
+/// or None if the text can't hold the pattern at all
+fn prepare(pattern: &str, text: &str) -> Option<(Vec<char>, Vec<char>)> {
+    let case_sensitive = pattern.chars().any(char::is_uppercase);
+    let fold = |c: char| match case_sensitive {
+        true => c,
+        false => c.to_lowercase().next().unwrap_or(c),
+    };
+    let pattern: Vec<char> = pattern
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(fold)
+        .collect();
+    let folded: Vec<char> = text.chars().map(fold).collect();
+    if pattern.is_empty() {
+        return None;
+    }
+    // Quick check that every character is there, in order
+    let mut rest = folded.iter();
+    if !pattern.iter().all(|p| rest.any(|c| c == p)) {
+        return None;
+    }
+    Some((pattern, folded))
+}
+
+/// Bonus for matching each character of the text, going by the one before it.
+/// This looks at the text before case folding, so that camelCase humps count.
+fn bonuses(text: &str) -> Vec<i64> {
+    let mut prev = '/';
+    text.chars()
+        .map(|c| {
+            let bonus = match prev {
+                '/' | '\\' => BONUS_SLASH,
+                '-' | '_' | ' ' => BONUS_WORD,
+                '.' => BONUS_DOT,
+                _ if prev.is_lowercase() && c.is_uppercase() => BONUS_CAPITAL,
+                _ => 0,
+            };
+            prev = c;
+            bonus
+        })
+        .collect()
+}
+
+/// Fills in the scores for pattern character `i` from those for the one
+/// before it
+fn step(
+    i: usize,
+    m: usize,
+    p: char,
+    text: &[char],
+    bonus: &[i64],
+    (prev_d, prev_m): (&[i64], &[i64]),
+    (d, best): (&mut [i64], &mut [i64]),
+) {
+    let gap = if i == m - 1 {
+        SCORE_GAP_TRAILING
+    } else {
+        SCORE_GAP_INNER
+    };
+    let valid = |score: i64| Some(score).filter(|score| *score != SCORE_MIN);
+    let mut prev_score = SCORE_MIN;
+    for (j, c) in text.iter().enumerate() {
+        let score = match (*c == p, i, j) {
+            (false, _, _) | (true, 1.., 0) => SCORE_MIN,
+            (true, 0, _) => j as i64 * SCORE_GAP_LEADING + bonus[j],
+            (true, _, _) => {
+                let skipped = valid(prev_m[j - 1]).map(|score| score + bonus[j]);
+                let run = valid(prev_d[j - 1]).map(|score| score + SCORE_CONSECUTIVE);
+                skipped.max(run).unwrap_or(SCORE_MIN)
+            }
+        };
+        d[j] = score;
+        prev_score = match valid(prev_score) {
+            Some(prev_score) => score.max(prev_score + gap),
+            None => score,
+        };
+        best[j] = prev_score;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The texts in the order a search for `pattern` lists them, leaving
+    /// out those that don't match
+    fn ranked<'a>(pattern: &str, texts: &[&'a str]) -> Vec<&'a str> {
+        let mut scored: Vec<(i64, &str)> = texts
+            .iter()
+            .filter_map(|text| Some((score(pattern, text)?, *text)))
+            .collect();
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        scored.into_iter().map(|(_, text)| text).collect()
+    }
+
+    #[test]
+    fn characters_must_all_be_found_in_order() {
+        assert!(score("abc", "a_b_c").is_some());
+        assert_eq!(score("abc", "acb"), None);
+        assert_eq!(score("", "anything"), None);
+        assert_eq!(score("abcd", "abc"), None);
+    }
+
+    #[test]
+    fn capitals_in_the_pattern_make_it_case_sensitive() {
+        assert!(score("main", "src/Main.rs").is_some());
+        assert_eq!(score("Main", "src/main.rs"), None);
+        assert!(score("Main", "src/Main.rs").is_some());
+    }
+
+    #[test]
+    fn consecutive_characters_rank_first() {
+        let texts = ["src/m_a_i_n.rs", "src/main.rs", "src/mxaxixn.rs"];
+        assert_eq!(ranked("main", &texts)[0], "src/main.rs");
+        assert!(score("main", "src/m_a_i_n.rs") > score("main", "src/mxaxixn.rs"));
+    }
+
+    #[test]
+    fn the_starts_of_path_components_and_words_rank_higher() {
+        assert!(score("v", "src/view.rs") > score("v", "src/review.rs"));
+        assert!(score("tc", "terminal_controller.rs") > score("tc", "tactic.rs"));
+        assert!(score("tc", "terminalController.rs") > score("tc", "tactic.rs"));
+    }
+
+    #[test]
+    fn shorter_gaps_rank_higher() {
+        let texts = [
+            "src/syntax/syntectengine.rs",
+            "src/keymap.rs",
+            "src/model.rs",
+        ];
+        assert_eq!(ranked("km", &texts), vec!["src/keymap.rs"]);
+        assert!(score("ab", "ab") > score("ab", "abxxxx"));
+        assert!(score("ab", "xab") > score("ab", "xxxxab"));
+    }
+
+    #[test]
+    fn positions_follow_the_best_match() {
+        let (total, positions) = match_positions("main", "src/main.rs").unwrap();
+        assert_eq!(positions, vec![4, 5, 6, 7]);
+        assert_eq!(Some(total), score("main", "src/main.rs"));
+        // The run at the end beats the word start with a gap after it
+        assert_eq!(match_positions("ab", "a_ab").unwrap().1, vec![2, 3]);
+        assert_eq!(match_positions("ab", "ba"), None);
+    }
+}
@@ -0,0 +1,224 @@
+use fancy_regex::Regex;
+use std::fs;
+use std::path::Path;
+
+/// The patterns of one `.gitignore` file
+pub struct Gitignore {
+    // Directory of the file, relative to where paths are given from, with a
+    // trailing slash unless it is that directory itself
+    base: String,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    regex: Regex,
+    // Whether the pattern started with `!`, bringing back what an earlier
+    // one ignored
+    negated: bool,
+    // Whether the pattern ended with `/`, so that it only matches directories
+    dir_only: bool,
+}
+
+impl Gitignore {
+    /// Reads the patterns of an ignore file lying in `base`. Returns None if
+    /// the file can't be read or has no patterns.
+    pub fn load(path: &Path, base: &str) -> Option<Gitignore> {
+        let text = fs::read_to_string(path).ok()?;
+        let base = match base.is_empty() || base.ends_with('/') {
+            true => base.to_string(),
+            false => format!("{}/", base),
+        };
+        let rules: Vec<Rule> = text.lines().filter_map(Rule::parse).collect();
+        match rules.is_empty() {
+            true => None,
+            false => Some(Gitignore { base, rules }),
+        }
+    }
+
+    /// Whether the patterns ignore a path, or bring it back with `!` when
+    /// false. Returns None if no pattern matches. Later patterns win over
+    /// earlier ones.
+    pub fn matched(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let path = path.strip_prefix(&self.base)?;
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(path).unwrap_or(false))
+            .map(|rule| !rule.negated)
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // A slash anywhere but at the end ties the pattern to the directory
+        // of the file, and otherwise it matches a name at any depth
+        let regex = match pattern.strip_prefix('/') {
+            Some(anchored) => format!("^{}$", glob_to_regex(anchored)),
+            None if pattern.contains('/') => format!("^{}$", glob_to_regex(pattern)),
+            None => format!("^(?:.*/)?{}$", glob_to_regex(pattern)),
+        };
+        Some(Rule {
+            regex: Regex::new(&regex).ok()?,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+/// Translates gitignore's glob syntax: `*`, `?`, `[abc]`, `[!abc]` and `**`
+/// standing for any number of directories
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let at_start = i == 0 || chars[i - 1] == '/';
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') && at_start => {
+                match chars.get(i + 2) {
+                    // `**/` matches no directory or any number of them
+                    Some('/') => {
+                        out.push_str("(?:.*/)?");
+                        i += 2;
+                    }
+                    // A trailing `**` matches everything inside
+                    None => {
+                        out.push_str(".*");
+                        i += 1;
+                    }
+                    _ => out.push_str("[^/]*"),
+                }
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+                Some(len) => {
+                    let class: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(negated) => format!("^{}", negated),
+                        None => class,
+                    };
+                    out.push_str(&format!("[{}]", class.replace('\\', "\\\\")));
+                    i += len + 1;
+                }
+                None => out.push_str("\\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                out.push_str(&escape(chars[i]));
+            }
+            c => out.push_str(&escape(c)),
+        }
+        i += 1;
+    }
+    out
+}
+
+fn escape(c: char) -> String {
+    match "\\.+*?()|[]{}^$#&-~".contains(c) {
+        true => format!("\\{}", c),
+        false => c.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gitignore(base: &str, patterns: &str) -> Gitignore {
+        Gitignore {
+            base: base.to_string(),
+            rules: patterns.lines().filter_map(Rule::parse).collect(),
+        }
+    }
+
+    fn ignored(patterns: &str, path: &str) -> Option<bool> {
+        gitignore("", patterns).matched(path, false)
+    }
+
+    #[test]
+    fn globs_become_regexes() {
+        assert_eq!(glob_to_regex("*.o"), r"[^/]*\.o");
+        assert_eq!(glob_to_regex("file?.[ch]"), r"file[^/]\.[ch]");
+        assert_eq!(glob_to_regex("[!ab]c"), "[^ab]c");
+        assert_eq!(glob_to_regex("**/build"), "(?:.*/)?build");
+        assert_eq!(glob_to_regex("logs/**"), "logs/.*");
+        assert_eq!(glob_to_regex("a/**/b"), "a/(?:.*/)?b");
+        assert_eq!(glob_to_regex(r"\*literal"), r"\*literal");
+        assert_eq!(glob_to_regex("a**b"), "a[^/]*[^/]*b");
+    }
+
+    #[test]
+    fn patterns_without_a_slash_match_at_any_depth() {
+        assert_eq!(ignored("*.o", "main.o"), Some(true));
+        assert_eq!(ignored("*.o", "src/deep/main.o"), Some(true));
+        assert_eq!(ignored("*.o", "main.obj"), None);
+        assert_eq!(ignored("target", "crates/x/target"), Some(true));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_are_anchored() {
+        assert_eq!(ignored("/build", "build"), Some(true));
+        assert_eq!(ignored("/build", "src/build"), None);
+        assert_eq!(ignored("doc/*.txt", "doc/a.txt"), Some(true));
+        assert_eq!(ignored("doc/*.txt", "src/doc/a.txt"), None);
+        assert_eq!(ignored("doc/*.txt", "doc/sub/a.txt"), None);
+    }
+
+    #[test]
+    fn double_stars_match_any_number_of_directories() {
+        assert_eq!(ignored("**/foo", "foo"), Some(true));
+        assert_eq!(ignored("**/foo", "a/b/foo"), Some(true));
+        assert_eq!(ignored("a/**/b", "a/b"), Some(true));
+        assert_eq!(ignored("a/**/b", "a/x/y/b"), Some(true));
+        assert_eq!(ignored("logs/**", "logs/2024/app.log"), Some(true));
+        assert_eq!(ignored("logs/**", "logs"), None);
+    }
+
+    #[test]
+    fn directory_patterns_only_match_directories() {
+        let rules = gitignore("", "build/");
+        assert_eq!(rules.matched("build", true), Some(true));
+        assert_eq!(rules.matched("build", false), None);
+        assert_eq!(rules.matched("src/build", true), Some(true));
+    }
+
+    #[test]
+    fn later_negated_patterns_bring_paths_back() {
+        let patterns = "*.log\n!keep.log";
+        assert_eq!(ignored(patterns, "debug.log"), Some(true));
+        assert_eq!(ignored(patterns, "keep.log"), Some(false));
+        // A pattern after the negation wins again
+        assert_eq!(ignored("!keep.log\n*.log", "keep.log"), Some(true));
+        assert_eq!(ignored(r"\!important", "!important"), Some(true));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let rules = gitignore("", "# *.o\n\n*.a   \n");
+        assert_eq!(rules.rules.len(), 1);
+        assert_eq!(rules.matched("lib.a", false), Some(true));
+        assert_eq!(rules.matched("main.o", false), None);
+    }
+
+    #[test]
+    fn patterns_apply_below_the_directory_of_their_file() {
+        let rules = gitignore("sub/", "/out\n*.tmp");
+        assert_eq!(rules.matched("sub/out", false), Some(true));
+        assert_eq!(rules.matched("sub/x/out", false), None);
+        assert_eq!(rules.matched("sub/x/a.tmp", false), Some(true));
+        assert_eq!(rules.matched("a.tmp", false), None);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::finder;
+use crate::quickfix::{Entry, Progress};
+use fancy_regex::Regex;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Matches a search finds before it gives up
+const MAX_MATCHES: usize = 10_000;
+
+// Files bigger than this aren't searched by the built-in search
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+// How often matches are handed over while a search goes on
+const BATCH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Splits the arguments of `:grep` into the pattern and the path to search,
+/// if one is given. A pattern with spaces in it is quoted with '' or "".
+pub fn parse_args(args: &str) -> Result<(String, Option<String>), String> {
+    let args = args.trim();
+    let (pattern, rest) = match args.chars().next() {
+        Some(quote @ ('"' | '\'')) => match args[1..].find(quote) {
+            Some(end) => (&args[1..1 + end], &args[2 + end..]),
+            None => return Err(format!("Missing closing {}", quote)),
+        },
+        _ => args.split_once(char::is_whitespace).unwrap_or((args, "")),
+    };
+    if pattern.is_empty() {
+        return Err(String::from("Usage: :grep pattern [path]"));
+    }
+    let path = Some(rest.trim()).filter(|path| !path.is_empty());
+    Ok((pattern.to_string(), path.map(String::from)))
+}
+
+/// Searches the files under `root`, or `root` itself when it is a file, for
+/// lines `regex` matches on a background thread. Files are found as the file
+/// finder finds them, so ignored files are left out, and files that look
+/// binary are skipped. Matches are handed to `found` in batches until the
+/// search is done, `cancel` is set or `found` returns false.
+pub fn search<F>(root: PathBuf, regex: Regex, cancel: Arc<AtomicBool>, mut found: F)
+where
+    F: FnMut(Vec<Entry>, Progress) -> bool + Send + 'static,
+{
+    if root.is_file() {
+        thread::spawn(move || found(search_file(&root, &regex, MAX_MATCHES), Progress::Done));
+        return;
+    }
+    let mut batch = vec![];
+    let mut count = 0;
+    let mut sent = Instant::now();
+    finder::walk(root.clone(), Arc::clone(&cancel), move |files, done| {
+        for file in files {
+            if cancel.load(Ordering::Relaxed) {
+                return false;
+            }
+            let entries = search_file(&root.join(file), &regex, MAX_MATCHES - count);
+            count += entries.len();
+            batch.extend(entries);
+            if count >= MAX_MATCHES {
+                found(std::mem::take(&mut batch), Progress::Done);
+                return false;
+            }
+            if !batch.is_empty() && sent.elapsed() >= BATCH_INTERVAL {
+                if !found(std::mem::take(&mut batch), Progress::Running) {
+                    return false;
+                }
+                sent = Instant::now();
+            }
+        }
+        match done {
+            true => found(std::mem::take(&mut batch), Progress::Done),
+            false => true,
+        }
+    });
+}
+
+/// The first match on each line of a file, `limit` of them at most
+fn search_file(path: &Path, regex: &Regex, limit: usize) -> Vec<Entry> {
+    let too_big = fs::metadata(path).map_or(true, |meta| meta.len() > MAX_FILE_SIZE);
+    let bytes = match too_big {
+        true => return vec![],
+        false => fs::read(path).unwrap_or_default(),
+    };
+    if bytes.contains(&0) {
+        return vec![];
+    }
+    String::from_utf8_lossy(&bytes)
+        .lines()
+        .enumerate()
+        .filter_map(|(line, text)| {
+            let found = regex.find(text).ok()??;
+            Some(Entry {
+                path: path.to_path_buf(),
+                line,
+                col: found.start(),
+                text: text.trim().to_string(),
+                kind: None,
+            })
+        })
+        .take(limit)
+        .collect()
+}
+
+/// Runs a search program such as `rg --vimgrep` in `dir` on a background
+/// thread, with the pattern and path as its last arguments, and hands the
+/// matches it prints to `found` as `search` does. Lines printed without a
+/// column get the column `regex` matches at. Returns an error if the
+/// program can't be started.
+pub fn run<F>(
+    program: &str,
+    (pattern, path): (&str, &str),
+    dir: PathBuf,
+    regex: Option<Regex>,
+    cancel: Arc<AtomicBool>,
+    mut found: F,
+) -> io::Result<()>
+where
+    F: FnMut(Vec<Entry>, Progress) -> bool + Send + 'static,
+{
+    let mut words = program.split_whitespace();
+    let name = words
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No grep program"))?
+        .to_string();
+    let mut child = Command::new(&name)
+        .args(words)
+        // Keep a pattern starting with a dash from being taken for an option
+        .args(pattern.starts_with('-').then_some("--"))
+        .arg(pattern)
+        .arg(path)
+        .current_dir(&dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (stdout, mut stderr) = match (child.stdout.take(), child.stderr.take()) {
+        (Some(stdout), Some(stderr)) => (stdout, stderr),
+        _ => return Err(io::Error::other("Couldn't read from the grep program")),
+    };
+    // Read on a thread of its own so that neither pipe fills up
+    let errors = thread::spawn(move || {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors);
+        errors
+    });
+
+    thread::spawn(move || {
+        let mut batch = vec![];
+        let mut count = 0;
+        let mut sent = Instant::now();
+        for line in BufReader::new(stdout).split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if cancel.load(Ordering::Relaxed) {
+                stop(&mut child);
+                return;
+            }
+            let line = String::from_utf8_lossy(&line);
+            if let Some(entry) = parse_line(line.trim_end_matches('\r'), &dir, regex.as_ref()) {
+                batch.push(entry);
+                count += 1;
+            }
+            if count >= MAX_MATCHES {
+                stop(&mut child);
+                found(batch, Progress::Done);
+                return;
+            }
+            if !batch.is_empty() && sent.elapsed() >= BATCH_INTERVAL {
+                if !found(std::mem::take(&mut batch), Progress::Running) {
+                    stop(&mut child);
+                    return;
+                }
+                sent = Instant::now();
+            }
+        }
+        let status = child.wait();
+        let errors = errors.join().unwrap_or_default();
+        let error = errors.lines().find(|line| !line.trim().is_empty());
+        // grep and rg exit with 1 when nothing matched
+        let progress = match (status, error) {
+            (Ok(status), _) if status.success() => Progress::Done,
+            (Ok(status), None) if status.code() == Some(1) => Progress::Done,
+            (Ok(_), Some(error)) => Progress::Failed(error.to_string()),
+            (Ok(status), None) => Progress::Failed(format!("{} failed: {}", name, status)),
+            (Err(err), _) => Progress::Failed(err.to_string()),
+        };
+        found(batch, progress);
+    });
+    Ok(())
+}
+
+fn stop(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Reads a line printed by a search program as file:line:col:text or
+/// file:line:text, with the file relative to `dir`
+fn parse_line(line: &str, dir: &Path, regex: Option<&Regex>) -> Option<Entry> {
+    let mut parts = line.splitn(3, ':');
+    let path = parts.next()?;
+    let row = parts.next()?.parse::<usize>().ok()?.checked_sub(1)?;
+    let rest = parts.next()?;
+    let column = rest
+        .split_once(':')
+        .and_then(|(col, text)| Some((col.parse::<usize>().ok()?.checked_sub(1)?, text)));
+    let (col, text) = match column {
+        Some(column) => column,
+        None => {
+            let found = regex.and_then(|regex| regex.find(rest).ok().flatten());
+            (found.map_or(0, |found| found.start()), rest)
+        }
+    };
+    let path = path.strip_prefix("./").unwrap_or(path);
+    Some(Entry {
+        path: dir.join(path),
+        line: row,
+        col,
+        text: text.trim().to_string(),
+        kind: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: &str, regex: Option<&Regex>) -> Option<(PathBuf, usize, usize, String)> {
+        let entry = parse_line(line, Path::new("/project"), regex)?;
+        Some((entry.path, entry.line, entry.col, entry.text))
+    }
+
+    #[test]
+    fn arguments_are_a_pattern_and_a_path() {
+        assert_eq!(parse_args("main"), Ok((String::from("main"), None)));
+        assert_eq!(parse_args(" main  src "), Ok((String::from("main"), Some(String::from("src")))));
+        let quoted = parse_args("\"fn main\" src/main.rs");
+        assert_eq!(quoted, Ok((String::from("fn main"), Some(String::from("src/main.rs")))));
+        assert_eq!(parse_args("'a \"b\"'"), Ok((String::from("a \"b\""), None)));
+        assert_eq!(parse_args("\"open"), Err(String::from("Missing closing \"")));
+        assert!(parse_args("  ").is_err());
+    }
+
+    #[test]
+    fn lines_with_a_column_are_read_as_they_are() {
+        let found = entry("src/main.rs:12:5:    let x = main();", None);
+        let expected = (PathBuf::from("/project/src/main.rs"), 11, 4, String::from("let x = main();"));
+        assert_eq!(found, Some(expected));
+    }
+
+    #[test]
+    fn lines_without_a_column_get_the_column_of_the_match() {
+        let regex = Regex::new("main").unwrap();
+        let found = entry("./src/main.rs:3:fn main() {", Some(&regex));
+        let expected = (PathBuf::from("/project/src/main.rs"), 2, 3, String::from("fn main() {"));
+        assert_eq!(found, Some(expected));
+        // Without a regex the match is taken to start the line
+        assert_eq!(entry("a.txt:1:text", None).map(|entry| entry.2), Some(0));
+    }
+
+    #[test]
+    fn colons_in_the_text_stay_in_it() {
+        let found = entry("a.rs:2:1:x: y::z", None).map(|entry| entry.3);
+        assert_eq!(found.as_deref(), Some("x: y::z"));
+        let found = entry("a.rs:2:std::io", None).map(|entry| (entry.2, entry.3));
+        assert_eq!(found, Some((0, String::from("std::io"))));
+    }
+
+    #[test]
+    fn lines_that_are_not_matches_are_skipped() {
+        assert_eq!(entry("Binary file a.bin matches", None), None);
+        assert_eq!(entry("a.rs:x:1:text", None), None);
+        assert_eq!(entry("a.rs:0:1:text", None), None);
+        assert_eq!(entry("a.rs", None), None);
+    }
+
+    #[test]
+    fn files_are_searched_line_by_line() {
+        let path = std::env::temp_dir().join(format!("ginkgo-grep-{}.txt", std::process::id()));
+        fs::write(&path, "one\n  two two\nthree\ntwo\n").unwrap();
+        let regex = Regex::new("two").unwrap();
+        let found: Vec<_> = search_file(&path, &regex, 10)
+            .into_iter()
+            .map(|entry| (entry.line, entry.col, entry.text))
+            .collect();
+        let limited = search_file(&path, &regex, 1).len();
+        fs::write(&path, b"two\0").unwrap();
+        let binary = search_file(&path, &regex, 10).len();
+        fs::remove_file(&path).unwrap();
+
+        let expected = vec![(1, 2, String::from("two two")), (3, 0, String::from("two"))];
+        assert_eq!(found, expected);
+        assert_eq!(limited, 1);
+        assert_eq!(binary, 0);
+    }
+}
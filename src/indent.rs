@@ -0,0 +1,257 @@
+use fancy_regex::Regex;
+use std::sync::OnceLock;
+
+static BRACES: OnceLock<IndentRules> = OnceLock::new();
+
+/// When a line changes the indentation of the lines around it. A line
+/// matching `increase` indents the lines after it by one more level, and a
+/// line matching `decrease` is itself indented one level less, like a
+/// closing brace. Patterns are matched against a line's code, with the text
+/// of comments and strings blanked out.
+pub struct IndentRules {
+    increase: Option<Regex>,
+    decrease: Option<Regex>,
+}
+
+impl IndentRules {
+    pub fn new(increase: Option<&str>, decrease: Option<&str>) -> Result<IndentRules, String> {
+        let compile = |pattern: Option<&str>| {
+            pattern
+                .map(|pattern| {
+                    Regex::new(pattern)
+                        .map_err(|err| format!("bad indent pattern {:?}: {}", pattern, err))
+                })
+                .transpose()
+        };
+        Ok(IndentRules {
+            increase: compile(increase)?,
+            decrease: compile(decrease)?,
+        })
+    }
+
+    /// Rules for languages that don't give their own: a level is added
+    /// after an opening brace and taken away before a closing one
+    pub fn braces() -> &'static IndentRules {
+        BRACES.get_or_init(|| IndentRules {
+            increase: Regex::new(r"\{\s*$").ok(),
+            decrease: Regex::new(r"^\s*\}").ok(),
+        })
+    }
+
+    pub fn increases(&self, code: &str) -> bool {
+        matches(&self.increase, code)
+    }
+
+    pub fn decreases(&self, code: &str) -> bool {
+        matches(&self.decrease, code)
+    }
+
+    /// Width in columns that a line should be indented to, given the line
+    /// above it as `(contents, code)` and the code of the line itself
+    pub fn indent_after(&self, prev: Option<(&str, &str)>, code: &str, tab_width: usize) -> usize {
+        let mut width = 0;
+        if let Some((contents, prev_code)) = prev {
+            width = indent_width(contents, tab_width);
+            if self.increases(prev_code) {
+                width += tab_width;
+            }
+        }
+        if self.decreases(code) {
+            width = width.saturating_sub(tab_width);
+        }
+        width
+    }
+}
+
+fn matches(regex: &Option<Regex>, code: &str) -> bool {
+    regex
+        .as_ref()
+        .is_some_and(|regex| regex.is_match(code).unwrap_or(false))
+}
+
+/// The spaces and tabs a line starts with
+pub fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Width in columns of the indentation a line starts with, with tabs
+/// reaching to the next tab stop
+pub fn indent_width(line: &str, tab_width: usize) -> usize {
+    leading_whitespace(line)
+        .chars()
+        .fold(0, |width, c| match c {
+            '\t' => width + tab_width - width % tab_width,
+            _ => width + 1,
+        })
+}
+
+/// Indentation `width` columns wide, made of tabs and then spaces unless
+/// `expandtab` asks for spaces only
+pub fn indent_string(width: usize, tab_width: usize, expandtab: bool) -> String {
+    match expandtab {
+        true => " ".repeat(width),
+        false => "\t".repeat(width / tab_width) + &" ".repeat(width % tab_width),
+    }
+}
+
+/// How a file is indented, as worked out from its contents
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndentStyle {
+    Tabs,
+    // Spaces, this many to a level
+    Spaces(usize),
+}
+
+// Lines looked at when working out how a file is indented
+const DETECT_LINES: usize = 10000;
+
+/// Guesses how lines are indented from their leading whitespace. Lines
+/// indented with tabs are counted against lines indented with spaces, and
+/// the width of a level of spaces is the step up in indentation seen most
+/// often from one line to the next. Returns None when it can't tell.
+pub fn detect_style<'a>(lines: impl Iterator<Item = &'a str>) -> Option<IndentStyle> {
+    let (mut tabs, mut spaces) = (0, 0);
+    // How often each width from 2 to 8 was stepped up by
+    let mut steps = [0; 9];
+    // Indentation of the last line indented with spaces or not at all
+    let mut prev = Some(0);
+    for line in lines.take(DETECT_LINES) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let whitespace = leading_whitespace(line);
+        if whitespace.starts_with('\t') {
+            tabs += 1;
+            prev = None;
+            continue;
+        }
+        let width = whitespace.len();
+        if width > 0 {
+            spaces += 1;
+        }
+        if let Some(step) = prev.and_then(|prev| width.checked_sub(prev)) {
+            if (2..steps.len()).contains(&step) {
+                steps[step] += 1;
+            }
+        }
+        prev = Some(width);
+    }
+
+    if tabs == 0 && spaces == 0 {
+        return None;
+    }
+    if tabs >= spaces {
+        return Some(IndentStyle::Tabs);
+    }
+    // Ties go to the narrower width
+    let (width, count) = steps
+        .iter()
+        .enumerate()
+        .rev()
+        .max_by_key(|(_, count)| **count)
+        .unwrap_or((0, &0));
+    match *count {
+        0 => None,
+        _ => Some(IndentStyle::Spaces(width)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_copy_the_indentation_above() {
+        let rules = IndentRules::braces();
+        assert_eq!(rules.indent_after(Some(("\tx = 1;", "\tx = 1;")), "y", 4), 4);
+        assert_eq!(rules.indent_after(Some(("  \tx", "  \tx")), "y", 4), 4);
+        assert_eq!(rules.indent_after(Some(("      x", "      x")), "y", 4), 6);
+        assert_eq!(rules.indent_after(None, "y", 4), 0);
+    }
+
+    #[test]
+    fn braces_add_and_take_away_a_level() {
+        let rules = IndentRules::braces();
+        assert_eq!(rules.indent_after(Some(("\tif x {", "\tif x {")), "y", 4), 8);
+        assert_eq!(rules.indent_after(Some(("\t\ty", "\t\ty")), "}", 4), 4);
+        // An opening brace followed at once by its closing one cancel out
+        assert_eq!(rules.indent_after(Some(("\tif x {", "\tif x {")), "} else {", 4), 4);
+        assert_eq!(rules.indent_after(None, "}", 4), 0);
+    }
+
+    #[test]
+    fn only_code_counts() {
+        let rules = IndentRules::braces();
+        // A brace inside a comment is blanked out of the code
+        assert_eq!(rules.indent_after(Some(("x // {", "x     ")), "y", 4), 0);
+    }
+
+    #[test]
+    fn custom_rules() {
+        let rules = IndentRules::new(Some(r":\s*$"), Some(r"^\s*(else|elif)\b")).unwrap();
+        assert_eq!(rules.indent_after(Some(("if x:", "if x:")), "y", 4), 4);
+        assert_eq!(rules.indent_after(Some(("    y", "    y")), "else:", 4), 0);
+        assert!(!rules.increases("x = {"));
+
+        let err = IndentRules::new(Some("("), None).err().unwrap();
+        assert!(err.starts_with("bad indent pattern \"(\""), "{}", err);
+    }
+
+    #[test]
+    fn indentation_is_tabs_then_spaces_unless_expanded() {
+        assert_eq!(indent_string(10, 4, false), "\t\t  ");
+        assert_eq!(indent_string(10, 4, true), " ".repeat(10));
+        assert_eq!(indent_string(0, 4, false), "");
+    }
+
+    #[test]
+    fn tabs_reach_the_next_tab_stop() {
+        assert_eq!(indent_width("\tx", 4), 4);
+        assert_eq!(indent_width("  \tx", 4), 4);
+        assert_eq!(indent_width("     \t x", 4), 9);
+        assert_eq!(indent_width("   ", 8), 3);
+        assert_eq!(leading_whitespace(" \t x "), " \t ");
+    }
+
+    fn detect(text: &str) -> Option<IndentStyle> {
+        detect_style(text.lines())
+    }
+
+    #[test]
+    fn tab_indented_files() {
+        assert_eq!(detect("fn f() {\n\tif x {\n\t\ty();\n\t}\n}"), Some(IndentStyle::Tabs));
+    }
+
+    #[test]
+    fn space_indented_files_get_the_step_seen_most() {
+        let yaml = "a:\n  b:\n    c: 1\n  d: 2\ne:\n  f: 3";
+        assert_eq!(detect(yaml), Some(IndentStyle::Spaces(2)));
+        let python = "def f():\n    if x:\n        y()\n    return 1\n\ndef g():\n    pass";
+        assert_eq!(detect(python), Some(IndentStyle::Spaces(4)));
+        // Steps down and blank lines don't count
+        let c = "int f() {\n        x();\n\n        y();\n}";
+        assert_eq!(detect(c), Some(IndentStyle::Spaces(8)));
+        // Ties go to the narrower width
+        assert_eq!(detect("a\n  b\nc\n    d"), Some(IndentStyle::Spaces(2)));
+    }
+
+    #[test]
+    fn mixed_files_go_with_what_most_lines_use() {
+        let mostly_tabs = "a {\n\tb\n\tc\n    d\n}";
+        assert_eq!(detect(mostly_tabs), Some(IndentStyle::Tabs));
+        let mostly_spaces = "a {\n    b\n    c\n\td\n}\ne {\n    f\n}";
+        assert_eq!(detect(mostly_spaces), Some(IndentStyle::Spaces(4)));
+        assert_eq!(detect("\ta\n  b"), Some(IndentStyle::Tabs));
+        // A line indented with tabs isn't a step for the line after it
+        assert_eq!(detect("\tx\n    y\n    z"), None);
+    }
+
+    #[test]
+    fn files_without_indentation_tell_nothing() {
+        assert_eq!(detect(""), None);
+        assert_eq!(detect("a\nb\n\nc"), None);
+        assert_eq!(detect("   \n\t\n"), None);
+        // Single spaces, as when aligning, aren't a level
+        assert_eq!(detect("a\n b\n  c"), None);
+    }
+}
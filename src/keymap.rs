@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use termion::event::Key;
+
+/// Something a key binding can do. Actions are named in config files and
+/// messages by their kebab-case names.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Quit,
+    Save,
+    SaveAs,
+    Find,
+    Undo,
+    Redo,
+    CommandPrompt,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    // Move by screen line rather than by row when wrapping
+    MoveDisplayLineUp,
+    MoveDisplayLineDown,
+    LineStart,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    // Jump to the bracket matching the next one on the row
+    MatchingBracket,
+    PageUp,
+    PageDown,
+    Insert,
+    InsertLineStart,
+    Append,
+    AppendLineEnd,
+    OpenBelow,
+    OpenAbove,
+    // Operators, which work on the rows a motion moves over or on the
+    // selected rows. Giving an operator twice works on the cursor row.
+    // Shift rows a level right or left
+    Indent,
+    Dedent,
+    // Indent rows the way autoindent would have
+    Reindent,
+    // Comment rows out with line comments, or with one block comment
+    Comment,
+    BlockComment,
+    // Pipe rows through a shell command typed at the command prompt
+    Filter,
+    // The comment operators on the cursor row
+    CommentLine,
+    BlockCommentLine,
+    NormalMode,
+    DeleteBack,
+    Newline,
+    Tab,
+    // Offer words from the buffer to complete the one before the cursor, or
+    // select the next or previous word offered
+    CompleteNext,
+    CompletePrev,
+    // Pick a file of the project to edit instead
+    FindFile,
+    // Jump to the next or previous place in the quickfix list
+    QuickfixNext,
+    QuickfixPrev,
+    // Does nothing, for switching a default binding off
+    Nop,
+}
+
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("quit", Action::Quit),
+    ("save", Action::Save),
+    ("save-as", Action::SaveAs),
+    ("find", Action::Find),
+    ("undo", Action::Undo),
+    ("redo", Action::Redo),
+    ("command-prompt", Action::CommandPrompt),
+    ("move-left", Action::MoveLeft),
+    ("move-right", Action::MoveRight),
+    ("move-up", Action::MoveUp),
+    ("move-down", Action::MoveDown),
+    ("move-display-line-up", Action::MoveDisplayLineUp),
+    ("move-display-line-down", Action::MoveDisplayLineDown),
+    ("line-start", Action::LineStart),
+    ("line-end", Action::LineEnd),
+    ("first-line", Action::FirstLine),
+    ("last-line", Action::LastLine),
+    ("matching-bracket", Action::MatchingBracket),
+    ("page-up", Action::PageUp),
+    ("page-down", Action::PageDown),
+    ("insert", Action::Insert),
+    ("insert-line-start", Action::InsertLineStart),
+    ("append", Action::Append),
+    ("append-line-end", Action::AppendLineEnd),
+    ("open-below", Action::OpenBelow),
+    ("open-above", Action::OpenAbove),
+    ("indent", Action::Indent),
+    ("dedent", Action::Dedent),
+    ("reindent", Action::Reindent),
+    ("comment", Action::Comment),
+    ("block-comment", Action::BlockComment),
+    ("filter", Action::Filter),
+    ("comment-line", Action::CommentLine),
+    ("block-comment-line", Action::BlockCommentLine),
+    ("normal-mode", Action::NormalMode),
+    ("delete-back", Action::DeleteBack),
+    ("newline", Action::Newline),
+    ("tab", Action::Tab),
+    ("complete-next", Action::CompleteNext),
+    ("complete-prev", Action::CompletePrev),
+    ("find-file", Action::FindFile),
+    ("quickfix-next", Action::QuickfixNext),
+    ("quickfix-prev", Action::QuickfixPrev),
+    ("nop", Action::Nop),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTION_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, action)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        ACTION_NAMES
+            .iter()
+            .find(|(_, action)| *action == self)
+            .map_or("", |(name, _)| name)
+    }
+
+    /// Whether the action only moves the cursor, so that it can give an
+    /// operator the rows to work on
+    pub fn is_motion(self) -> bool {
+        matches!(
+            self,
+            Action::MoveLeft
+                | Action::MoveRight
+                | Action::MoveUp
+                | Action::MoveDown
+                | Action::MoveDisplayLineUp
+                | Action::MoveDisplayLineDown
+                | Action::LineStart
+                | Action::LineEnd
+                | Action::FirstLine
+                | Action::LastLine
+                | Action::MatchingBracket
+                | Action::PageUp
+                | Action::PageDown
+        )
+    }
+}
+
+const DEFAULT_NORMAL: &[(&str, Action)] = &[
+    ("<Esc>", Action::NormalMode),
+    ("<C-q>", Action::Quit),
+    ("<C-s>", Action::Save),
+    ("<C-f>", Action::Find),
+    ("<C-r>", Action::Redo),
+    ("u", Action::Undo),
+    (":", Action::CommandPrompt),
+    ("h", Action::MoveLeft),
+    ("<Left>", Action::MoveLeft),
+    ("<BS>", Action::MoveLeft),
+    ("<Del>", Action::MoveLeft),
+    ("<C-h>", Action::MoveLeft),
+    ("l", Action::MoveRight),
+    ("<Right>", Action::MoveRight),
+    ("k", Action::MoveUp),
+    ("<Up>", Action::MoveUp),
+    ("j", Action::MoveDown),
+    ("<Down>", Action::MoveDown),
+    ("<CR>", Action::MoveDown),
+    ("gk", Action::MoveDisplayLineUp),
+    ("g<Up>", Action::MoveDisplayLineUp),
+    ("gj", Action::MoveDisplayLineDown),
+    ("g<Down>", Action::MoveDisplayLineDown),
+    ("0", Action::LineStart),
+    ("<Home>", Action::LineStart),
+    ("$", Action::LineEnd),
+    ("<End>", Action::LineEnd),
+    ("gg", Action::FirstLine),
+    ("G", Action::LastLine),
+    ("%", Action::MatchingBracket),
+    ("<PageUp>", Action::PageUp),
+    ("<PageDown>", Action::PageDown),
+    ("i", Action::Insert),
+    ("I", Action::InsertLineStart),
+    ("a", Action::Append),
+    ("A", Action::AppendLineEnd),
+    ("o", Action::OpenBelow),
+    ("O", Action::OpenAbove),
+    (">", Action::Indent),
+    ("<lt>", Action::Dedent),
+    ("=", Action::Reindent),
+    ("gc", Action::Comment),
+    ("gb", Action::BlockComment),
+    ("!", Action::Filter),
+    ("gcc", Action::CommentLine),
+    ("gbc", Action::BlockCommentLine),
+    ("<C-p>", Action::FindFile),
+    ("]q", Action::QuickfixNext),
+    ("[q", Action::QuickfixPrev),
+];
+
+const DEFAULT_INSERT: &[(&str, Action)] = &[
+    ("<C-q>", Action::Quit),
+    ("<C-s>", Action::Save),
+    ("<C-S>", Action::SaveAs),
+    ("<C-f>", Action::Find),
+    ("<Esc>", Action::NormalMode),
+    ("<C-c>", Action::NormalMode),
+    ("<Left>", Action::MoveLeft),
+    ("<Right>", Action::MoveRight),
+    ("<Up>", Action::MoveUp),
+    ("<Down>", Action::MoveDown),
+    ("<Home>", Action::LineStart),
+    ("<End>", Action::LineEnd),
+    ("<PageUp>", Action::PageUp),
+    ("<PageDown>", Action::PageDown),
+    ("<BS>", Action::DeleteBack),
+    ("<Del>", Action::DeleteBack),
+    ("<C-h>", Action::DeleteBack),
+    ("<CR>", Action::Newline),
+    ("<Tab>", Action::Tab),
+    ("<C-n>", Action::CompleteNext),
+    ("<C-p>", Action::CompletePrev),
+];
+
+// Names of special keys in key notation, matched without regard to case
+const KEY_NAMES: &[(&str, Key)] = &[
+    ("esc", Key::Esc),
+    ("cr", Key::Char('\n')),
+    ("enter", Key::Char('\n')),
+    ("return", Key::Char('\n')),
+    ("tab", Key::Char('\t')),
+    ("s-tab", Key::BackTab),
+    ("bs", Key::Backspace),
+    ("backspace", Key::Backspace),
+    ("del", Key::Delete),
+    ("delete", Key::Delete),
+    ("insert", Key::Insert),
+    ("space", Key::Char(' ')),
+    ("lt", Key::Char('<')),
+    ("bslash", Key::Char('\\')),
+    ("bar", Key::Char('|')),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("home", Key::Home),
+    ("end", Key::End),
+    ("pageup", Key::PageUp),
+    ("pagedown", Key::PageDown),
+];
+
+/// Parses vim-style key notation such as `gg`, `<C-s>`, `<leader>w` or
+/// `<Esc>:q<CR>` into the keys it stands for
+pub fn parse_keys(notation: &str, leader: &[Key]) -> Result<Vec<Key>, String> {
+    let mut keys = vec![];
+    let mut rest = notation;
+    while let Some(c) = rest.chars().next() {
+        let special = rest
+            .strip_prefix('<')
+            .and_then(|after| after.find('>').map(|end| &after[..end]))
+            .filter(|name| !name.is_empty());
+        match special {
+            Some(name) => {
+                if name.eq_ignore_ascii_case("leader") {
+                    keys.extend_from_slice(leader);
+                } else {
+                    keys.push(parse_special_key(name).ok_or_else(|| format!("Unknown key: <{}>", name))?);
+                }
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                keys.push(Key::Char(c));
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if keys.is_empty() {
+        return Err(String::from("Empty key sequence"));
+    }
+    Ok(keys)
+}
+
+fn parse_special_key(name: &str) -> Option<Key> {
+    let lower = name.to_lowercase();
+    if let Some((_, key)) = KEY_NAMES.iter().find(|(n, _)| *n == lower) {
+        return Some(*key);
+    }
+    let modified = |prefix: &str| {
+        let mut chars = name.get(2..)?.chars();
+        match (lower.starts_with(prefix), chars.next(), chars.next()) {
+            (true, Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+    if let Some(c) = modified("c-") {
+        return Some(Key::Ctrl(c));
+    }
+    if let Some(c) = modified("a-").or_else(|| modified("m-")) {
+        return Some(Key::Alt(c));
+    }
+    let number = lower.strip_prefix('f')?.parse().ok()?;
+    (1..=12).contains(&number).then_some(Key::F(number))
+}
+
+/// Writes keys back in key notation
+pub fn format_keys(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| {
+            if let Some((name, _)) = KEY_NAMES.iter().find(|(_, k)| k == key) {
+                let name = match *name {
+                    "cr" | "bs" => name.to_uppercase(),
+                    "lt" => String::from("lt"),
+                    "pageup" => String::from("PageUp"),
+                    "pagedown" => String::from("PageDown"),
+                    "s-tab" => String::from("S-Tab"),
+                    _ => name[..1].to_uppercase() + &name[1..],
+                };
+                return format!("<{}>", name);
+            }
+            match key {
+                Key::Char(c) => c.to_string(),
+                Key::Ctrl(c) => format!("<C-{}>", c),
+                Key::Alt(c) => format!("<A-{}>", c),
+                Key::F(n) => format!("<F{}>", n),
+                _ => format!("<{:?}>", key),
+            }
+        })
+        .collect()
+}
+
+/// What a key sequence is bound to
+#[derive(Clone, Debug)]
+pub enum Binding {
+    Action(Action),
+    // Keys to replay, as `:map` and `:noremap` bind. Unless `remap` is set
+    // they only go through the default bindings.
+    Keys { keys: Vec<Key>, remap: bool },
+}
+
+impl Binding {
+    fn describe(&self) -> String {
+        match self {
+            Binding::Action(action) => action.name().to_string(),
+            Binding::Keys { keys, remap: true } => format_keys(keys),
+            Binding::Keys { keys, remap: false } => format!("* {}", format_keys(keys)),
+        }
+    }
+}
+
+/// How far a sequence of pending keys gets
+pub enum Resolution {
+    // The keys are the start of a longer sequence, so wait for more
+    Wait,
+    // The first `len` keys are bound
+    Bound { binding: Binding, len: usize },
+    // The first key is bound to nothing, so it gets its mode's default
+    // treatment, e.g. being inserted in insert mode
+    Unbound,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeymapMode {
+    Normal,
+    Insert,
+}
+
+impl KeymapMode {
+    pub fn from_name(name: &str) -> Option<KeymapMode> {
+        match name {
+            "normal" => Some(KeymapMode::Normal),
+            "insert" => Some(KeymapMode::Insert),
+            _ => None,
+        }
+    }
+}
+
+/// The bindings of one mode: the defaults, and the user's on top of them
+struct Keymap {
+    defaults: HashMap<Vec<Key>, Binding>,
+    user: HashMap<Vec<Key>, Binding>,
+}
+
+impl Keymap {
+    fn new(defaults: &[(&str, Action)]) -> Keymap {
+        let defaults = defaults
+            .iter()
+            .map(|(keys, action)| (parse_keys(keys, &[]).unwrap(), Binding::Action(*action)))
+            .collect();
+        Keymap {
+            defaults,
+            user: HashMap::new(),
+        }
+    }
+
+    /// The binding of exactly `keys`, and whether a longer sequence starts with them
+    fn lookup(&self, keys: &[Key], remap: bool) -> (Option<&Binding>, bool) {
+        let tables = if remap {
+            vec![&self.user, &self.defaults]
+        } else {
+            vec![&self.defaults]
+        };
+        let exact = tables.iter().find_map(|table| table.get(keys));
+        let longer = tables
+            .iter()
+            .flat_map(|table| table.keys())
+            .any(|bound| bound.len() > keys.len() && bound.starts_with(keys));
+        (exact, longer)
+    }
+}
+
+/// Key bindings of the modes that have them
+pub struct Keymaps {
+    normal: Keymap,
+    insert: Keymap,
+}
+
+impl Keymaps {
+    pub fn new() -> Keymaps {
+        Keymaps {
+            normal: Keymap::new(DEFAULT_NORMAL),
+            insert: Keymap::new(DEFAULT_INSERT),
+        }
+    }
+
+    fn keymap(&self, mode: KeymapMode) -> &Keymap {
+        match mode {
+            KeymapMode::Normal => &self.normal,
+            KeymapMode::Insert => &self.insert,
+        }
+    }
+
+    fn keymap_mut(&mut self, mode: KeymapMode) -> &mut Keymap {
+        match mode {
+            KeymapMode::Normal => &mut self.normal,
+            KeymapMode::Insert => &mut self.insert,
+        }
+    }
+
+    pub fn bind(&mut self, mode: KeymapMode, keys: Vec<Key>, binding: Binding) {
+        self.keymap_mut(mode).user.insert(keys, binding);
+    }
+
+    /// Removes a user binding. Returns false if there was none.
+    pub fn unbind(&mut self, mode: KeymapMode, keys: &[Key]) -> bool {
+        self.keymap_mut(mode).user.remove(keys).is_some()
+    }
+
+    /// The user's bindings in a mode as `keys binding` lines, sorted by keys.
+    /// Bindings that don't remap are marked with a `*`.
+    pub fn describe(&self, mode: KeymapMode) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .keymap(mode)
+            .user
+            .iter()
+            .map(|(keys, binding)| format!("{} {}", format_keys(keys), binding.describe()))
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    /// Works out what the pending keys do. A sequence that is bound but also
+    /// starts a longer one waits for more keys unless `timed_out` is set, in
+    /// which case the longest bound sequence at the start wins.
+    pub fn resolve(&self, mode: KeymapMode, pending: &[Key], remap: bool, timed_out: bool) -> Resolution {
+        let keymap = self.keymap(mode);
+        let (exact, longer) = keymap.lookup(pending, remap);
+        if longer && !timed_out {
+            return Resolution::Wait;
+        }
+        if let Some(binding) = exact {
+            return Resolution::Bound {
+                binding: binding.clone(),
+                len: pending.len(),
+            };
+        }
+        for len in (1..pending.len()).rev() {
+            if let (Some(binding), _) = keymap.lookup(&pending[..len], remap) {
+                return Resolution::Bound {
+                    binding: binding.clone(),
+                    len,
+                };
+            }
+        }
+        Resolution::Unbound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(notation: &str) -> Vec<Key> {
+        parse_keys(notation, &[Key::Char(',')]).unwrap()
+    }
+
+    /// The action the keys resolve to and how many keys it takes
+    fn action(resolution: Resolution) -> Option<(Action, usize)> {
+        match resolution {
+            Resolution::Bound { binding: Binding::Action(action), len } => Some((action, len)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn key_notation_is_parsed() {
+        let parsed = keys("<Esc>:q<CR>");
+        assert_eq!(parsed, vec![Key::Esc, Key::Char(':'), Key::Char('q'), Key::Char('\n')]);
+        assert_eq!(keys("<leader>w<C-s>"), vec![Key::Char(','), Key::Char('w'), Key::Ctrl('s')]);
+        assert_eq!(keys("<lt>"), vec![Key::Char('<')]);
+        assert!(parse_keys("<Nope>", &[]).is_err());
+        assert!(parse_keys("", &[]).is_err());
+    }
+
+    #[test]
+    fn a_prefix_waits_for_more_keys() {
+        let keymaps = Keymaps::new();
+        let normal = KeymapMode::Normal;
+        assert!(matches!(keymaps.resolve(normal, &keys("g"), true, false), Resolution::Wait));
+        // gc is bound, but so is gcc
+        assert!(matches!(keymaps.resolve(normal, &keys("gc"), true, false), Resolution::Wait));
+        let gg = keymaps.resolve(normal, &keys("gg"), true, false);
+        assert_eq!(action(gg), Some((Action::FirstLine, 2)));
+    }
+
+    #[test]
+    fn a_timeout_takes_the_longest_bound_prefix() {
+        let keymaps = Keymaps::new();
+        let normal = KeymapMode::Normal;
+        let gc = keymaps.resolve(normal, &keys("gc"), true, true);
+        assert_eq!(action(gc), Some((Action::Comment, 2)));
+        assert!(matches!(keymaps.resolve(normal, &keys("g"), true, true), Resolution::Unbound));
+    }
+
+    #[test]
+    fn a_key_that_ends_a_sequence_early_leaves_the_bound_start() {
+        let keymaps = Keymaps::new();
+        let gcj = keymaps.resolve(KeymapMode::Normal, &keys("gcj"), true, false);
+        assert_eq!(action(gcj), Some((Action::Comment, 2)));
+        let gx = keymaps.resolve(KeymapMode::Normal, &keys("gx"), true, false);
+        assert!(matches!(gx, Resolution::Unbound));
+    }
+
+    #[test]
+    fn noremap_keys_only_see_the_default_bindings() {
+        let mut keymaps = Keymaps::new();
+        let normal = KeymapMode::Normal;
+        keymaps.bind(normal, keys("j"), Binding::Keys { keys: keys("k"), remap: true });
+
+        let remapped = keymaps.resolve(normal, &keys("j"), true, false);
+        assert!(matches!(remapped, Resolution::Bound { binding: Binding::Keys { .. }, len: 1 }));
+        let default = keymaps.resolve(normal, &keys("j"), false, false);
+        assert_eq!(action(default), Some((Action::MoveDown, 1)));
+    }
+
+    #[test]
+    fn user_bindings_can_be_the_start_of_a_sequence() {
+        let mut keymaps = Keymaps::new();
+        let normal = KeymapMode::Normal;
+        keymaps.bind(normal, keys("<leader>w"), Binding::Action(Action::Save));
+        assert!(matches!(keymaps.resolve(normal, &keys(","), true, false), Resolution::Wait));
+        // Keys replayed without remapping don't see the user's bindings
+        let unmapped = keymaps.resolve(normal, &keys(","), false, false);
+        assert!(matches!(unmapped, Resolution::Unbound));
+        let save = keymaps.resolve(normal, &keys(",w"), true, false);
+        assert_eq!(action(save), Some((Action::Save, 2)));
+
+        assert!(keymaps.unbind(normal, &keys(",w")));
+        assert!(matches!(keymaps.resolve(normal, &keys(","), true, false), Resolution::Unbound));
+    }
+}
@@ -1,12 +1,36 @@
+mod backend;
+mod eventloop;
+mod keymap;
+mod make;
 mod model;
+mod options;
+mod overlay;
 mod terminalcontroller;
 mod terminalview;
 mod command;
+mod comment;
+mod completion;
+mod editorconfig;
+mod errorformat;
+mod finder;
+mod fuzzy;
+mod gitignore;
+mod grep;
+mod indent;
+mod quickfix;
+mod range;
+mod shell;
 mod syntax;
+mod theme;
 
-use clap::{App, Arg};
-use model::Model;
+use backend::TermionBackend;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use eventloop::{EditorEvent, EventLoop};
+use model::{Model, StatusMsg};
+use options::LineNumbers;
 use std::cell::RefCell;
+use std::io;
+use std::path::Path;
 use std::rc::Rc;
 use terminalcontroller::TerminalController;
 use terminalview::TerminalView;
@@ -20,33 +44,148 @@ pub trait View {
 }
 
 pub trait InputHandler {
-    fn process_input(&mut self) -> Result<bool, std::io::Error>;
+    fn process_input(&mut self, evt: EditorEvent) -> Result<bool, std::io::Error>;
 }
 
 fn main() {
     let args = App::new("Ginkgo")
         .version(GINKGO_VERSION)
         .about("Edits a file")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("file")
                 .help("The file to open")
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("wrap")
+                .long("wrap")
+                .short("w")
+                .help("Soft-wrap long lines instead of scrolling horizontally"),
+        )
+        .arg(
+            Arg::with_name("numbers")
+                .long("numbers")
+                .short("n")
+                .takes_value(true)
+                .possible_values(&["off", "absolute", "relative", "hybrid"])
+                .help("Show line numbers in the gutter"),
+        )
+        .arg(
+            Arg::with_name("cursorline")
+                .long("cursorline")
+                .help("Highlight the row the cursor is on"),
+        )
+        .arg(
+            Arg::with_name("syntax-theme")
+                .long("syntax-theme")
+                .takes_value(true)
+                .value_name("THEME")
+                .help("Color syntect-highlighted files with a bundled theme or a .tmTheme file"),
+        )
+        .subcommand(
+            SubCommand::with_name("syntax")
+                .about("Tools for writing syntax definitions")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Validates syntax definition files")
+                        .arg(
+                            Arg::with_name("definitions")
+                                .help("The definition files to check")
+                                .required(true)
+                                .multiple(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("dump")
+                        .about("Prints the highlighted spans of a file")
+                        .arg(
+                            Arg::with_name("file")
+                                .help("The file to highlight")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .help("Print the spans as JSON"),
+                        )
+                        .arg(
+                            Arg::with_name("syntax")
+                                .long("syntax")
+                                .takes_value(true)
+                                .value_name("DEFINITION")
+                                .help("Highlight with this definition instead of the file type's"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("test")
+                        .about("Checks the bundled definitions and compares fixtures with their expected spans")
+                        .arg(
+                            Arg::with_name("dir")
+                                .help("Directory holding the fixtures and their .spans files")
+                                .default_value("test"),
+                        ),
+                ),
+        )
         .get_matches();
 
+    if let Some(args) = args.subcommand_matches("syntax") {
+        let ok = run_syntax_tool(args).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            false
+        });
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     let filename = args.value_of("file").unwrap();
 
-    let model = Rc::new(RefCell::new(Model::new()));
-    let view = TerminalView::new(Rc::clone(&model));
-    let mut controller = TerminalController::new(Rc::clone(&model), &view);
+    let mut model = Model::new();
+    let config_error = model.config.load(Path::new(filename)).err();
+    let command_line = &mut model.config.command_line;
+    if args.is_present("wrap") {
+        command_line.wrap = Some(true);
+    }
+    if args.is_present("cursorline") {
+        command_line.cursorline = Some(true);
+    }
+    if let Some(numbers) = args.value_of("numbers").and_then(LineNumbers::from_name) {
+        command_line.line_numbers = Some(numbers);
+    }
+    if let Some(theme) = args.value_of("syntax-theme") {
+        // Check the theme now, while errors can still be printed
+        #[cfg(feature = "syntect")]
+        if let Err(err) = syntax::syntectengine::load_theme(theme) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        command_line.syntax_theme = Some(theme.to_string());
+    }
+    model.update_options();
+
+    let model = Rc::new(RefCell::new(model));
+    let view = TerminalView::new(Rc::clone(&model), Rc::new(TermionBackend::new()));
+    let events = Rc::new(EventLoop::new().expect("Unable to read from the terminal"));
 
     model.borrow_mut().open_file(filename);
+    if let Some(err) = config_error {
+        model.borrow_mut().set_status_msg(StatusMsg::Error(err.to_string()));
+    }
+
+    let mut controller = TerminalController::new(Rc::clone(&model), &view, Rc::clone(&events));
      
     loop {
         view.draw();
+        let evt = match events.next_event() {
+            Ok(evt) => evt,
+            Err(_) => {
+                view.cleanup();
+                return;
+            }
+        };
         // Returns true to continue processing input, or false to terminate
-        match controller.process_input() {
+        match controller.process_input(evt) {
             Ok(true) => {
                 continue;
             }
@@ -60,3 +199,40 @@ fn main() {
         }
     }
 }
+
+/// Runs a `syntax` subcommand. Returns whether it succeeded.
+fn run_syntax_tool(args: &ArgMatches) -> io::Result<bool> {
+    match args.subcommand() {
+        ("check", Some(args)) => {
+            let mut ok = true;
+            for filename in args.values_of("definitions").into_iter().flatten() {
+                match syntax::syntaxtool::check(Path::new(filename)) {
+                    Ok(()) => println!("{}: ok", filename),
+                    Err(err) => {
+                        ok = false;
+                        eprintln!("{}", err);
+                    }
+                }
+            }
+            Ok(ok)
+        }
+        ("dump", Some(args)) => {
+            let filename = Path::new(args.value_of("file").unwrap());
+            let definition = args.value_of("syntax").map(Path::new);
+            let out = syntax::syntaxtool::dump(filename, definition, args.is_present("json"))?;
+            print!("{}", out);
+            Ok(true)
+        }
+        ("test", Some(args)) => {
+            let outcomes = syntax::syntaxtool::test(Path::new(args.value_of("dir").unwrap()))?;
+            for outcome in outcomes.iter() {
+                match &outcome.failure {
+                    None => println!("{}: ok", outcome.name),
+                    Some(failure) => println!("{}: FAILED\n{}", outcome.name, failure),
+                }
+            }
+            Ok(outcomes.iter().all(|outcome| outcome.failure.is_none()))
+        }
+        _ => Ok(false),
+    }
+}
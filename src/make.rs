@@ -0,0 +1,83 @@
+use crate::errorformat::{ErrorFormat, Parser};
+use crate::quickfix::{Entry, Progress};
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often messages are handed over while the build goes on
+const BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs a build command with the shell in `dir` on a background thread, and
+/// reads the compiler messages in what it prints to stdout and stderr with
+/// `format`. Messages are handed to `found` in batches until the command
+/// exits, `cancel` is set or `found` returns false. A command that fails
+/// hands over the last line it printed. Returns an error if the command
+/// can't be started.
+pub fn run<F>(
+    command: &str,
+    dir: PathBuf,
+    format: ErrorFormat,
+    cancel: Arc<AtomicBool>,
+    mut found: F,
+) -> io::Result<()>
+where
+    F: FnMut(Vec<Entry>, Progress) -> bool + Send + 'static,
+{
+    // Both streams go down one pipe, so that messages stay in order
+    let (output, writer) = io::pipe()?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(&dir)
+        .stdin(Stdio::null())
+        .stdout(writer.try_clone()?)
+        .stderr(writer)
+        .spawn()?;
+    let command = command.to_string();
+
+    thread::spawn(move || {
+        let mut parser = Parser::new(format, dir);
+        let mut batch = vec![];
+        let mut last_line = String::new();
+        let mut sent = Instant::now();
+        for line in BufReader::new(output).split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if cancel.load(Ordering::Relaxed) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end();
+            if !line.trim().is_empty() {
+                last_line = line.trim().to_string();
+            }
+            batch.extend(parser.line(line));
+            if !batch.is_empty() && sent.elapsed() >= BATCH_INTERVAL {
+                if !found(std::mem::take(&mut batch), Progress::Running) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return;
+                }
+                sent = Instant::now();
+            }
+        }
+        let progress = match child.wait() {
+            Ok(status) if status.success() => Progress::Done,
+            Ok(status) if last_line.is_empty() => {
+                Progress::Failed(format!("{} failed: {}", command, status))
+            }
+            Ok(_) => Progress::Failed(last_line),
+            Err(err) => Progress::Failed(err.to_string()),
+        };
+        found(batch, progress);
+    });
+    Ok(())
+}
@@ -1,14 +1,53 @@
+use crate::comment::CommentTokens;
+use crate::indent::{self, IndentRules, IndentStyle};
+use crate::options::{Charset, Config, LineEnding, OptionSet, Options};
+use crate::syntax::detect::FileHints;
+use crate::syntax::syntaxengine::{HLMode, HLSpan};
+use crate::syntax::{self, HLState, SyntaxHighlighter};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::io::ErrorKind;
 use std::path::PathBuf;
+use std::time::Instant;
+
+const UTF8_BOM: &str = "\u{feff}";
+
+// Bytes looked through for a matching bracket before giving up, so that
+// finding one stays quick in very large files
+const BRACKET_SCAN_LIMIT: usize = 200_000;
+
+const BRACKETS: &[(u8, u8)] = &[(b'(', b')'), (b'[', b']'), (b'{', b'}')];
+
+/// Positions of a bracket and the bracket matching it, as (x, y)
+pub type BracketPair = ((usize, usize), (usize, usize));
 
 #[allow(dead_code)]
 pub struct Erow {
     idx: usize,
     contents: String,
+    // Syntax highlighting of contents, kept up to date as the row is edited
+    hl: Vec<HLSpan>,
+    // Highlighter state at the end of this row, where the next row starts from
+    hl_state: HLState,
+}
+
+/// Severity of a sign, which decides the color it is drawn in. The more
+/// severe kinds come first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignKind {
+    Error,
+    Warning,
+    Info,
+}
+
+/// A marker drawn in the gutter's sign column next to a row, e.g. for
+/// diagnostics, version control changes or marks
+pub struct Sign {
+    pub symbol: char,
+    pub kind: SignKind,
 }
 
 pub enum StatusMsg {
@@ -27,6 +66,12 @@ pub struct Model {
     pub path: PathBuf,
     pub ext: String,
     pub status_msg: StatusMsg,
+    // When the status message was last set, so that it can expire
+    pub status_time: Instant,
+    // Label and current input of the prompt line, if a prompt is open
+    pub prompt: Option<(String, String)>,
+    // Term of the last search, whose matches are highlighted until the search ends
+    pub search_term: Option<String>,
 
     pub anchor_start: (usize, usize),
     pub anchor_end: (usize, usize),
@@ -36,6 +81,17 @@ pub struct Model {
 
     pub dirty: bool,
 
+    pub options: Options,
+    // Where the options come from, to work them out again when the filetype
+    // changes or an option is set
+    pub config: Config,
+    // Signs to draw in the gutter, keyed by row index
+    pub signs: HashMap<usize, Sign>,
+
+    highlighter: Option<Box<dyn SyntaxHighlighter>>,
+    // Filetype set with `:set filetype=`, which overrides detection
+    filetype: Option<String>,
+
     rows: Vec<Erow>,
 }
 
@@ -53,23 +109,51 @@ impl Model {
             status_msg: StatusMsg::Normal(String::from(
                 "HELP: Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find",
             )),
+            status_time: Instant::now(),
+            prompt: None,
+            search_term: None,
             anchor_start: (0, 0),
             anchor_end: (0, 0),
             text_selected: false,
             mode: 'N',
-            dirty: false
+            dirty: false,
+            options: Options::new(),
+            config: Config::default(),
+            signs: HashMap::new(),
+            highlighter: None,
+            filetype: None,
         }
     }
 
+    pub fn set_status_msg(&mut self, msg: StatusMsg) {
+        self.status_msg = msg;
+        self.status_time = Instant::now();
+    }
+
     pub fn open_file(&mut self, input_path: &str) {
         self.path = PathBuf::from(input_path);
+        self.filename = self.path.file_name().unwrap().to_str().unwrap().to_string();
+        self.ext = self
+            .path
+            .extension()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default()
+            .to_string();
+
+        // The charset and line endings have to be known before reading
+        if let Err(err) = self.config.load_editorconfig(&self.path) {
+            self.set_status_msg(StatusMsg::Error(err.to_string()));
+        }
+        self.update_options();
 
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(self.path.clone());
-        let reader: BufReader<File>;
+        let mut reader: BufReader<File>;
 
         match f {
             Ok(file) => {
@@ -77,39 +161,108 @@ impl Model {
             }
             Err(err) => match err.kind() {
                 ErrorKind::NotFound => {
-                    self.status_msg =
-                        StatusMsg::Error(format!("Unable to create file {:?}.", input_path));
+                    self.set_status_msg(StatusMsg::Error(format!(
+                        "Unable to create file {:?}.",
+                        input_path
+                    )));
                     return;
                 }
                 ErrorKind::PermissionDenied => {
-                    self.status_msg = StatusMsg::Error(format!(
+                    self.set_status_msg(StatusMsg::Error(format!(
                         "Unable to open {:?}. Permission denied.",
                         input_path
-                    ));
+                    )));
                     return;
                 }
                 other_error => {
-                    self.status_msg = StatusMsg::Error(format!(
+                    self.set_status_msg(StatusMsg::Error(format!(
                         "Problem opening file {:?}. {:?}.",
                         input_path, other_error
-                    ));
+                    )));
                     return;
                 }
             },
         };
 
-        for line_ in reader.lines() {
-            let line = line_.unwrap();
-            self.append_row(line);
+        let mut bytes = vec![];
+        if let Err(err) = reader.read_to_end(&mut bytes) {
+            self.set_status_msg(StatusMsg::Error(format!(
+                "Problem reading file {:?}. {}.",
+                input_path, err
+            )));
+            return;
+        }
+        let contents = self.decode(&bytes);
+        let lines: Vec<&str> = match self.options.end_of_line {
+            LineEnding::Cr => contents.strip_suffix('\r').unwrap_or(&contents).split('\r').collect(),
+            _ => contents.lines().collect(),
+        };
+        for line in lines {
+            self.append_row(line.to_string());
+        }
+        self.select_highlighter();
+        self.detect_indent();
+        self.update_options();
+    }
+
+    /// Works out from the contents whether the file is indented with tabs or
+    /// spaces, and how many, for auto-indent and the Tab key to do the same.
+    /// Rows inside comments and strings are left out, as their indentation
+    /// is often off by one, e.g. in block comments.
+    fn detect_indent(&mut self) {
+        let lines = (0..self.num_rows())
+            .filter(|idx| !self.row_continues_token(*idx))
+            .map(|idx| self.rows[idx].contents.as_str());
+        let (expandtab, tab_width) = match indent::detect_style(lines) {
+            Some(IndentStyle::Tabs) => (Some(false), None),
+            Some(IndentStyle::Spaces(width)) => (Some(true), Some(width)),
+            None => (None, None),
+        };
+        self.config.detected = OptionSet {
+            expandtab,
+            tab_width,
+            ..OptionSet::default()
+        };
+    }
+
+    /// Turns the bytes of a file into text according to the charset option.
+    /// A byte order mark is dropped, and so are bytes that aren't valid UTF-8.
+    fn decode(&self, bytes: &[u8]) -> String {
+        match self.options.charset {
+            Charset::Latin1 => bytes.iter().map(|b| *b as char).collect(),
+            Charset::Utf8 | Charset::Utf8Bom => {
+                let bytes = bytes.strip_prefix(UTF8_BOM.as_bytes()).unwrap_or(bytes);
+                String::from_utf8_lossy(bytes).into_owned()
+            }
+        }
+    }
+
+    /// The buffer as it is saved: lines joined with the configured line
+    /// ending and encoded in the configured charset
+    fn encode(&self) -> Vec<u8> {
+        let eol = self.options.end_of_line.as_str();
+        let mut text = String::new();
+        if self.options.charset == Charset::Utf8Bom {
+            text.push_str(UTF8_BOM);
+        }
+        for (idx, row) in self.rows.iter().enumerate() {
+            let mut line = row.contents.as_str();
+            if self.options.trim_trailing_whitespace {
+                line = line.trim_end();
+            }
+            text.push_str(line);
+            if idx + 1 < self.rows.len() || self.options.insert_final_newline {
+                text.push_str(eol);
+            }
+        }
+        match self.options.charset {
+            // Characters latin1 has no code for are saved as '?'
+            Charset::Latin1 => text
+                .chars()
+                .map(|c| u8::try_from(c as u32).unwrap_or(b'?'))
+                .collect(),
+            Charset::Utf8 | Charset::Utf8Bom => text.into_bytes(),
         }
-        self.filename = self.path.file_name().unwrap().to_str().unwrap().to_string();
-        self.ext = self
-            .path
-            .extension()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or_default()
-            .to_string();
     }
 
     pub fn save_file(&mut self) {
@@ -120,30 +273,30 @@ impl Model {
             .create(true)
             .open(self.path.clone());
 
-        let mut bytes: usize = 0;
-        println!("{}", self.num_rows());
         match f {
             Ok(mut file) => {
-                for row in self.rows.iter() {
-                    let contents = &row.contents;
-                    bytes += file.write(contents.as_bytes()).unwrap();
-                    bytes += file.write(b"\n").unwrap();
+                let bytes = self.encode();
+                if let Err(err) = file.write_all(&bytes) {
+                    self.set_status_msg(StatusMsg::Error(format!(
+                        "Unable to write to {}: {:?}.",
+                        self.filename, err
+                    )));
+                    return;
                 }
-                self.status_msg = StatusMsg::Normal(format!("{} bytes written to disk.", bytes));
+                self.set_status_msg(StatusMsg::Normal(format!(
+                    "{} bytes written to disk.",
+                    bytes.len()
+                )));
             }
             Err(err) => {
-                self.status_msg =
-                    StatusMsg::Error(format!("Unable to write to {}: {:?}.", self.filename, err));
+                self.set_status_msg(StatusMsg::Error(format!(
+                    "Unable to write to {}: {:?}.",
+                    self.filename, err
+                )));
             }
         }
     }
 
-    //TODO: Will do the same as name_file, except deletes the old file with the old name
-    // Should probably call 'save' too...
-    pub fn rename_file(&mut self, new_name: &str) {
-        todo!()
-    }
-
     pub fn name_file(&mut self, new_name: &str) {
         let filename = OsStr::new(new_name);
         self.path.set_file_name(filename);
@@ -155,21 +308,282 @@ impl Model {
             .to_str()
             .unwrap_or_default()
             .to_string();
+        self.select_highlighter();
+        self.update_options();
+    }
+
+    /// Works the options out again from the config, for the current filetype
+    pub fn update_options(&mut self) {
+        let syntax_name = self.syntax_name().map(String::from);
+        let filetypes: Vec<&str> = self
+            .filetype
+            .iter()
+            .chain(syntax_name.iter())
+            .map(String::as_str)
+            .chain(Some(self.ext.as_str()).filter(|ext| !ext.is_empty()))
+            .collect();
+        let options = self.config.options(&filetypes);
+        let rehighlight = options.syntax_theme != self.options.syntax_theme;
+        self.options = options;
+        if rehighlight {
+            self.select_highlighter();
+        }
+    }
+
+    /// Changes an option for the rest of the session, as `:set name=value` does
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        self.config.set(name, value)?;
+        self.update_options();
+        Ok(())
+    }
+
+    /// Picks the syntax highlighter for the file and re-highlights every row
+    fn select_highlighter(&mut self) {
+        self.highlighter = match &self.filetype {
+            Some(filetype) => syntax::highlighter_for_filetype(filetype, &self.options),
+            None => {
+                let lines: Vec<&str> = self.rows.iter().map(|row| row.contents.as_str()).collect();
+                let hints = FileHints::new(&self.path, &lines);
+                syntax::highlighter_for_file(&hints, None, &self.options)
+            }
+        };
+        self.rehighlight();
+    }
+
+    /// Highlights the buffer as the given filetype from now on. Returns false,
+    /// leaving the highlighting alone, if no syntax is known by that name.
+    pub fn set_filetype(&mut self, filetype: &str) -> bool {
+        match syntax::highlighter_for_filetype(filetype, &self.options) {
+            Some(highlighter) => {
+                self.filetype = Some(filetype.to_string());
+                self.highlighter = Some(highlighter);
+                self.rehighlight();
+                self.update_options();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn rehighlight(&mut self) {
+        let mut state = HLState::default();
+        for row in self.rows.iter_mut() {
+            row.hl = vec![];
+            if let Some(highlighter) = &self.highlighter {
+                let (hl, next) = highlighter.highlight_row(&row.contents, &state);
+                row.hl = hl;
+                state = next;
+            }
+            row.hl_state = state.clone();
+        }
+    }
+
+    /// Name of the language the buffer is highlighted as, if any
+    pub fn syntax_name(&self) -> Option<&str> {
+        self.highlighter.as_ref().map(|hl| hl.name())
+    }
+
+    /// Re-highlights a row after it changed. Following rows are re-highlighted
+    /// only while the state they start from keeps changing, so an edit inside
+    /// a row usually costs just that row.
+    fn update_row_highlight(&mut self, row_idx: usize) {
+        let highlighter = match &self.highlighter {
+            Some(highlighter) => highlighter,
+            None => return,
+        };
+
+        for idx in row_idx..self.rows.len() {
+            let state = match idx {
+                0 => HLState::default(),
+                _ => self.rows[idx - 1].hl_state.clone(),
+            };
+            let row = &mut self.rows[idx];
+            let (hl, next) = highlighter.highlight_row(&row.contents, &state);
+            row.hl = hl;
+            if next == row.hl_state {
+                return;
+            }
+            row.hl_state = next;
+        }
+    }
+
+    /// State a newly inserted row at `idx` should pretend to have ended in. Using
+    /// the state the following row used to start from makes sure that row gets
+    /// re-highlighted if the new row changes it.
+    fn inherited_hl_state(&self, idx: usize) -> HLState {
+        match idx {
+            0 => HLState::default(),
+            _ => self.rows[idx - 1].hl_state.clone(),
+        }
+    }
+
+    /// How the buffer's language is indented, with brace rules for languages
+    /// that don't say
+    pub fn indent_rules(&self) -> &IndentRules {
+        self.highlighter
+            .as_ref()
+            .and_then(|highlighter| highlighter.indent_rules())
+            .unwrap_or_else(|| IndentRules::braces())
+    }
+
+    /// Characters typed in pairs in the buffer's language, as (opening, closing)
+    pub fn pairs(&self) -> &[(char, char)] {
+        self.highlighter
+            .as_ref()
+            .and_then(|highlighter| highlighter.pairs())
+            .unwrap_or(syntax::DEFAULT_PAIRS)
+    }
+
+    /// How the buffer's language writes comments, if it is known
+    pub fn comment_tokens(&self) -> Option<&CommentTokens> {
+        self.highlighter
+            .as_ref()
+            .and_then(|highlighter| highlighter.comment_tokens())
+    }
+
+    /// A row with the text of its comments and strings blanked out, so that
+    /// indentation rules only see code. Byte offsets stay the same.
+    pub fn row_code(&self, row_idx: usize) -> String {
+        let row = match self.rows.get(row_idx) {
+            Some(row) => row,
+            None => return String::new(),
+        };
+        let mut code = String::with_capacity(row.contents.len());
+        let mut spans = row
+            .hl
+            .iter()
+            .filter(|span| matches!(span.mode, HLMode::Comment | HLMode::String))
+            .peekable();
+        for (idx, c) in row.contents.char_indices() {
+            while spans.peek().is_some_and(|span| span.end <= idx) {
+                spans.next();
+            }
+            match spans.peek() {
+                Some(span) if span.start <= idx => {
+                    code.extend(std::iter::repeat_n(' ', c.len_utf8()))
+                }
+                _ => code.push(c),
+            }
+        }
+        code
+    }
+
+    /// Where the partner of the bracket at `(x, y)` is, if that is a bracket
+    /// in code. Brackets inside comments and strings are skipped, and the
+    /// search gives up after `BRACKET_SCAN_LIMIT` bytes.
+    pub fn matching_bracket(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        let code = self.row_code(y);
+        let bracket = *code.as_bytes().get(x)?;
+        let (open, close, forward) = BRACKETS.iter().find_map(|(open, close)| {
+            match bracket {
+                b if b == *open => Some((*open, *close, true)),
+                b if b == *close => Some((*close, *open, false)),
+                _ => None,
+            }
+        })?;
+
+        let mut depth = 0;
+        let mut scanned = 0;
+        let mut row_idx = y;
+        let mut code = code;
+        loop {
+            // Positions to look at in this row, moving away from the bracket
+            let positions: Box<dyn Iterator<Item = usize>> = match (forward, row_idx == y) {
+                (true, true) => Box::new(x + 1..code.len()),
+                (true, false) => Box::new(0..code.len()),
+                (false, true) => Box::new((0..x).rev()),
+                (false, false) => Box::new((0..code.len()).rev()),
+            };
+            for idx in positions {
+                match code.as_bytes()[idx] {
+                    b if b == open => depth += 1,
+                    b if b == close && depth == 0 => return Some((idx, row_idx)),
+                    b if b == close => depth -= 1,
+                    _ => {}
+                }
+            }
+            scanned += code.len() + 1;
+            if scanned > BRACKET_SCAN_LIMIT {
+                return None;
+            }
+            row_idx = match forward {
+                true if row_idx + 1 < self.num_rows() => row_idx + 1,
+                false if row_idx > 0 => row_idx - 1,
+                _ => return None,
+            };
+            code = self.row_code(row_idx);
+        }
+    }
+
+    /// The bracket under the cursor together with its partner. In insert
+    /// mode, where the cursor sits between characters, and at the end of a
+    /// row, the bracket just before the cursor counts too.
+    pub fn bracket_pair_at_cursor(&self) -> Option<BracketPair> {
+        if self.cy >= self.num_rows() {
+            return None;
+        }
+        let mut candidates = vec![self.cx];
+        if (self.mode == 'I' || self.cx == self.cur_row_len()) && self.cx > 0 {
+            candidates.push(self.cx - 1);
+        }
+        candidates.into_iter().find_map(|x| {
+            self.matching_bracket(x, self.cy)
+                .map(|partner| ((x, self.cy), partner))
+        })
+    }
+
+    /// Whether a row begins inside a comment or string carried over from the
+    /// rows above, whose indentation is left as written
+    pub fn row_continues_token(&self, row_idx: usize) -> bool {
+        if row_idx == 0 || self.rows[row_idx - 1].hl_state == HLState::Start {
+            return false;
+        }
+        let contents = &self.rows[row_idx].contents;
+        let start = indent::leading_whitespace(contents).len();
+        start < contents.len() && self.row_code(row_idx)[start..].starts_with(' ')
+    }
+
+    /// The closest row above `row_idx` that the indentation of the rows
+    /// below can go by: one that isn't blank and doesn't start inside a
+    /// comment or string
+    pub fn indent_base_row(&self, row_idx: usize) -> Option<usize> {
+        (0..row_idx.min(self.num_rows())).rev().find(|idx| {
+            !self.rows[*idx].contents.trim().is_empty() && !self.row_continues_token(*idx)
+        })
+    }
+
+    /// Width the indentation of a row at `row_idx` with the given code should
+    /// be, going by the rows above it
+    pub fn expected_indent(&self, row_idx: usize, code: &str) -> usize {
+        let prev = self
+            .indent_base_row(row_idx)
+            .map(|idx| (self.rows[idx].contents.as_str(), self.row_code(idx)));
+        self.indent_rules().indent_after(
+            prev.as_ref().map(|(contents, code)| (*contents, code.as_str())),
+            code,
+            self.options.tab_width,
+        )
+    }
+
+    /// Highlighted spans of a row, in order
+    pub fn get_row_highlight(&self, row_idx: usize) -> &[HLSpan] {
+        &self.rows.get(row_idx).unwrap().hl
     }
 
-    ///
     fn append_row(&mut self, line: String) {
         let num_rows = self.num_rows();
 
         let row = Erow {
             idx: num_rows,
             contents: line,
+            hl: vec![],
+            hl_state: self.inherited_hl_state(num_rows),
         };
 
         self.rows.insert(num_rows, row);
+        self.update_row_highlight(num_rows);
     }
 
-    ///
     fn insert_row(&mut self, idx: usize, line: &str) {
         let num_rows = self.num_rows();
         if idx > num_rows {
@@ -179,6 +593,8 @@ impl Model {
         let row = Erow {
             idx,
             contents: line.to_string(),
+            hl: vec![],
+            hl_state: self.inherited_hl_state(idx),
         };
 
         for i in idx..num_rows {
@@ -186,10 +602,14 @@ impl Model {
         }
 
         self.rows.insert(idx, row);
+        self.update_row_highlight(idx);
     }
 
-    ///
     pub fn insert_newline(&mut self) {
+        let num_rows = self.num_rows();
+        if self.cy == num_rows {
+            self.insert_row(num_rows, "");
+        }
         let cur_row = self.rows.get_mut(self.cy).unwrap();
         let cur_row_len = cur_row.contents.len();
 
@@ -205,12 +625,12 @@ impl Model {
                 .unwrap()
                 .contents
                 .truncate(self.cx);
+            self.update_row_highlight(self.cy);
         }
         self.cy += 1;
         self.cx = 0;
     }
 
-    ///
     pub fn insert_char(&mut self, c: char) {
         let num_rows = self.num_rows();
         if self.cy == num_rows {
@@ -224,6 +644,7 @@ impl Model {
             at = cur_row.contents.len()
         }
         cur_row.contents.insert(at, c);
+        self.update_row_highlight(self.cy);
 
         self.cx += 1;
     }
@@ -235,11 +656,14 @@ impl Model {
     /// # Arguments
     ///
     /// * `contents` - The string to insert
-    ///
     pub fn insert_string(&mut self, contents: &str) {
+        let num_rows = self.num_rows();
+        if self.cy == num_rows {
+            self.insert_row(num_rows, "");
+        }
         // Initialize the buffer to the current line prior to the cursor
         let mut buffer: String =
-            (&self.rows.get(self.cy).unwrap().contents[0..self.cx]).to_string();
+            self.rows.get(self.cy).unwrap().contents[0..self.cx].to_string();
         // Add the contents we are pushing
         buffer.push_str(contents);
         // Add the end after the cursor
@@ -249,9 +673,7 @@ impl Model {
         self.delete_row(idx);
 
         // Insert each line as a new row, deliminating by newline characters in the buffer
-        let mut line_len = 0;
         for line in buffer.split('\n') {
-            line_len = line.len();
             self.insert_row(idx, line);
             idx += 1;
         }
@@ -264,8 +686,10 @@ impl Model {
             // If the insertion was within the same line, just move cx forward
             self.cx += contents.len();
         } else {
-            // If we ended up adding additional lines, adjust cx and cy
-            self.cx = line_len;
+            // If we ended up adding additional lines, adjust cx and cy. The
+            // cursor goes after the last line of the contents, before the
+            // rest of the row the string was inserted into.
+            self.cx = contents.rsplit('\n').next().unwrap_or_default().len();
             self.cy = idx;
         }
     }
@@ -277,6 +701,8 @@ impl Model {
         for i in row_idx..num_rows {
             self.rows.get_mut(i).unwrap().idx -= 1;
         }
+        // The row that moved up now starts from a different state
+        self.update_row_highlight(row_idx);
     }
 
     pub fn delete_rows(&mut self, row_idx: usize, num_removed: usize) {
@@ -287,6 +713,7 @@ impl Model {
         for i in row_idx..num_rows {
             self.rows.get_mut(i).unwrap().idx -= num_removed;
         }
+        self.update_row_highlight(row_idx);
     }
 
     pub fn delete_char(&mut self) {
@@ -305,12 +732,14 @@ impl Model {
                 return;
             }
             cur_row.remove(self.cx.saturating_sub(1));
+            self.update_row_highlight(self.cy);
             self.cx -= 1;
         } else {
             let cur_row = self.rows.get(self.cy).unwrap().contents.clone();
             let prev_row = &mut self.rows.get_mut(self.cy - 1).unwrap().contents;
             self.cx = prev_row.len();
             prev_row.push_str(&cur_row);
+            self.update_row_highlight(self.cy - 1);
             self.delete_row(self.cy);
             self.cy -= 1;
         }
@@ -343,6 +772,7 @@ impl Model {
         let start_row = &mut self.rows.get_mut(anchor_start.1).unwrap().contents;
         start_row.truncate(anchor_start.0);
         start_row.push_str(&end_row[anchor_end.0..]);
+        self.update_row_highlight(anchor_start.1);
 
         // Delete the complete lines in between the selection's starting and ending rows
         let num_deleted = anchor_end.1 - anchor_start.1;
@@ -430,3 +860,100 @@ impl Model {
         self.rows.len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Opens a file called `name` holding `contents`, in a directory of its own
+    fn open(name: &str, contents: &str) -> Model {
+        let dir = format!("ginkgo-model-{}-{}", std::process::id(), name);
+        let dir = std::env::temp_dir().join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        let mut model = Model::new();
+        model.open_file(path.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        model
+    }
+
+    #[test]
+    fn opening_a_file_picks_up_its_indentation() {
+        let model = open("spaces.py", "def f():\n    if x:\n        y()\n");
+        assert_eq!((model.options.expandtab, model.options.tab_width), (true, 4));
+        let model = open("tabs.c", "int f() {\n\tif (x) {\n\t\ty();\n\t}\n}\n");
+        assert_eq!((model.options.expandtab, model.options.tab_width), (false, 4));
+        let model = open("flat.txt", "a\nb\n");
+        let defaults = Options::new();
+        assert_eq!(model.options.expandtab, defaults.expandtab);
+        assert_eq!(model.options.tab_width, defaults.tab_width);
+    }
+
+    #[test]
+    fn comment_rows_are_left_out_of_detection() {
+        // The comment's steps of four would otherwise outnumber the code's steps of two
+        let comment = "/*\n    a\n        b\n    c\n        d\n    e\n        f\n*/\n";
+        let text = format!("{}int f() {{\n  x();\n  if (y) {{\n    z();\n  }}\n}}\n", comment);
+        let model = open("comment.c", &text);
+        assert_eq!((model.options.expandtab, model.options.tab_width), (true, 2));
+    }
+
+    #[test]
+    fn brackets_in_comments_and_strings_are_skipped() {
+        let model = open("call.c", "f(a, \")\", /* ) */ b);\n");
+        assert_eq!(model.matching_bracket(1, 0), Some((19, 0)));
+        assert_eq!(model.matching_bracket(19, 0), Some((1, 0)));
+        // Brackets in strings and comments have no partners of their own
+        assert_eq!(model.matching_bracket(6, 0), None);
+        assert_eq!(model.matching_bracket(13, 0), None);
+    }
+
+    #[test]
+    fn brackets_match_across_rows() {
+        let model = open("rows.c", "int f() {\n\tif (x) {\n\t\ty();\n\t}\n}\n");
+        assert_eq!(model.matching_bracket(8, 0), Some((0, 4)));
+        assert_eq!(model.matching_bracket(0, 4), Some((8, 0)));
+        assert_eq!(model.matching_bracket(8, 1), Some((1, 3)));
+        assert_eq!(model.matching_bracket(5, 0), Some((6, 0)));
+        // Not a bracket, or one without a partner
+        assert_eq!(model.matching_bracket(0, 0), None);
+        let model = open("open.c", "f(\n\tx\n");
+        assert_eq!(model.matching_bracket(1, 0), None);
+    }
+
+    #[test]
+    fn the_search_gives_up_past_the_scan_limit() {
+        let row = "x".repeat(99);
+        let rows = BRACKET_SCAN_LIMIT / 100;
+        let near = format!("(\n{}\n)", vec![row.as_str(); rows - 10].join("\n"));
+        let far = format!("(\n{}\n)", vec![row.as_str(); rows + 10].join("\n"));
+
+        let mut model = Model::new();
+        model.insert_string(&near);
+        assert_eq!(model.matching_bracket(0, 0), Some((0, rows - 9)));
+        let mut model = Model::new();
+        model.insert_string(&far);
+        assert_eq!(model.matching_bracket(0, 0), None);
+        assert_eq!(model.matching_bracket(0, rows + 11), None);
+    }
+
+    #[test]
+    fn the_bracket_before_the_cursor_counts_in_insert_mode_and_at_the_end() {
+        let mut model = open("pair.c", "g(h(x));\n");
+        model.set_cursor(3, 0);
+        assert_eq!(model.bracket_pair_at_cursor(), Some(((3, 0), (5, 0))));
+        model.set_cursor(4, 0);
+        assert_eq!(model.bracket_pair_at_cursor(), None);
+        model.mode = 'I';
+        assert_eq!(model.bracket_pair_at_cursor(), Some(((3, 0), (5, 0))));
+        model.mode = 'N';
+        model.set_cursor(8, 0);
+        assert_eq!(model.bracket_pair_at_cursor(), None);
+
+        let mut model = open("end.c", "{\n}\n");
+        model.set_cursor(1, 0);
+        assert_eq!(model.bracket_pair_at_cursor(), Some(((0, 0), (0, 1))));
+    }
+}
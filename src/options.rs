@@ -0,0 +1,587 @@
+use crate::editorconfig;
+use crate::errorformat;
+use crate::theme::DEFAULT_THEME;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+// Name of the user's config file inside the config directory
+const CONFIG_FILE: &str = "config.yaml";
+
+// Name of a project's config file, looked for in the edited file's directory
+// and every directory above it
+const PROJECT_CONFIG_FILE: &str = ".ginkgo.yaml";
+
+/// Directory holding the user's configuration: `$XDG_CONFIG_HOME/ginkgo`,
+/// or `~/.config/ginkgo` when that isn't set
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("ginkgo"))
+}
+
+/// How the gutter numbers each row
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineNumbers {
+    Off,
+    // Every row shows its own line number
+    Absolute,
+    // Every row shows its distance from the cursor row
+    Relative,
+    // Relative numbers, except the cursor row which shows its line number
+    Hybrid,
+}
+
+impl LineNumbers {
+    pub fn from_name(name: &str) -> Option<LineNumbers> {
+        match name {
+            "off" => Some(LineNumbers::Off),
+            "absolute" => Some(LineNumbers::Absolute),
+            "relative" => Some(LineNumbers::Relative),
+            "hybrid" => Some(LineNumbers::Hybrid),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineNumbers::Off => "off",
+            LineNumbers::Absolute => "absolute",
+            LineNumbers::Relative => "relative",
+            LineNumbers::Hybrid => "hybrid",
+        }
+    }
+}
+
+/// What ends each line of a saved file
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn from_name(name: &str) -> Option<LineEnding> {
+        match name {
+            "lf" => Some(LineEnding::Lf),
+            "crlf" => Some(LineEnding::Crlf),
+            "cr" => Some(LineEnding::Cr),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "lf",
+            LineEnding::Crlf => "crlf",
+            LineEnding::Cr => "cr",
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// Encoding files are read and saved in
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum Charset {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    // UTF-8 starting with a byte order mark
+    #[serde(rename = "utf-8-bom")]
+    Utf8Bom,
+    #[serde(rename = "latin1")]
+    Latin1,
+}
+
+impl Charset {
+    pub fn from_name(name: &str) -> Option<Charset> {
+        match name {
+            "utf-8" => Some(Charset::Utf8),
+            "utf-8-bom" => Some(Charset::Utf8Bom),
+            "latin1" => Some(Charset::Latin1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Charset::Utf8 => "utf-8",
+            Charset::Utf8Bom => "utf-8-bom",
+            Charset::Latin1 => "latin1",
+        }
+    }
+}
+
+/// Editor options that change how buffers are displayed and edited
+#[derive(Clone, PartialEq)]
+pub struct Options {
+    // Columns per indentation level, and how many spaces Tab inserts with expandtab
+    pub tab_width: usize,
+    // Insert spaces instead of a tab character when Tab is pressed
+    pub expandtab: bool,
+    // Indent new lines to match the code above them
+    pub autoindent: bool,
+    // Type the closing bracket or quote along with the opening one
+    pub autopairs: bool,
+    // Wrap long rows across several screen lines instead of scrolling horizontally
+    pub wrap: bool,
+    // When wrapping, break rows at word boundaries where possible
+    pub wrap_at_words: bool,
+    // Drawn at the start of every continuation line of a wrapped row
+    pub wrap_indicator: String,
+    pub line_numbers: LineNumbers,
+    // Always reserve the sign column, even when no row has a sign
+    pub sign_column: bool,
+    // Give the row the cursor is on its own background
+    pub cursorline: bool,
+    // Theme the syntect highlighter colors rows with, either a bundled theme
+    // name or the path of a .tmTheme file. Without one, highlight modes are
+    // drawn in the editor's own colors.
+    pub syntax_theme: Option<String>,
+    // Name of the color theme for highlight modes and the UI
+    pub theme: String,
+    // Seconds between automatic saves of a modified buffer, or 0 to never autosave
+    pub autosave: u64,
+    // How many times Ctrl-Q has to be pressed to quit with unsaved changes
+    pub quit_times: u8,
+    // Keys `<leader>` stands for in key bindings, in key notation
+    pub leader: String,
+    pub end_of_line: LineEnding,
+    pub charset: Charset,
+    // Strip whitespace at the end of lines when saving
+    pub trim_trailing_whitespace: bool,
+    // End the last line of a saved file with a line ending too
+    pub insert_final_newline: bool,
+    // Column past which text is marked as too long, or 0 for no limit
+    pub max_line_length: usize,
+    // Program `:grep` runs with the pattern and path as its last arguments,
+    // which prints matches as file:line:col:text or file:line:text. When
+    // empty, or when it can't be run, the editor searches by itself.
+    pub grep_program: String,
+    // Shell command `:make` runs, with the arguments given to `:make` added
+    pub make_program: String,
+    // Patterns for reading compiler messages out of the output of `:make`,
+    // as described in errorformat.rs
+    pub error_format: String,
+    // Milliseconds to wait for the next key of a sequence when the keys so
+    // far are bound but also start a longer binding
+    pub keymap_timeout: u64,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            tab_width: 4,
+            expandtab: false,
+            autoindent: true,
+            autopairs: true,
+            wrap: false,
+            wrap_at_words: true,
+            wrap_indicator: String::from("↪ "),
+            line_numbers: LineNumbers::Off,
+            sign_column: false,
+            cursorline: false,
+            syntax_theme: None,
+            theme: String::from(DEFAULT_THEME),
+            autosave: 0,
+            quit_times: 3,
+            leader: String::from("\\"),
+            keymap_timeout: 1000,
+            end_of_line: LineEnding::Lf,
+            charset: Charset::Utf8,
+            trim_trailing_whitespace: false,
+            insert_final_newline: true,
+            max_line_length: 0,
+            grep_program: String::new(),
+            make_program: String::from("make"),
+            error_format: String::from(errorformat::DEFAULT_FORMAT),
+        }
+    }
+
+    /// Current value of an option as `:set` shows it, or None if there is no
+    /// option by that name
+    pub fn value(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "tab_width" => self.tab_width.to_string(),
+            "expandtab" => self.expandtab.to_string(),
+            "autoindent" => self.autoindent.to_string(),
+            "autopairs" => self.autopairs.to_string(),
+            "wrap" => self.wrap.to_string(),
+            "wrap_at_words" => self.wrap_at_words.to_string(),
+            "wrap_indicator" => self.wrap_indicator.clone(),
+            "line_numbers" => self.line_numbers.name().to_string(),
+            "sign_column" => self.sign_column.to_string(),
+            "cursorline" => self.cursorline.to_string(),
+            "syntax_theme" => self.syntax_theme.clone().unwrap_or_default(),
+            "theme" => self.theme.clone(),
+            "autosave" => self.autosave.to_string(),
+            "quit_times" => self.quit_times.to_string(),
+            "leader" => self.leader.clone(),
+            "keymap_timeout" => self.keymap_timeout.to_string(),
+            "end_of_line" => self.end_of_line.name().to_string(),
+            "charset" => self.charset.name().to_string(),
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace.to_string(),
+            "insert_final_newline" => self.insert_final_newline.to_string(),
+            "max_line_length" => self.max_line_length.to_string(),
+            "grep_program" => self.grep_program.clone(),
+            "make_program" => self.make_program.clone(),
+            "error_format" => self.error_format.clone(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Options as written in a config file. Options left out keep the value
+/// they get from the defaults or an earlier file, and `filetypes` holds
+/// options that only apply to files of a given language.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OptionSet {
+    pub tab_width: Option<usize>,
+    pub expandtab: Option<bool>,
+    pub autoindent: Option<bool>,
+    pub autopairs: Option<bool>,
+    pub wrap: Option<bool>,
+    pub wrap_at_words: Option<bool>,
+    pub wrap_indicator: Option<String>,
+    pub line_numbers: Option<LineNumbers>,
+    pub sign_column: Option<bool>,
+    pub cursorline: Option<bool>,
+    pub syntax_theme: Option<String>,
+    pub theme: Option<String>,
+    pub autosave: Option<u64>,
+    pub quit_times: Option<u8>,
+    pub leader: Option<String>,
+    pub keymap_timeout: Option<u64>,
+    pub end_of_line: Option<LineEnding>,
+    pub charset: Option<Charset>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+    pub max_line_length: Option<usize>,
+    pub grep_program: Option<String>,
+    pub make_program: Option<String>,
+    pub error_format: Option<String>,
+    // Key bindings by mode ("normal" or "insert"), each mapping keys in key
+    // notation to the name of an action
+    pub keymap: BTreeMap<String, BTreeMap<String, String>>,
+    // Keyed by language name or file extension, e.g. "python" or "py"
+    pub filetypes: HashMap<String, OptionSet>,
+}
+
+impl OptionSet {
+    pub fn read(filename: &Path) -> io::Result<OptionSet> {
+        let file = File::open(filename)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", filename.display(), err)))?;
+        let reader = BufReader::new(file);
+        serde_yaml::from_reader(reader).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", filename.display(), err),
+            )
+        })
+    }
+
+    /// Sets an option from its text form, as given to `:set name=value`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<Option<T>, String> {
+            value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid value for {}: {}", name, value))
+        }
+        match name {
+            "tab_width" => {
+                self.tab_width = parse(name, value)?.filter(|width| *width > 0);
+                if self.tab_width.is_none() {
+                    return Err(format!("Invalid value for {}: {}", name, value));
+                }
+            }
+            "expandtab" => self.expandtab = parse(name, value)?,
+            "autoindent" => self.autoindent = parse(name, value)?,
+            "autopairs" => self.autopairs = parse(name, value)?,
+            "wrap" => self.wrap = parse(name, value)?,
+            "wrap_at_words" => self.wrap_at_words = parse(name, value)?,
+            "wrap_indicator" => self.wrap_indicator = Some(value.to_string()),
+            "line_numbers" => {
+                self.line_numbers = LineNumbers::from_name(value);
+                if self.line_numbers.is_none() {
+                    return Err(format!("Invalid value for {}: {}", name, value));
+                }
+            }
+            "sign_column" => self.sign_column = parse(name, value)?,
+            "cursorline" => self.cursorline = parse(name, value)?,
+            "syntax_theme" => self.syntax_theme = Some(value.to_string()),
+            "theme" => self.theme = Some(value.to_string()),
+            "autosave" => self.autosave = parse(name, value)?,
+            "quit_times" => self.quit_times = parse(name, value)?,
+            "leader" => self.leader = Some(value.to_string()),
+            "keymap_timeout" => self.keymap_timeout = parse(name, value)?,
+            "end_of_line" => {
+                self.end_of_line = LineEnding::from_name(value);
+                if self.end_of_line.is_none() {
+                    return Err(format!("Invalid value for {}: {}", name, value));
+                }
+            }
+            "charset" => {
+                self.charset = Charset::from_name(value);
+                if self.charset.is_none() {
+                    return Err(format!("Invalid value for {}: {}", name, value));
+                }
+            }
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace = parse(name, value)?,
+            "insert_final_newline" => self.insert_final_newline = parse(name, value)?,
+            "max_line_length" => self.max_line_length = parse(name, value)?,
+            "grep_program" => self.grep_program = Some(value.to_string()),
+            "make_program" => self.make_program = Some(value.to_string()),
+            "error_format" => self.error_format = Some(value.to_string()),
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+        Ok(())
+    }
+
+    /// Overrides the options this set gives a value
+    fn apply(&self, options: &mut Options) {
+        if let Some(tab_width) = self.tab_width {
+            options.tab_width = tab_width.max(1);
+        }
+        if let Some(expandtab) = self.expandtab {
+            options.expandtab = expandtab;
+        }
+        if let Some(autoindent) = self.autoindent {
+            options.autoindent = autoindent;
+        }
+        if let Some(autopairs) = self.autopairs {
+            options.autopairs = autopairs;
+        }
+        if let Some(wrap) = self.wrap {
+            options.wrap = wrap;
+        }
+        if let Some(wrap_at_words) = self.wrap_at_words {
+            options.wrap_at_words = wrap_at_words;
+        }
+        if let Some(wrap_indicator) = &self.wrap_indicator {
+            options.wrap_indicator = wrap_indicator.clone();
+        }
+        if let Some(line_numbers) = self.line_numbers {
+            options.line_numbers = line_numbers;
+        }
+        if let Some(sign_column) = self.sign_column {
+            options.sign_column = sign_column;
+        }
+        if let Some(cursorline) = self.cursorline {
+            options.cursorline = cursorline;
+        }
+        if let Some(syntax_theme) = &self.syntax_theme {
+            options.syntax_theme = Some(syntax_theme.clone()).filter(|theme| !theme.is_empty());
+        }
+        if let Some(theme) = &self.theme {
+            options.theme = theme.clone();
+        }
+        if let Some(autosave) = self.autosave {
+            options.autosave = autosave;
+        }
+        if let Some(quit_times) = self.quit_times {
+            options.quit_times = quit_times.max(1);
+        }
+        if let Some(leader) = &self.leader {
+            options.leader = leader.clone();
+        }
+        if let Some(keymap_timeout) = self.keymap_timeout {
+            options.keymap_timeout = keymap_timeout;
+        }
+        if let Some(end_of_line) = self.end_of_line {
+            options.end_of_line = end_of_line;
+        }
+        if let Some(charset) = self.charset {
+            options.charset = charset;
+        }
+        if let Some(trim_trailing_whitespace) = self.trim_trailing_whitespace {
+            options.trim_trailing_whitespace = trim_trailing_whitespace;
+        }
+        if let Some(insert_final_newline) = self.insert_final_newline {
+            options.insert_final_newline = insert_final_newline;
+        }
+        if let Some(max_line_length) = self.max_line_length {
+            options.max_line_length = max_line_length;
+        }
+        if let Some(grep_program) = &self.grep_program {
+            options.grep_program = grep_program.clone();
+        }
+        if let Some(make_program) = &self.make_program {
+            options.make_program = make_program.clone();
+        }
+        if let Some(error_format) = &self.error_format {
+            options.error_format = error_format.clone();
+        }
+    }
+
+    /// The section for a filetype, which may be named by any of `filetypes`
+    /// in any case
+    fn section(&self, filetypes: &[&str]) -> Option<&OptionSet> {
+        self.filetypes.iter().find_map(|(name, section)| {
+            filetypes
+                .iter()
+                .any(|filetype| filetype.eq_ignore_ascii_case(name))
+                .then_some(section)
+        })
+    }
+}
+
+/// Where options come from, from lowest to highest priority: the defaults,
+/// the indentation found in the edited file, the user's config file, its
+/// `.editorconfig` files, the project's config file, the command line and
+/// finally `:set` while editing
+#[derive(Default)]
+pub struct Config {
+    user: Option<OptionSet>,
+    pub detected: OptionSet,
+    editorconfig: OptionSet,
+    project: Option<OptionSet>,
+    pub command_line: OptionSet,
+    session: OptionSet,
+}
+
+impl Config {
+    /// Reads the user's config file and the config file of the project
+    /// `filename` belongs to, where they exist. A file that fails to read is
+    /// left out, and the first such error is returned.
+    pub fn load(&mut self, filename: &Path) -> io::Result<()> {
+        let read = |path: Option<PathBuf>| match path {
+            Some(path) if path.is_file() => OptionSet::read(&path).map(Some),
+            _ => Ok(None),
+        };
+        let user = read(config_dir().map(|dir| dir.join(CONFIG_FILE)));
+        let project = read(find_project_config(filename));
+        self.user = user.as_ref().ok().cloned().flatten();
+        self.project = project.as_ref().ok().cloned().flatten();
+        user.and(project).map(|_| ())
+    }
+
+    /// Reads the `.editorconfig` settings for `filename`, replacing those of
+    /// any file opened before
+    pub fn load_editorconfig(&mut self, filename: &Path) -> io::Result<()> {
+        self.editorconfig = OptionSet::default();
+        self.editorconfig = editorconfig::load(filename)?;
+        Ok(())
+    }
+
+    /// Changes an option for the rest of the session
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        self.session.set(name, value)
+    }
+
+    /// Key bindings from the config files as (mode, keys, action) names, in
+    /// the order they should be bound
+    pub fn key_bindings(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.user.iter().chain(self.project.iter()).flat_map(|file| {
+            file.keymap.iter().flat_map(|(mode, bindings)| {
+                bindings
+                    .iter()
+                    .map(move |(keys, action)| (mode.as_str(), keys.as_str(), action.as_str()))
+            })
+        })
+    }
+
+    /// Options for a file of the given filetype, which may be known by
+    /// several names such as its language and its extension. Each source
+    /// applies its filetype section right after its general options.
+    pub fn options(&self, filetypes: &[&str]) -> Options {
+        let mut options = Options::new();
+        let sources = [&self.detected]
+            .into_iter()
+            .chain(self.user.iter())
+            .chain([&self.editorconfig])
+            .chain(self.project.iter())
+            .chain([&self.command_line, &self.session]);
+        for source in sources {
+            source.apply(&mut options);
+            if let Some(section) = source.section(filetypes) {
+                section.apply(&mut options);
+            }
+        }
+        options
+    }
+}
+
+/// `filename` as an absolute path, resolving a relative one against the
+/// working directory. None if the working directory can't be found.
+pub fn absolute_path(filename: &Path) -> Option<PathBuf> {
+    match filename.is_absolute() {
+        true => Some(filename.to_path_buf()),
+        false => Some(env::current_dir().ok()?.join(filename)),
+    }
+}
+
+/// The closest `.ginkgo.yaml` in the directory of `filename` or above it
+fn find_project_config(filename: &Path) -> Option<PathBuf> {
+    absolute_path(filename)?
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|config| config.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(expandtab: bool, tab_width: usize) -> OptionSet {
+        OptionSet {
+            expandtab: Some(expandtab),
+            tab_width: Some(tab_width),
+            ..OptionSet::default()
+        }
+    }
+
+    #[test]
+    fn detected_indentation_replaces_the_defaults() {
+        let config = Config {
+            detected: detected(true, 2),
+            ..Config::default()
+        };
+        let options = config.options(&[]);
+        assert!(options.expandtab);
+        assert_eq!(options.tab_width, 2);
+    }
+
+    #[test]
+    fn the_user_config_overrides_detected_indentation() {
+        let mut user = OptionSet::default();
+        user.set("tab_width", "8").unwrap();
+        let config = Config {
+            user: Some(user),
+            detected: detected(true, 2),
+            ..Config::default()
+        };
+        let options = config.options(&[]);
+        assert!(options.expandtab);
+        assert_eq!(options.tab_width, 8);
+    }
+
+    #[test]
+    fn editorconfig_overrides_detected_indentation() {
+        let mut editorconfig = OptionSet::default();
+        editorconfig.set("expandtab", "false").unwrap();
+        let config = Config {
+            detected: detected(true, 2),
+            editorconfig,
+            ..Config::default()
+        };
+        assert!(!config.options(&[]).expandtab);
+    }
+}
@@ -0,0 +1,90 @@
+/// Identifies an open overlay, as handed out by `TerminalView::open_overlay`
+pub type OverlayId = usize;
+
+/// Where an overlay goes on screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    // Under the cursor, or over it when there's no room below
+    Cursor,
+    // Under a position in the text given as (x, y), or over it when there's
+    // no room below, with the contents starting in the column of that position
+    Text(usize, usize),
+    // In the middle of the text area
+    Center,
+    // With the top-left corner at this position of the screen
+    Screen(usize, usize),
+}
+
+/// A floating window drawn on top of the text, such as a completion menu or
+/// a picker. Overlays are drawn in the order they were opened, so the last
+/// one opened is on top.
+#[derive(Clone, Debug)]
+pub struct Overlay {
+    pub lines: Vec<String>,
+    // Indices of the characters of each line to pick out, such as those a
+    // search matched
+    pub highlights: Vec<Vec<usize>>,
+    // Shown in the top border
+    pub title: Option<String>,
+    pub placement: Placement,
+    pub border: bool,
+    // Size of the contents, not counting the border. Without one the overlay
+    // fits its contents, as far as the screen allows.
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    // Line drawn as selected, which is kept scrolled into view
+    pub selected: Option<usize>,
+    // First line in view
+    pub scroll: usize,
+}
+
+impl Overlay {
+    pub fn new(lines: Vec<String>, placement: Placement) -> Overlay {
+        Overlay {
+            lines,
+            highlights: vec![],
+            title: None,
+            placement,
+            border: true,
+            width: None,
+            height: None,
+            selected: None,
+            scroll: 0,
+        }
+    }
+
+    /// Scrolls by `delta` lines, keeping the last line at the bottom or above
+    pub fn scroll_by(&mut self, delta: isize, height: usize) {
+        let last = self.lines.len().saturating_sub(height);
+        self.scroll = self.scroll.saturating_add_signed(delta).min(last);
+    }
+
+    /// Scrolls as little as needed to bring the selected line into view of
+    /// `height` lines, or to fill the view when lines were taken away
+    pub fn scroll_to_selected(&mut self, height: usize) {
+        if let Some(selected) = self.selected {
+            if selected < self.scroll {
+                self.scroll = selected;
+            } else if selected >= self.scroll + height {
+                self.scroll = selected + 1 - height;
+            }
+        }
+        self.scroll = self.scroll.min(self.lines.len().saturating_sub(height));
+    }
+}
+
+/// The part of the screen an overlay was drawn to, border included, in
+/// 0-based screen columns and lines
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+}
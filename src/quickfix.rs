@@ -0,0 +1,90 @@
+use crate::model::SignKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A place in a file that a command such as `:grep` pointed out
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    // Row and byte column, counting from 0
+    pub line: usize,
+    pub col: usize,
+    // What to show for it, such as the text of the matching line
+    pub text: String,
+    // For compiler messages, the sign the entry's row gets in the gutter
+    pub kind: Option<SignKind>,
+}
+
+impl Entry {
+    /// The entry as it is listed: file:line:col: text, with the file
+    /// relative to `dir` when it is inside it
+    pub fn describe(&self, dir: &Path) -> String {
+        let path = self.path.strip_prefix(dir).unwrap_or(&self.path);
+        format!(
+            "{}:{}:{}: {}",
+            path.display(),
+            self.line + 1,
+            self.col + 1,
+            self.text
+        )
+    }
+}
+
+/// How far the command filling a quickfix list has got when it hands over
+/// entries
+pub enum Progress {
+    Running,
+    Done,
+    // The command failed, with the message to show for it
+    Failed(String),
+}
+
+/// The places a command found, which are stepped through one at a time.
+/// Entries may keep arriving while the command runs.
+pub struct Quickfix {
+    // The command that filled the list
+    pub title: String,
+    pub entries: Vec<Entry>,
+    // Entry last jumped to
+    pub current: Option<usize>,
+    // Whether the command has finished
+    pub done: bool,
+    // Set to stop the command early
+    cancel: Arc<AtomicBool>,
+}
+
+impl Quickfix {
+    pub fn new(title: String) -> Quickfix {
+        Quickfix {
+            title,
+            entries: vec![],
+            current: None,
+            done: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Flag that stops the command filling this list once set
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancel)
+    }
+
+    /// Entry after the current one, or the first before any was jumped to.
+    /// Returns None past the last entry.
+    pub fn next(&self) -> Option<usize> {
+        let next = self.current.map_or(0, |current| current + 1);
+        Some(next).filter(|next| *next < self.entries.len())
+    }
+
+    /// Entry before the current one, or None at the first
+    pub fn prev(&self) -> Option<usize> {
+        self.current?.checked_sub(1)
+    }
+}
+
+impl Drop for Quickfix {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,176 @@
+/// Lines an ex command works on, as line numbers counting from 1. Line 0
+/// stands for the place before the first line, as in `:0r file`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub first: usize,
+    pub last: usize,
+}
+
+/// What addresses in a range refer to
+pub struct Context {
+    // Line numbers of the cursor line and the last line
+    pub current: usize,
+    pub last: usize,
+    // First and last lines of the selection, if there is one
+    pub selection: Option<(usize, usize)>,
+}
+
+/// Reads the range at the start of an ex command, returning it, if there is
+/// one, along with the rest of the command. A range is `%` for every line,
+/// or one address or two separated by a comma. An address is a line number,
+/// `.` for the cursor line, `$` for the last line or `'<` and `'>` for the
+/// first and last lines of the selection, followed by any number of `+N`
+/// and `-N` offsets. A range given backwards is turned around.
+pub fn parse<'a>(text: &'a str, context: &Context) -> Result<(Option<Range>, &'a str), String> {
+    if let Some(rest) = text.strip_prefix('%') {
+        let range = Range {
+            first: 1.min(context.last),
+            last: context.last,
+        };
+        return Ok((Some(range), rest));
+    }
+    let (first, rest) = match address(text, context)? {
+        (Some(first), rest) => (first, rest),
+        (None, rest) => return Ok((None, rest)),
+    };
+    let (last, rest) = match rest.strip_prefix(',') {
+        Some(after) => match address(after, context)? {
+            (Some(last), rest) => (last, rest),
+            (None, _) => return Err(String::from("Missing address after ,")),
+        },
+        None => (first, rest),
+    };
+    if first.max(last) > context.last {
+        return Err(String::from("Invalid range"));
+    }
+    let range = Range {
+        first: first.min(last),
+        last: first.max(last),
+    };
+    Ok((Some(range), rest))
+}
+
+/// Reads one address, if the text starts with one
+fn address<'a>(text: &'a str, context: &Context) -> Result<(Option<usize>, &'a str), String> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (mut line, mut rest) = if digits > 0 {
+        let number = text[..digits]
+            .parse()
+            .map_err(|_| format!("Invalid line number: {}", &text[..digits]))?;
+        (Some(number), &text[digits..])
+    } else if let Some(rest) = text.strip_prefix('.') {
+        (Some(context.current), rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (Some(context.last), rest)
+    } else if let Some(rest) = text.strip_prefix("'<") {
+        let (first, _) = context.selection.ok_or("No text is selected")?;
+        (Some(first), rest)
+    } else if let Some(rest) = text.strip_prefix("'>") {
+        let (_, last) = context.selection.ok_or("No text is selected")?;
+        (Some(last), rest)
+    } else {
+        (None, text)
+    };
+
+    // Offsets without a line to start from count from the cursor line
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let after = &rest[1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let offset: usize = match digits {
+            0 => 1,
+            _ => after[..digits]
+                .parse()
+                .map_err(|_| format!("Invalid offset: {}", &after[..digits]))?,
+        };
+        let base = line.unwrap_or(context.current);
+        line = match sign {
+            '+' => base.checked_add(offset),
+            _ => base.checked_sub(offset),
+        };
+        if line.is_none() {
+            return Err(String::from("Invalid range"));
+        }
+        rest = &after[digits..];
+    }
+    Ok((line, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The cursor is on line 5 of 20, with lines 3 to 7 selected
+    const CONTEXT: Context = Context {
+        current: 5,
+        last: 20,
+        selection: Some((3, 7)),
+    };
+
+    fn range(text: &str) -> Option<(usize, usize)> {
+        let (range, _) = parse(text, &CONTEXT).unwrap();
+        range.map(|range| (range.first, range.last))
+    }
+
+    fn error(text: &str) -> String {
+        parse(text, &CONTEXT).unwrap_err()
+    }
+
+    #[test]
+    fn the_rest_of_the_command_follows_the_range() {
+        assert_eq!(parse("3,7!sort", &CONTEXT), Ok((Some(Range { first: 3, last: 7 }), "!sort")));
+        assert_eq!(parse("r !date", &CONTEXT), Ok((None, "r !date")));
+        assert_eq!(parse("", &CONTEXT), Ok((None, "")));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(range("%"), Some((1, 20)));
+        assert_eq!(range("12"), Some((12, 12)));
+        assert_eq!(range("."), Some((5, 5)));
+        assert_eq!(range("$"), Some((20, 20)));
+        assert_eq!(range("'<,'>"), Some((3, 7)));
+        assert_eq!(range("'>"), Some((7, 7)));
+        // Line 0 is before the first line, as in `:0r`
+        assert_eq!(range("0"), Some((0, 0)));
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(range(".,+3"), Some((5, 8)));
+        assert_eq!(range("-2"), Some((3, 3)));
+        assert_eq!(range("+"), Some((6, 6)));
+        assert_eq!(range(".+1,$-1"), Some((6, 19)));
+        assert_eq!(range("'<-1,'>+2"), Some((2, 9)));
+        assert_eq!(range("3+2+2-1"), Some((6, 6)));
+    }
+
+    #[test]
+    fn a_backwards_range_is_turned_around() {
+        assert_eq!(range("7,3"), Some((3, 7)));
+        assert_eq!(range("$,."), Some((5, 20)));
+    }
+
+    #[test]
+    fn bad_ranges() {
+        assert_eq!(error("21"), "Invalid range");
+        assert_eq!(error("$+1"), "Invalid range");
+        assert_eq!(error(".-10"), "Invalid range");
+        assert_eq!(error("3,"), "Missing address after ,");
+        assert_eq!(error("99999999999999999999999"), "Invalid line number: 99999999999999999999999");
+        let context = Context {
+            selection: None,
+            ..CONTEXT
+        };
+        assert_eq!(parse("'<,'>", &context), Err(String::from("No text is selected")));
+    }
+
+    #[test]
+    fn every_line_of_an_empty_buffer_is_none() {
+        let context = Context {
+            current: 0,
+            last: 0,
+            selection: None,
+        };
+        assert_eq!(parse("%", &context), Ok((Some(Range { first: 0, last: 0 }), "")));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::model::StatusMsg;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// What a shell command that succeeded printed
+pub struct Output {
+    pub text: String,
+    // First line it printed to stderr, such as a warning from a formatter
+    pub errors: Option<String>,
+}
+
+impl Output {
+    /// Message to show once the command's output is shown: `summary`, or
+    /// what the command printed to stderr if it printed anything
+    pub fn status(&self, summary: String) -> StatusMsg {
+        match &self.errors {
+            Some(errors) => StatusMsg::Warn(errors.clone()),
+            None => StatusMsg::Normal(summary),
+        }
+    }
+
+    /// What the command printed, for putting in the buffer. A command that
+    /// printed anything to stderr fails with it, even one that exited with
+    /// a zero status, since a formatter may report a problem that way and
+    /// print something other than the text it was given.
+    pub fn into_text(self) -> Result<String, String> {
+        match self.errors {
+            Some(errors) => Err(errors),
+            None => Ok(self.text),
+        }
+    }
+}
+
+/// Runs a command with the shell in the working directory, with `input`
+/// written to its stdin, and returns what it printed. A command that exits
+/// with a non-zero status fails with the first line it printed to stderr,
+/// or with its status if it printed nothing there.
+pub fn run(command: &str, input: Option<&str>) -> Result<Output, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Couldn't run {}: {}", command, err))?;
+
+    // Written from a thread of its own, as a command may print while it
+    // reads and block once nobody reads what it printed
+    let stdin = child.stdin.take();
+    let input = input.unwrap_or_default().to_string();
+    let writer = thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            // A command that doesn't read all of it closes the pipe early
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+    let output = child.wait_with_output().map_err(|err| err.to_string())?;
+    let _ = writer.join();
+
+    let errors = String::from_utf8_lossy(&output.stderr);
+    let errors = errors.lines().map(str::trim).find(|line| !line.is_empty());
+    if !output.status.success() {
+        return Err(match errors {
+            Some(error) => error.to_string(),
+            None => format!("{} failed: {}", command, output.status),
+        });
+    }
+    Ok(Output {
+        text: String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"),
+        errors: errors.map(String::from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_is_piped_through_the_command() {
+        let output = run("sort", Some("b\na\n")).unwrap();
+        assert_eq!(output.text, "a\nb\n");
+        assert_eq!(output.errors, None);
+    }
+
+    #[test]
+    fn warnings_of_a_command_that_succeeds_are_kept() {
+        let output = run("echo careful >&2; echo done", None).unwrap();
+        assert_eq!(output.text, "done\n");
+        assert_eq!(output.errors.as_deref(), Some("careful"));
+    }
+
+    #[test]
+    fn text_with_warnings_isnt_put_in_the_buffer() {
+        let output = run("echo careful >&2; echo done", None).unwrap();
+        assert_eq!(output.into_text(), Err(String::from("careful")));
+        let output = run("echo done", None).unwrap();
+        assert_eq!(output.into_text(), Ok(String::from("done\n")));
+    }
+
+    #[test]
+    fn a_command_that_fails_gives_its_first_error() {
+        let err = run("echo; echo broken >&2; echo more >&2; exit 2", None).err();
+        assert_eq!(err.as_deref(), Some("broken"));
+        let err = run("exit 3", None).err();
+        assert_eq!(err.as_deref(), Some("exit 3 failed: exit status: 3"));
+    }
+}
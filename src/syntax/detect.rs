@@ -0,0 +1,217 @@
+use crate::syntax::syntaxengine::SyntaxDefinition;
+use fancy_regex::Regex;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// How many lines at each end of a file are searched for a modeline
+const MODELINE_LINES: usize = 5;
+
+static VIM_MODELINE: OnceLock<Regex> = OnceLock::new();
+static EMACS_MODELINE: OnceLock<Regex> = OnceLock::new();
+
+/// What is known about a buffer that can tell which language it is in
+pub struct FileHints<'a> {
+    // File name without its directory, e.g. "Makefile"
+    pub filename: &'a str,
+    pub ext: &'a str,
+    // The first and the last few lines of the buffer
+    pub head: Vec<&'a str>,
+    pub tail: Vec<&'a str>,
+}
+
+impl<'a> FileHints<'a> {
+    pub fn new(path: &'a Path, lines: &[&'a str]) -> FileHints<'a> {
+        let name = |s: Option<&'a std::ffi::OsStr>| s.and_then(|s| s.to_str()).unwrap_or("");
+        FileHints {
+            filename: name(path.file_name()),
+            ext: name(path.extension()),
+            head: lines.iter().take(MODELINE_LINES).copied().collect(),
+            tail: lines
+                .iter()
+                .rev()
+                .take(MODELINE_LINES)
+                .rev()
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// The first line with any text on it
+    pub fn first_line(&self) -> Option<&'a str> {
+        self.head
+            .iter()
+            .find(|line| !line.trim().is_empty())
+            .copied()
+    }
+}
+
+/// Picks the definition for a file by what it is called: a well-known file
+/// name wins, then the extension, then the interpreter named by a shebang line
+pub fn detect<'d>(
+    definitions: &'d [SyntaxDefinition],
+    hints: &FileHints,
+) -> Option<&'d SyntaxDefinition> {
+    if let Some(syntax) = definitions
+        .iter()
+        .find(|s| s.matches_filename(hints.filename))
+    {
+        return Some(syntax);
+    }
+    if !hints.ext.is_empty() {
+        if let Some(syntax) = definitions.iter().find(|s| s.matches_extension(hints.ext)) {
+            return Some(syntax);
+        }
+    }
+    let interpreter = hints
+        .head
+        .first()
+        .and_then(|line| shebang_interpreter(line))?;
+    // Versioned interpreters such as python3.11 fall back to their plain name
+    let plain = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    definitions
+        .iter()
+        .find(|s| s.matches_interpreter(&interpreter) || s.matches_interpreter(plain))
+}
+
+/// Guesses the definition from the first line of text, for files whose name
+/// tells nothing
+pub fn detect_by_contents<'d>(
+    definitions: &'d [SyntaxDefinition],
+    hints: &FileHints,
+) -> Option<&'d SyntaxDefinition> {
+    let first_line = hints.first_line()?;
+    definitions
+        .iter()
+        .find(|s| s.matches_first_line(first_line))
+}
+
+/// The program a `#!` line runs, looking through `env` to the program it starts
+pub fn shebang_interpreter(line: &str) -> Option<String> {
+    let command = line.strip_prefix("#!")?;
+    let mut words = command.split_whitespace();
+    let program = words.next()?.rsplit('/').next()?;
+    if program != "env" {
+        return Some(program.to_string());
+    }
+    // Skip env's options and variable assignments
+    words
+        .find(|word| !word.starts_with('-') && !word.contains('='))
+        .map(|word| word.rsplit('/').next().unwrap_or(word).to_string())
+}
+
+/// Filetype named by a vim modeline (`vim: ft=python` or `vim: set filetype=sh:`)
+/// or an emacs one (`-*- mode: python -*-`) near the start or end of the file
+pub fn modeline_filetype(hints: &FileHints) -> Option<String> {
+    let vim = VIM_MODELINE.get_or_init(|| {
+        Regex::new(r"\b(?:vim?|ex):.*?\b(?:ft|filetype|syn|syntax)=([\w+#-]+)").unwrap()
+    });
+    let emacs = EMACS_MODELINE.get_or_init(|| {
+        // `mode:` has to start a variable, so that e.g. `indent-tabs-mode:` doesn't count
+        Regex::new(r"-\*-\s*(?:(?:.*?;\s*)?mode:\s*)?([\w+#-]+)\s*(?:;[^*]*)?-\*-").unwrap()
+    });
+
+    for line in hints.head.iter().chain(hints.tail.iter()) {
+        for re in [vim, emacs] {
+            if let Ok(Some(caps)) = re.captures(line) {
+                if let Some(name) = caps.get(1) {
+                    return Some(name.as_str().to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::syntaxengine;
+
+    /// Name of the bundled definition picked for a file called `filename`
+    /// holding `text`
+    fn detected(filename: &str, text: &str) -> Option<String> {
+        let definitions: Vec<_> = syntaxengine::bundled_definitions().flatten().collect();
+        let lines: Vec<&str> = text.lines().collect();
+        let hints = FileHints::new(Path::new(filename), &lines);
+        detect(&definitions, &hints)
+            .or_else(|| detect_by_contents(&definitions, &hints))
+            .map(|syntax| syntax.name.clone())
+    }
+
+    fn modeline(text: &str) -> Option<String> {
+        let lines: Vec<&str> = text.lines().collect();
+        modeline_filetype(&FileHints::new(Path::new("file"), &lines))
+    }
+
+    #[test]
+    fn names_extensions_then_shebangs() {
+        assert_eq!(detected("src/Makefile", ""), Some(String::from("Makefile")));
+        assert_eq!(detected("lib.rs", "#!/bin/sh"), Some(String::from("Rust")));
+        assert_eq!(detected("build", "#!/bin/bash\necho"), Some(String::from("Shell")));
+        assert_eq!(detected("notes", "just text"), None);
+        // Only a shebang on the very first line counts
+        assert_eq!(detected("run", "\n#!/bin/sh"), None);
+    }
+
+    #[test]
+    fn shebangs_look_through_env() {
+        assert_eq!(shebang_interpreter("#!/usr/bin/env python3"), Some(String::from("python3")));
+        let line = "#!/usr/bin/env -S PYTHONPATH=lib python3 -u";
+        assert_eq!(shebang_interpreter(line), Some(String::from("python3")));
+        assert_eq!(shebang_interpreter("#! /bin/bash -e"), Some(String::from("bash")));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env -i"), None);
+        assert_eq!(shebang_interpreter("# not a shebang"), None);
+    }
+
+    #[test]
+    fn versioned_interpreters_fall_back_to_their_plain_name() {
+        let python = Some(String::from("Python"));
+        assert_eq!(detected("tool", "#!/usr/bin/python3.11"), python);
+        assert_eq!(detected("tool", "#!/usr/bin/env -S python3 -u"), python);
+        assert_eq!(detected("tool", "#!/usr/local/bin/python2"), python);
+        assert_eq!(detected("tool", "#!/usr/bin/perl5.36"), None);
+    }
+
+    #[test]
+    fn contents_are_a_last_resort() {
+        assert_eq!(detected("setup", "\nimport os\n"), Some(String::from("Python")));
+        assert_eq!(detected("x.json", "import os"), Some(String::from("JSON")));
+    }
+
+    #[test]
+    fn vim_modelines() {
+        assert_eq!(modeline("# vim: ft=python"), Some(String::from("python")));
+        assert_eq!(modeline("/* vim: set ts=4 filetype=c: */"), Some(String::from("c")));
+        assert_eq!(modeline("// vi:syntax=cpp"), Some(String::from("cpp")));
+        assert_eq!(modeline("# vim: set ts=4 sw=4:"), None);
+    }
+
+    #[test]
+    fn emacs_modelines() {
+        assert_eq!(modeline("#!/bin/sh\n# -*- mode: python -*-"), Some(String::from("python")));
+        assert_eq!(modeline(";; -*- lisp -*-"), Some(String::from("lisp")));
+        assert_eq!(modeline("-*- python; coding: utf-8 -*-"), Some(String::from("python")));
+        let line = "# -*- coding: utf-8; mode: sh; fill-column: 80 -*-";
+        assert_eq!(modeline(line), Some(String::from("sh")));
+    }
+
+    #[test]
+    fn other_emacs_variables_name_no_filetype() {
+        assert_eq!(modeline("# -*- coding: utf-8 -*-"), None);
+        assert_eq!(modeline("/* -*- indent-tabs-mode: nil; c-basic-offset: 4 -*- */"), None);
+    }
+
+    #[test]
+    fn modelines_are_searched_for_at_both_ends() {
+        let mut lines = vec!["text"; 20];
+        lines[4] = "# vim: ft=sh";
+        assert_eq!(modeline(&lines.join("\n")), Some(String::from("sh")));
+        lines[4] = "text";
+        lines[15] = "# vim: ft=make";
+        assert_eq!(modeline(&lines.join("\n")), Some(String::from("make")));
+        // Lines in the middle are too far from either end
+        lines[15] = "text";
+        lines[10] = "# vim: ft=sh";
+        assert_eq!(modeline(&lines.join("\n")), None);
+    }
+}
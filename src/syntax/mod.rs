@@ -1 +1,123 @@
-mod syntaxengine;
\ No newline at end of file
+pub mod detect;
+pub mod syntaxengine;
+pub mod syntaxtool;
+#[cfg(feature = "syntect")]
+pub mod syntectengine;
+
+use crate::comment::CommentTokens;
+use crate::indent::IndentRules;
+use crate::options::Options;
+use crate::syntax::detect::FileHints;
+use crate::syntax::syntaxengine::{definitions, HLMode, HLSpan, Highlighter};
+
+/// Pairs for languages that don't list their own. Single quotes are left
+/// out since they are apostrophes in prose.
+pub const DEFAULT_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+
+/// A syntax highlighting backend. Rows are highlighted one at a time, each
+/// starting from the state the previous row ended in, so a row only needs to
+/// be re-highlighted when it or the state it starts from changes.
+pub trait SyntaxHighlighter {
+    /// Name of the language being highlighted
+    fn name(&self) -> &str;
+
+    /// How the language is indented, if the highlighter knows
+    fn indent_rules(&self) -> Option<&IndentRules> {
+        None
+    }
+
+    /// Characters typed in pairs, as (opening, closing), if the highlighter knows
+    fn pairs(&self) -> Option<&[(char, char)]> {
+        None
+    }
+
+    /// How the language writes comments, if the highlighter knows
+    fn comment_tokens(&self) -> Option<&CommentTokens> {
+        None
+    }
+
+    /// Highlights a row starting from `state`, the state at the end of the
+    /// previous row. Returns the highlighted spans in order together with the
+    /// state at the end of this row. Text not covered by any span is `HLMode::Normal`.
+    fn highlight_row(&self, row: &str, state: &HLState) -> (Vec<HLSpan>, HLState);
+}
+
+/// Highlighter state between two rows. Each backend keeps its own kind of
+/// state, and `Start` is where the first row of a buffer begins.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum HLState {
+    #[default]
+    Start,
+    // Context stack of the native engine, each entry being a context index
+    // and the mode of its unmatched text
+    Native(Vec<(usize, HLMode)>),
+    #[cfg(feature = "syntect")]
+    Syntect(Box<syntectengine::SyntectState>),
+}
+
+/// Picks a highlighter for a file. An explicit `filetype` or one named by a
+/// modeline decides; otherwise the file name, extension and shebang are tried
+/// before guessing from the contents. Our own YAML definitions win at each
+/// step, and syntect's syntaxes cover the languages we have no definition for.
+pub fn highlighter_for_file(
+    hints: &FileHints,
+    filetype: Option<&str>,
+    options: &Options,
+) -> Option<Box<dyn SyntaxHighlighter>> {
+    let filetype = filetype
+        .map(String::from)
+        .or_else(|| detect::modeline_filetype(hints));
+    if let Some(highlighter) = filetype.and_then(|ft| highlighter_for_filetype(&ft, options)) {
+        return Some(highlighter);
+    }
+
+    let definitions = definitions();
+    if let Some(highlighter) = detect::detect(&definitions, hints).and_then(native) {
+        return Some(highlighter);
+    }
+    #[cfg(feature = "syntect")]
+    if let Some(highlighter) =
+        syntectengine::SyntectHighlighter::for_name(hints, options.syntax_theme.as_deref())
+    {
+        return Some(Box::new(highlighter));
+    }
+    if let Some(highlighter) = detect::detect_by_contents(&definitions, hints).and_then(native) {
+        return Some(highlighter);
+    }
+    #[cfg(feature = "syntect")]
+    if let Some(highlighter) =
+        syntectengine::SyntectHighlighter::for_contents(hints, options.syntax_theme.as_deref())
+    {
+        return Some(Box::new(highlighter));
+    }
+    None
+}
+
+/// Finds a highlighter by filetype, which is a language name in any case or
+/// one of its extensions, e.g. "Python" or "py"
+#[cfg_attr(not(feature = "syntect"), allow(unused_variables))]
+pub fn highlighter_for_filetype(
+    filetype: &str,
+    options: &Options,
+) -> Option<Box<dyn SyntaxHighlighter>> {
+    if let Some(highlighter) = definitions()
+        .iter()
+        .find(|syntax| syntax.matches_filetype(filetype))
+        .and_then(native)
+    {
+        return Some(highlighter);
+    }
+    #[cfg(feature = "syntect")]
+    if let Some(highlighter) =
+        syntectengine::SyntectHighlighter::for_filetype(filetype, options.syntax_theme.as_deref())
+    {
+        return Some(Box::new(highlighter));
+    }
+    None
+}
+
+fn native(syntax: &syntaxengine::SyntaxDefinition) -> Option<Box<dyn SyntaxHighlighter>> {
+    Highlighter::new(syntax)
+        .ok()
+        .map(|highlighter| Box::new(highlighter) as Box<dyn SyntaxHighlighter>)
+}
@@ -1,13 +1,37 @@
-use serde_yaml;
-use serde::{Serialize, Deserialize};
+use crate::comment::CommentTokens;
+use crate::indent::IndentRules;
+use crate::options;
+use crate::syntax::{HLState, SyntaxHighlighter};
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::{fs, io};
-use std::io::BufReader;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 
-const SYNTAX_DEF_PATH: &str = "definitions/";
+// Subdirectory of the config directory holding the user's own definitions
+const USER_SYNTAX_DIR: &str = "syntax";
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+// Definitions compiled into the binary, so highlighting works without any
+// files on disk
+const BUNDLED_DEFINITIONS: &[(&str, &str)] = &[
+    ("c_syntax.yaml", include_str!("definitions/c_syntax.yaml")),
+    ("cpp_syntax.yaml", include_str!("definitions/cpp_syntax.yaml")),
+    ("rust_syntax.yaml", include_str!("definitions/rust_syntax.yaml")),
+    ("python_syntax.yaml", include_str!("definitions/python_syntax.yaml")),
+    ("shell_syntax.yaml", include_str!("definitions/shell_syntax.yaml")),
+    ("markdown_syntax.yaml", include_str!("definitions/markdown_syntax.yaml")),
+    ("yaml_syntax.yaml", include_str!("definitions/yaml_syntax.yaml")),
+    ("json_syntax.yaml", include_str!("definitions/json_syntax.yaml")),
+    ("make_syntax.yaml", include_str!("definitions/make_syntax.yaml")),
+    ("dockerfile_syntax.yaml", include_str!("definitions/dockerfile_syntax.yaml")),
+    ("cmake_syntax.yaml", include_str!("definitions/cmake_syntax.yaml")),
+];
+
+// Name of the context every row starts highlighting in
+const MAIN_CONTEXT: &str = "main";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum HLMode {
     Normal,
     Symbol,
@@ -21,32 +45,431 @@ pub enum HLMode {
     Reserved
 }
 
+/// A highlighted byte range of a single row
+#[derive(Clone, Debug, PartialEq)]
+pub struct HLSpan {
+    pub start: usize,
+    pub end: usize,
+    pub mode: HLMode,
+    // Exact color picked by a theme, drawn instead of the mode's color
+    pub rgb: Option<(u8, u8, u8)>,
+}
+
+/// One pattern of a context. A match may also change the context stack:
+/// `push` enters a new context on top of the current one, `pop` returns to
+/// the context below, and `set` replaces the current context. Text inside a
+/// context that no pattern matches takes the `match_type` of the match that
+/// entered the context, so e.g. the body of a block comment stays a comment.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SMatch {
     pattern: String,
-    match_type: HLMode
+    match_type: HLMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    push: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pop: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    set: Option<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct SyntaxDefinition {
-    name: String,
+#[serde(deny_unknown_fields)]
+pub struct SyntaxDefinition {
+    pub name: String,
     extensions: Vec<String>,
+    // Whole file names such as "Makefile", for files without a telling extension
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    filenames: Vec<String>,
+    // Programs named on a "#!" line, such as "python" or "bash"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    interpreters: Vec<String>,
+    // Pattern recognising the first line of a file when nothing else does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_line: Option<String>,
+    // How lines change the indentation of the lines around them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    indent: Option<IndentDefinition>,
+    // Characters typed in pairs, each written as the opening and closing
+    // character together, e.g. "()"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pairs: Option<Vec<String>>,
+    // Tokens for commenting lines out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    comment: Option<CommentTokens>,
     contexts: HashMap<String, Vec<SMatch>>
 }
 
-struct Highlighter {
-    
+/// Patterns for the lines after which the next line is indented further, and
+/// for the lines that are indented one level less than the line above, such
+/// as a closing brace
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IndentDefinition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    increase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decrease: Option<String>,
+}
+
+impl SyntaxDefinition {
+    pub fn matches_filename(&self, filename: &str) -> bool {
+        self.filenames.iter().any(|f| f == filename)
+    }
+
+    pub fn matches_extension(&self, ext: &str) -> bool {
+        self.extensions.iter().any(|e| e == ext)
+    }
+
+    pub fn matches_interpreter(&self, interpreter: &str) -> bool {
+        self.interpreters.iter().any(|i| i == interpreter)
+    }
+
+    pub fn matches_first_line(&self, line: &str) -> bool {
+        self.first_line
+            .as_ref()
+            .and_then(|pattern| Regex::new(pattern).ok())
+            .is_some_and(|regex| regex.is_match(line).unwrap_or(false))
+    }
+
+    /// Whether `filetype`, as given to `:set filetype=` or in a modeline, names
+    /// this definition: either its name in any case or one of its extensions
+    pub fn matches_filetype(&self, filetype: &str) -> bool {
+        self.name.eq_ignore_ascii_case(filetype) || self.matches_extension(filetype)
+    }
 }
 
-fn read_syntax_file(filename: &str) -> io::Result<SyntaxDefinition> {
-    let file = File::open("c_syntax.yaml")?;
+/// How a match changes the context stack. Contexts are referred to by index.
+#[derive(Clone, Copy)]
+enum Transition {
+    None,
+    Push(usize),
+    Pop,
+    Set(usize),
+}
+
+struct CompiledMatch {
+    regex: Regex,
+    mode: HLMode,
+    transition: Transition,
+}
+
+/// A syntax definition with its patterns compiled, ready to highlight rows
+pub struct Highlighter {
+    pub name: String,
+    // Compiled contexts, with the main context always at index 0
+    contexts: Vec<Vec<CompiledMatch>>,
+    indent: Option<IndentRules>,
+    pairs: Option<Vec<(char, char)>>,
+    comment: Option<CommentTokens>,
+}
+
+impl Highlighter {
+    /// Compiles a definition. Every problem found is reported, one per line
+    /// of the error message, rather than just the first.
+    pub fn new(syntax: &SyntaxDefinition) -> io::Result<Highlighter> {
+        if !syntax.contexts.contains_key(MAIN_CONTEXT) {
+            return Err(invalid_data(format!(
+                "{}: missing '{}' context",
+                syntax.name, MAIN_CONTEXT
+            )));
+        }
+
+        // Give every context an index, with main first so it is the default
+        let mut names: Vec<&String> = syntax.contexts.keys().collect();
+        names.sort_by_key(|name| (name.as_str() != MAIN_CONTEXT, name.as_str()));
+        let index_of = |name: &str| -> Result<usize, String> {
+            names
+                .iter()
+                .position(|n| n.as_str() == name)
+                .ok_or_else(|| format!("{}: unknown context '{}'", syntax.name, name))
+        };
+
+        let mut errors = vec![];
+        if let Some(pattern) = &syntax.first_line {
+            if let Err(err) = Regex::new(pattern) {
+                errors.push(format!("{}: bad first_line {:?}: {}", syntax.name, pattern, err));
+            }
+        }
+        let indent = match &syntax.indent {
+            Some(indent) => {
+                IndentRules::new(indent.increase.as_deref(), indent.decrease.as_deref())
+                    .map_err(|err| errors.push(format!("{}: {}", syntax.name, err)))
+                    .ok()
+            }
+            None => None,
+        };
+        let pairs = syntax.pairs.as_ref().map(|pairs| {
+            pairs
+                .iter()
+                .filter_map(|pair| {
+                    let mut chars = pair.chars();
+                    match (chars.next(), chars.next(), chars.next()) {
+                        (Some(open), Some(close), None) => Some((open, close)),
+                        _ => {
+                            errors.push(format!("{}: bad pair {:?}", syntax.name, pair));
+                            None
+                        }
+                    }
+                })
+                .collect()
+        });
+        let mut contexts = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            let mut compiled = vec![];
+            for m in syntax.contexts[*name].iter() {
+                let regex = Regex::new(&m.pattern).map_err(|err| {
+                    format!("{}: bad pattern {:?}: {}", syntax.name, m.pattern, err)
+                });
+                let transition = match (&m.push, m.pop, &m.set) {
+                    (None, false, None) => Ok(Transition::None),
+                    (Some(ctx), false, None) => index_of(ctx).map(Transition::Push),
+                    // Nothing is below the main context to return to
+                    (None, true, None) if name.as_str() == MAIN_CONTEXT => Err(format!(
+                        "{}: pattern {:?} pops the '{}' context",
+                        syntax.name, m.pattern, MAIN_CONTEXT
+                    )),
+                    (None, true, None) => Ok(Transition::Pop),
+                    (None, false, Some(ctx)) => index_of(ctx).map(Transition::Set),
+                    _ => Err(format!(
+                        "{}: pattern {:?} may only use one of push, pop and set",
+                        syntax.name, m.pattern
+                    )),
+                };
+                match (regex, transition) {
+                    (Ok(regex), Ok(transition)) => compiled.push(CompiledMatch {
+                        regex,
+                        mode: m.match_type,
+                        transition,
+                    }),
+                    (regex, transition) => {
+                        errors.extend(regex.err());
+                        errors.extend(transition.err());
+                    }
+                }
+            }
+            contexts.push(compiled);
+        }
+
+        if !errors.is_empty() {
+            return Err(invalid_data(errors.join("\n")));
+        }
+        Ok(Highlighter {
+            name: syntax.name.clone(),
+            contexts,
+            indent,
+            pairs,
+            comment: syntax.comment.clone(),
+        })
+    }
+}
+
+impl SyntaxHighlighter for Highlighter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn indent_rules(&self) -> Option<&IndentRules> {
+        self.indent.as_ref()
+    }
+
+    fn pairs(&self) -> Option<&[(char, char)]> {
+        self.pairs.as_deref()
+    }
+
+    fn comment_tokens(&self) -> Option<&CommentTokens> {
+        self.comment.as_ref()
+    }
+
+    /// At each position the pattern of the current context matching earliest
+    /// wins, and when several match at the same place the one listed first wins.
+    /// The state is the context stack, where an empty stack means the main context.
+    fn highlight_row(&self, row: &str, state: &HLState) -> (Vec<HLSpan>, HLState) {
+        let mut spans = vec![];
+        let mut stack = match state {
+            HLState::Native(stack) => stack.clone(),
+            _ => vec![],
+        };
+        let mut pos = 0;
+        // Position of the last empty match that pushed or set a context, to stop
+        // contexts from switching back and forth forever. Empty pops are allowed
+        // while the stack still has something to pop, since it only shrinks.
+        let mut empty_at = None;
+
+        loop {
+            let (context, context_mode) = stack.last().copied().unwrap_or((0, HLMode::Normal));
+
+            let mut best: Option<(usize, usize, &CompiledMatch)> = None;
+            if pos <= row.len() {
+                for m in self.contexts[context].iter() {
+                    let found = match m.regex.find_from_pos(row, pos) {
+                        Ok(Some(found)) => found,
+                        _ => continue,
+                    };
+                    if best.is_none_or(|(start, _, _)| found.start() < start) {
+                        best = Some((found.start(), found.end(), m));
+                    }
+                }
+            }
+
+            let (start, end, m) = match best {
+                Some(best) => best,
+                None => {
+                    push_span(&mut spans, pos, row.len(), context_mode);
+                    break;
+                }
+            };
+
+            if end == start {
+                let stuck = match m.transition {
+                    Transition::None => true,
+                    Transition::Pop => stack.is_empty(),
+                    _ => empty_at == Some(start),
+                };
+                if stuck {
+                    // An empty match can't be highlighted, so step past it
+                    if start >= row.len() {
+                        push_span(&mut spans, pos, row.len(), context_mode);
+                        break;
+                    }
+                    let next = start + row[start..].chars().next().map_or(1, |c| c.len_utf8());
+                    push_span(&mut spans, pos, next, context_mode);
+                    pos = next;
+                    continue;
+                }
+                empty_at = Some(start);
+            }
+
+            push_span(&mut spans, pos, start, context_mode);
+            push_span(&mut spans, start, end, m.mode);
+            pos = end;
+
+            match m.transition {
+                Transition::None => {}
+                Transition::Push(ctx) => stack.push((ctx, m.mode)),
+                Transition::Pop => {
+                    stack.pop();
+                }
+                Transition::Set(ctx) => {
+                    stack.pop();
+                    stack.push((ctx, m.mode));
+                }
+            }
+        }
+        if stack.is_empty() {
+            (spans, HLState::Start)
+        } else {
+            (spans, HLState::Native(stack))
+        }
+    }
+}
+
+/// Adds a span unless it is empty or plain text
+fn push_span(spans: &mut Vec<HLSpan>, start: usize, end: usize, mode: HLMode) {
+    if end > start && mode != HLMode::Normal {
+        spans.push(HLSpan { start, end, mode, rgb: None });
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Directory the user's own syntax definitions are read from
+pub fn user_definitions_dir() -> Option<PathBuf> {
+    options::config_dir().map(|dir| dir.join(USER_SYNTAX_DIR))
+}
+
+pub fn read_syntax_file(filename: &Path) -> io::Result<SyntaxDefinition> {
+    let file = File::open(filename)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", filename.display(), err)))?;
     let reader = BufReader::new(file);
-    let syntax: SyntaxDefinition = serde_yaml::from_reader(reader).unwrap();
+    let syntax: SyntaxDefinition = serde_yaml::from_reader(reader)
+        .map_err(|err| invalid_data(format!("{}: {}", filename.display(), err)))?;
     Ok(syntax)
 }
-/*
-let file = File::open("c_syntax.yaml").unwrap();
-    let reader = BufReader::new(file);
 
-    let res: SyntaxDefinition = serde_yaml::from_reader(reader).unwrap();
-    */
\ No newline at end of file
+/// Parses the bundled definitions
+pub fn bundled_definitions() -> impl Iterator<Item = io::Result<SyntaxDefinition>> {
+    BUNDLED_DEFINITIONS.iter().map(|(name, text)| {
+        serde_yaml::from_str(text).map_err(|err| invalid_data(format!("{}: {}", name, err)))
+    })
+}
+
+/// Every definition, the user's before the bundled ones so that they can
+/// replace them
+pub fn definitions() -> Vec<SyntaxDefinition> {
+    let mut definitions = vec![];
+    if let Some(entries) = user_definitions_dir().and_then(|dir| fs::read_dir(dir).ok()) {
+        let mut paths: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("yaml"))
+            .collect();
+        paths.sort();
+        definitions.extend(paths.iter().filter_map(|path| read_syntax_file(path).ok()));
+    }
+    definitions.extend(bundled_definitions().flatten());
+    definitions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(contexts: &str) -> SyntaxDefinition {
+        let yaml = format!("name: test\nextensions: [test]\ncontexts:\n{}", contexts);
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn the_main_context_cant_pop() {
+        let syntax = definition("  main:\n    - pattern: '$'\n      match_type: Comment\n      pop: true\n");
+        let err = Highlighter::new(&syntax).err().unwrap();
+        assert_eq!(err.to_string(), "test: pattern \"$\" pops the 'main' context");
+    }
+
+    #[test]
+    fn an_empty_pop_with_nothing_to_pop_moves_on() {
+        // Built by hand, as a definition popping main no longer compiles
+        let highlighter = Highlighter {
+            name: String::from("test"),
+            contexts: vec![vec![CompiledMatch {
+                regex: Regex::new("$|(?=b)").unwrap(),
+                mode: HLMode::Comment,
+                transition: Transition::Pop,
+            }]],
+            indent: None,
+            pairs: None,
+            comment: None,
+        };
+        let (spans, state) = highlighter.highlight_row("abc", &HLState::Start);
+        assert_eq!(spans, vec![]);
+        assert_eq!(state, HLState::Start);
+    }
+
+    #[test]
+    fn empty_pops_return_to_the_context_below() {
+        let syntax = definition(
+            "
+  main:
+    - pattern: '#'
+      match_type: Comment
+      push: comment
+  comment:
+    - pattern: '$'
+      match_type: Comment
+      pop: true
+",
+        );
+        let highlighter = Highlighter::new(&syntax).unwrap();
+        let (spans, state) = highlighter.highlight_row("a # b", &HLState::Start);
+        let spans: Vec<_> = spans.iter().map(|span| (span.start, span.end, span.mode)).collect();
+        assert_eq!(spans, vec![(2, 3, HLMode::Comment), (3, 5, HLMode::Comment)]);
+        assert_eq!(state, HLState::Start);
+    }
+}
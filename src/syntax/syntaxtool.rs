@@ -0,0 +1,211 @@
+use crate::options::Options;
+use crate::syntax::detect::FileHints;
+use crate::syntax::syntaxengine::{bundled_definitions, read_syntax_file, HLMode, Highlighter};
+use crate::syntax::{self, HLState, SyntaxHighlighter};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Validates a syntax definition: that it parses, that every pattern
+/// compiles and that every context it refers to exists
+pub fn check(filename: &Path) -> io::Result<()> {
+    let syntax = read_syntax_file(filename)?;
+    Highlighter::new(&syntax).map_err(|err| {
+        let lines: Vec<String> = err
+            .to_string()
+            .lines()
+            .map(|line| format!("{}: {}", filename.display(), line))
+            .collect();
+        io::Error::new(err.kind(), lines.join("\n"))
+    })?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct DumpSpan<'a> {
+    // 1-based line number, with byte offsets into that line
+    line: usize,
+    start: usize,
+    end: usize,
+    mode: HLMode,
+    text: &'a str,
+}
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    syntax: &'a str,
+    spans: Vec<DumpSpan<'a>>,
+}
+
+/// Lists every highlighted span of a file, as text or as JSON. The file is
+/// highlighted with `definition` if given, otherwise with whatever syntax
+/// the editor would pick for it.
+pub fn dump(filename: &Path, definition: Option<&Path>, json: bool) -> io::Result<String> {
+    let contents = fs::read_to_string(filename)?;
+    let highlighter: Box<dyn SyntaxHighlighter> = match definition {
+        Some(definition) => Box::new(Highlighter::new(&read_syntax_file(definition)?)?),
+        None => {
+            let lines: Vec<&str> = contents.lines().collect();
+            let hints = FileHints::new(filename, &lines);
+            syntax::highlighter_for_file(&hints, None, &Options::new()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}: no syntax for this file type", filename.display()),
+                )
+            })?
+        }
+    };
+
+    let mut state = HLState::default();
+    let mut spans = vec![];
+    for (idx, row) in contents.lines().enumerate() {
+        let (hl, next) = highlighter.highlight_row(row, &state);
+        state = next;
+        spans.extend(hl.iter().map(|span| DumpSpan {
+            line: idx + 1,
+            start: span.start,
+            end: span.end,
+            mode: span.mode,
+            text: &row[span.start..span.end],
+        }));
+    }
+
+    if json {
+        let dump = Dump {
+            syntax: highlighter.name(),
+            spans,
+        };
+        return serde_json::to_string_pretty(&dump)
+            .map(|out| out + "\n")
+            .map_err(io::Error::other);
+    }
+    let mut out = format!("syntax: {}\n", highlighter.name());
+    for span in spans.iter() {
+        out.push_str(&format!(
+            "{}:{}-{} {:?} {:?}\n",
+            span.line, span.start, span.end, span.mode, span.text
+        ));
+    }
+    Ok(out)
+}
+
+/// How one definition or fixture fared in `test`
+pub struct Outcome {
+    pub name: String,
+    // Why it failed, if it did, indented to go under the name
+    pub failure: Option<String>,
+}
+
+/// Checks that the bundled definitions compile, then highlights every
+/// fixture in `dir` that has a `<fixture>.spans` file next to it and compares
+/// the result with it. Returns how each definition and fixture fared.
+pub fn test(dir: &Path) -> io::Result<Vec<Outcome>> {
+    let mut outcomes = test_definitions();
+    outcomes.extend(test_fixtures(dir)?);
+    Ok(outcomes)
+}
+
+/// Checks that each bundled definition parses and compiles
+pub fn test_definitions() -> Vec<Outcome> {
+    bundled_definitions()
+        .map(|syntax| match syntax.and_then(|syntax| Highlighter::new(&syntax)) {
+            Ok(highlighter) => Outcome {
+                name: format!("definition {}", highlighter.name()),
+                failure: None,
+            },
+            Err(err) => Outcome {
+                name: String::from("definition"),
+                failure: Some(err.to_string()),
+            },
+        })
+        .collect()
+}
+
+/// Compares the spans of each fixture in `dir` with its `.spans` file
+pub fn test_fixtures(dir: &Path) -> io::Result<Vec<Outcome>> {
+    let mut expectations: Vec<_> = fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("spans"))
+        .collect();
+    expectations.sort();
+
+    let mut outcomes = vec![];
+    for expected_path in expectations.iter() {
+        let fixture = expected_path.with_extension("");
+        let expected = fs::read_to_string(expected_path)?;
+        let failure = match dump(&fixture, None, false) {
+            Ok(actual) => first_difference(&expected, &actual),
+            Err(err) => Some(format!("  {}", err)),
+        };
+        outcomes.push(Outcome {
+            name: fixture.display().to_string(),
+            failure,
+        });
+    }
+    Ok(outcomes)
+}
+
+/// The first line where the actual spans differ from the expected ones
+fn first_difference(expected: &str, actual: &str) -> Option<String> {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(e), Some(a)) if e == a => continue,
+            (None, None) => return None,
+            (e, a) => {
+                return Some(format!(
+                    "  expected: {}\n  actual:   {}",
+                    e.unwrap_or("<end of output>"),
+                    a.unwrap_or("<end of output>")
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names of what failed, with why, for the assertion message
+    fn failures(outcomes: &[Outcome]) -> Vec<String> {
+        outcomes
+            .iter()
+            .filter_map(|outcome| Some(format!("{}\n{}", outcome.name, outcome.failure.as_ref()?)))
+            .collect()
+    }
+
+    #[test]
+    fn bundled_definitions_compile() {
+        let outcomes = test_definitions();
+        assert_eq!(outcomes.len(), bundled_definitions().count());
+        assert_eq!(failures(&outcomes), Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_bundled_definition_has_a_fixture() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+        let tested: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().extension().and_then(|e| e.to_str()) == Some("spans"))
+            .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+            .filter_map(|spans| Some(spans.lines().next()?.strip_prefix("syntax: ")?.to_string()))
+            .collect();
+        for syntax in bundled_definitions() {
+            let name = syntax.unwrap().name;
+            assert!(tested.contains(&name), "no fixture for {}", name);
+        }
+    }
+
+    #[test]
+    fn fixtures_match_their_spans() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+        let outcomes = test_fixtures(&dir).unwrap();
+        assert!(!outcomes.is_empty());
+        assert_eq!(failures(&outcomes), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::syntax::detect::FileHints;
+use crate::syntax::syntaxengine::{user_definitions_dir, HLMode, HLSpan};
+use crate::syntax::{HLState, SyntaxHighlighter};
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use syntect::highlighting::{HighlightIterator, HighlightState, Highlighter, Theme, ThemeSet};
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+
+/// Scope prefixes and the mode text in them is highlighted as. Within a scope
+/// the first matching prefix wins, so more specific prefixes come first.
+const SCOPE_MODES: &[(&str, HLMode)] = &[
+    ("comment", HLMode::Comment),
+    ("punctuation.definition.comment", HLMode::Comment),
+    ("punctuation.definition.string", HLMode::String),
+    ("constant.character.escape", HLMode::Special),
+    ("constant.numeric", HLMode::Number),
+    ("constant.language", HLMode::Reserved),
+    ("string", HLMode::String),
+    ("keyword.operator", HLMode::Symbol),
+    ("keyword", HLMode::Keyword),
+    ("storage.type", HLMode::Type),
+    ("storage", HLMode::Reserved),
+    ("support.type", HLMode::Type),
+    ("entity.name.type", HLMode::Type),
+    ("entity.name.function", HLMode::Call),
+    ("support.function", HLMode::Call),
+    ("variable.function", HLMode::Call),
+    ("meta.preprocessor", HLMode::Special),
+    ("punctuation", HLMode::Symbol),
+    ("markup.heading", HLMode::Keyword),
+    ("markup.raw", HLMode::String),
+    ("markup.underline.link", HLMode::Call),
+    ("markup", HLMode::Special),
+];
+
+static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+static SCOPE_MODE_SELECTORS: OnceLock<Vec<(Scope, HLMode)>> = OnceLock::new();
+
+/// Syntect's bundled syntaxes together with any `.sublime-syntax` files in the
+/// user's definitions directory, loaded the first time they are needed
+fn syntax_set() -> &'static SyntaxSet {
+    SYNTAX_SET.get_or_init(|| {
+        let dir = match user_definitions_dir() {
+            Some(dir) if dir.is_dir() => dir,
+            _ => return SyntaxSet::load_defaults_newlines(),
+        };
+        let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+        if builder.add_from_folder(dir, true).is_err() {
+            return SyntaxSet::load_defaults_newlines();
+        }
+        builder.build()
+    })
+}
+
+/// Loads a theme, either one of syntect's bundled themes by name or a
+/// `.tmTheme` file by path
+pub fn load_theme(name: &str) -> io::Result<Theme> {
+    if Path::new(name).is_file() {
+        return ThemeSet::get_theme(name).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", name, err))
+        });
+    }
+    let mut themes = ThemeSet::load_defaults();
+    themes.themes.remove(name).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("unknown theme '{}'", name))
+    })
+}
+
+/// Parser state at the end of a row
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntectState {
+    parse: ParseState,
+    scopes: ScopeStack,
+    // Only kept when highlighting with a theme
+    highlight: Option<HighlightState>,
+}
+
+/// Highlights rows with one of syntect's syntaxes. Without a theme, scopes
+/// are mapped onto our own highlight modes; with one, spans also carry the
+/// theme's colors.
+pub struct SyntectHighlighter {
+    syntax: SyntaxReference,
+    theme: Option<Theme>,
+}
+
+impl SyntectHighlighter {
+    /// Finds the syntax for a file by its extension or, for files such as
+    /// Makefiles, its whole name
+    pub fn for_name(hints: &FileHints, theme: Option<&str>) -> Option<SyntectHighlighter> {
+        let set = syntax_set();
+        let syntax = Some(hints.ext)
+            .filter(|ext| !ext.is_empty())
+            .and_then(|ext| set.find_syntax_by_extension(ext))
+            .or_else(|| set.find_syntax_by_extension(hints.filename))?;
+        Some(SyntectHighlighter::new(syntax, theme))
+    }
+
+    /// Finds the syntax recognising the first line of a file, e.g. its shebang
+    pub fn for_contents(hints: &FileHints, theme: Option<&str>) -> Option<SyntectHighlighter> {
+        let syntax = syntax_set().find_syntax_by_first_line(hints.first_line()?)?;
+        Some(SyntectHighlighter::new(syntax, theme))
+    }
+
+    /// Finds the syntax with the given name or extension, in any case
+    pub fn for_filetype(filetype: &str, theme: Option<&str>) -> Option<SyntectHighlighter> {
+        let syntax = syntax_set().find_syntax_by_token(filetype)?;
+        Some(SyntectHighlighter::new(syntax, theme))
+    }
+
+    /// A theme that fails to load is left out rather than leaving the buffer
+    /// unhighlighted
+    fn new(syntax: &SyntaxReference, theme: Option<&str>) -> SyntectHighlighter {
+        SyntectHighlighter {
+            syntax: syntax.clone(),
+            theme: theme.and_then(|name| load_theme(name).ok()),
+        }
+    }
+}
+
+impl SyntaxHighlighter for SyntectHighlighter {
+    fn name(&self) -> &str {
+        &self.syntax.name
+    }
+
+    fn highlight_row(&self, row: &str, state: &HLState) -> (Vec<HLSpan>, HLState) {
+        let highlighter = self.theme.as_ref().map(Highlighter::new);
+        let mut state = match state {
+            HLState::Syntect(state) => (**state).clone(),
+            _ => SyntectState {
+                parse: ParseState::new(&self.syntax),
+                scopes: ScopeStack::new(),
+                highlight: highlighter
+                    .as_ref()
+                    .map(|hl| HighlightState::new(hl, ScopeStack::new())),
+            },
+        };
+
+        // The bundled syntaxes expect every line to end in a newline
+        let line = format!("{}\n", row);
+        let ops = state.parse.parse_line(&line, syntax_set());
+
+        // Theme colors as (end, color) runs covering the line
+        let mut colors = vec![];
+        if let (Some(hl), Some(hl_state)) = (&highlighter, &mut state.highlight) {
+            let mut end = 0;
+            for (style, text) in HighlightIterator::new(hl_state, &ops, &line, hl) {
+                end += text.len();
+                let fg = style.foreground;
+                colors.push((end, (fg.r, fg.g, fg.b)));
+            }
+        }
+        let color_at = |pos: usize| {
+            colors
+                .iter()
+                .find(|(end, _)| *end > pos)
+                .map(|(_, rgb)| *rgb)
+        };
+
+        let mut spans = vec![];
+        let mut pos = 0;
+        for (at, op) in ops.iter() {
+            let at = (*at).min(row.len());
+            push_span(&mut spans, pos, at, scope_mode(&state.scopes), color_at(pos));
+            state.scopes.apply(op);
+            pos = at;
+        }
+        push_span(&mut spans, pos, row.len(), scope_mode(&state.scopes), color_at(pos));
+
+        (spans, HLState::Syntect(Box::new(state)))
+    }
+}
+
+/// The mode of the innermost scope that maps to one
+fn scope_mode(scopes: &ScopeStack) -> HLMode {
+    let selectors = SCOPE_MODE_SELECTORS.get_or_init(|| {
+        SCOPE_MODES
+            .iter()
+            .map(|(prefix, mode)| (Scope::new(prefix).unwrap(), *mode))
+            .collect()
+    });
+    for scope in scopes.as_slice().iter().rev() {
+        for (prefix, mode) in selectors.iter() {
+            if prefix.is_prefix_of(*scope) {
+                return *mode;
+            }
+        }
+    }
+    HLMode::Normal
+}
+
+/// Adds a span, merging it into the previous one when they look the same.
+/// Plain text is only kept when the theme gives it a color.
+fn push_span(spans: &mut Vec<HLSpan>, start: usize, end: usize, mode: HLMode, rgb: Option<(u8, u8, u8)>) {
+    if end <= start || (mode == HLMode::Normal && rgb.is_none()) {
+        return;
+    }
+    if let Some(last) = spans.last_mut() {
+        if last.end == start && last.mode == mode && last.rgb == rgb {
+            last.end = end;
+            return;
+        }
+    }
+    spans.push(HLSpan { start, end, mode, rgb });
+}
@@ -1,93 +1,219 @@
 use crate::command::{CommandState, Command};
-use crate::model::{Model, StatusMsg};
+use crate::comment;
+use crate::completion::{self, Completion};
+use crate::finder::{self, Finder};
+use crate::eventloop::{EditorEvent, EventLoop, Timer};
+use crate::errorformat::ErrorFormat;
+use crate::grep;
+use crate::indent;
+use crate::keymap::{self, Action, Binding, KeymapMode, Keymaps, Resolution};
+use crate::make;
+use crate::model::{Model, Sign, SignKind, StatusMsg};
+use crate::options::OptionSet;
+use crate::overlay::{Overlay, OverlayId, Placement};
+use crate::quickfix::{Entry, Progress, Quickfix};
+use crate::range::{self, Range};
+use crate::shell;
 use crate::terminalview::TerminalView;
+use crate::theme::Theme;
 use crate::InputHandler;
-use crate::View;
+use fancy_regex::Regex;
 use std::cell::RefCell;
-use std::io::{stdin, stdout, Write};
+use std::env;
+use std::fs;
+use std::path::Path;
 use std::rc::Rc;
-use termion::event::{Event, Key, MouseEvent};
-use termion::input::{MouseTerminal, TermRead};
-use termion::raw::IntoRawMode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use termion::event::{Event, Key, MouseButton, MouseEvent};
 
-const QUIT_TIMES: u8 = 3;
+// How many mappings may expand while handling one key before the mapping is
+// taken to be recursive
+const MAX_MAPPING_EXPANSIONS: usize = 1000;
+
+// Most words the completion popup shows at once
+const COMPLETION_HEIGHT: usize = 10;
+
+// Most matches the file finder lists
+const FINDER_LINES: usize = 1000;
+
+// Narrowest the list of the file finder gets
+const FINDER_MIN_WIDTH: usize = 30;
+
+// Most entries the quickfix list shows at once
+const QUICKFIX_HEIGHT: usize = 10;
+
+// Lines an overlay scrolls by for each step of the mouse wheel
+const OVERLAY_WHEEL_LINES: isize = 3;
+
+// How long a status message stays on screen before it is cleared
+const STATUS_MSG_TIMEOUT: Duration = Duration::from_secs(5);
 
 type PromptCallback = fn(&mut TerminalController, &str) -> Result<bool, std::io::Error>;
 
+// Called once the command filling the quickfix list is done
+type QuickfixCallback = fn(&mut TerminalController, Progress);
+
 enum PromptType {
     Find,
     Rename,
     Command,
 }
 
+/// Matches of the last search, stepped through with n/N
+struct Search {
+    occurrences: Vec<(usize, usize)>,
+    idx: usize,
+    term_len: usize,
+}
+
+/// An edit of whole rows, which works on the selected rows or on those a
+/// motion moves over
+#[derive(Clone, Copy, PartialEq)]
+enum Operator {
+    Indent,
+    Dedent,
+    Reindent,
+    Comment,
+    BlockComment,
+    Filter,
+}
+
+impl Operator {
+    fn for_action(action: Action) -> Option<Operator> {
+        match action {
+            Action::Indent => Some(Operator::Indent),
+            Action::Dedent => Some(Operator::Dedent),
+            Action::Reindent => Some(Operator::Reindent),
+            Action::Comment => Some(Operator::Comment),
+            Action::BlockComment => Some(Operator::BlockComment),
+            Action::Filter => Some(Operator::Filter),
+            _ => None,
+        }
+    }
+}
+
+/// What typing a bracket or quote does besides inserting it
+enum Pairing {
+    // Move past the same closing character instead of inserting another
+    StepOver,
+    // Insert this closing character after it
+    Close(char),
+}
+
+/// The file finder, with the overlays listing its matches and previewing
+/// the selected file
+struct FilePicker {
+    finder: Finder,
+    list: OverlayId,
+    preview: OverlayId,
+    // File in the preview, which is only read again when another is selected
+    previewed: Option<String>,
+}
+
 enum TerminalMode {
     Normal,
     Insert,
     Prompt(PromptType),
+    Search(Search),
+    Finder,
 }
 
 pub struct TerminalController<'a> {
     model: Rc<RefCell<Model>>,
     view: &'a TerminalView,
+    events: Rc<EventLoop>,
     quit_times: u8,
     mode: TerminalMode,
-    states: CommandState
+    states: CommandState,
+    // Status message time the expiry timer was last scheduled for
+    status_time: Option<Instant>,
+    keymaps: Keymaps,
+    // Keys typed or replayed by a mapping that haven't been handled yet, each
+    // with whether user bindings apply to it
+    pending_keys: Vec<(Key, bool)>,
+    // Interval the autosave timer is running at, in seconds, or 0 when it is off
+    autosave: u64,
+    // Operator waiting for the motion that gives the rows it works on
+    operator: Option<Operator>,
+    // Words offered to complete the one being typed, and the popup they are
+    // shown in
+    completion: Option<(Completion, OverlayId)>,
+    // Whether the mouse button went down on an overlay
+    mouse_on_overlay: bool,
+    picker: Option<FilePicker>,
+    // Places found by the last `:grep` or `:make`, and the overlay listing
+    // them while it is open
+    quickfix: Option<Quickfix>,
+    quickfix_list: Option<OverlayId>,
 }
 
 impl<'a> TerminalController<'a> {
-    pub fn new(model: Rc<RefCell<Model>>, view: &TerminalView) -> TerminalController {
-        TerminalController {
+    pub fn new(
+        model: Rc<RefCell<Model>>,
+        view: &'a TerminalView,
+        events: Rc<EventLoop>,
+    ) -> TerminalController<'a> {
+        let mut controller = TerminalController {
             model,
             view,
-            quit_times: QUIT_TIMES,
+            events,
+            quit_times: 0,
             mode: TerminalMode::Normal,
-            states: CommandState::new()
-        }
+            states: CommandState::new(),
+            status_time: None,
+            keymaps: Keymaps::new(),
+            pending_keys: vec![],
+            autosave: 0,
+            operator: None,
+            completion: None,
+            mouse_on_overlay: false,
+            picker: None,
+            quickfix: None,
+            quickfix_list: None,
+        };
+        controller.quit_times = controller.model.borrow().options.quit_times;
+        controller.load_key_bindings();
+        controller.apply_options();
+        controller.schedule_status_expiry();
+        controller
     }
 
     pub fn process_input_prompt(
         &mut self,
-        prompt: String,
+        evt: Event,
         callback: PromptCallback,
     ) -> Result<bool, std::io::Error> {
-        let stdin = stdin();
-        let mut stdout = MouseTerminal::from(stdout().into_raw_mode().unwrap());
-        let mut msg = String::from("");
-
-        stdout.flush().unwrap();
-        self.view.draw_prompt(&prompt, &msg);
-
-        for c in stdin.events() {
-            let evt = c.unwrap();
-            match evt {
-                Event::Key(key) => match key {
-                    Key::Esc | Key::Ctrl('c') => {
-                        self.enter_normal_mode();
-                        return Ok(true);
-                    }
-                    Key::Backspace | Key::Delete | Key::Ctrl('h') => {
-                        msg.pop();
-                        self.view.draw_prompt(&prompt, &msg);
-                    }
-                    Key::Char('\r') | Key::Char('\n') => {
-                        let result = callback(self, &msg);
-                        self.enter_normal_mode();
-                        return result;
-                    }
-                    Key::Char(c) => {
-                        msg.push(c);
-                        self.view.draw_prompt(&prompt, &msg);
-                    }
-                    _ => {
-                        continue;
-                    }
-                },
-                _ => {
-                    continue;
+        let key = match evt {
+            Event::Key(key) => key,
+            _ => return Ok(true),
+        };
+        match key {
+            Key::Esc | Key::Ctrl('c') => {
+                self.enter_normal_mode();
+            }
+            Key::Backspace | Key::Delete | Key::Ctrl('h') => {
+                if let Some((_, msg)) = &mut self.model.borrow_mut().prompt {
+                    msg.pop();
+                }
+            }
+            Key::Char('\r') | Key::Char('\n') => {
+                let msg = match self.model.borrow_mut().prompt.take() {
+                    Some((_, msg)) => msg,
+                    None => String::new(),
+                };
+                // Leave the prompt first so the callback is free to switch modes
+                self.enter_normal_mode();
+                return callback(self, &msg);
+            }
+            Key::Char(c) => {
+                if let Some((_, msg)) = &mut self.model.borrow_mut().prompt {
+                    msg.push(c);
                 }
             }
+            _ => {}
         }
-        stdout.flush().unwrap();
         Ok(true)
     }
 
@@ -112,56 +238,69 @@ impl<'a> TerminalController<'a> {
             }
             // Return if no matches were found
             if occurrences.is_empty() {
-                model.status_msg =
-                    StatusMsg::Warn(format!("No occurrences found for \'{}\'", term));
+                model.set_status_msg(StatusMsg::Warn(format!(
+                    "No occurrences found for \'{}\'",
+                    term
+                )));
                 return Ok(true);
             }
-            model.status_msg = StatusMsg::Normal("n = next, N = prev".to_string());
+            model.set_status_msg(StatusMsg::Normal("n = next, N = prev".to_string()));
+            model.search_term = Some(term.to_string());
         }
 
-        let mut idx = 0;
-        loop {
-            let stdin = stdin();
-            let o = occurrences.get(idx).unwrap();
+        controller.mode = TerminalMode::Search(Search {
+            occurrences,
+            idx: 0,
+            term_len,
+        });
+        controller.select_search_match();
+        Ok(true)
+    }
 
-            // Use limited scope for model
-            {
-                let model = &mut controller.model.borrow_mut();
-                model.anchor_start = (o.0, o.1);
-                model.anchor_end = (o.0 + term_len, o.1);
-                model.text_selected = true;
-                model.set_cursor(o.0, o.1);
-            }
+    /// Selects the current search match and moves the cursor onto it
+    fn select_search_match(&mut self) {
+        if let TerminalMode::Search(search) = &self.mode {
+            let o = search.occurrences[search.idx];
+            let model = &mut self.model.borrow_mut();
+            model.anchor_start = (o.0, o.1);
+            model.anchor_end = (o.0 + search.term_len, o.1);
+            model.text_selected = true;
+            model.set_cursor(o.0, o.1);
+        }
+        self.scroll();
+    }
 
-            // Model is borrowed immutably for view functions
-            controller.scroll();
-            controller.view.draw();
-
-            'keys: for c in stdin.keys() {
-                match c.unwrap() {
-                    // 'n' searches forward
-                    Key::Char('n') => {
-                        idx = (idx + 1) % occurrences.len();
-                        break 'keys;
-                    }
-                    // 'N' searches backward
-                    Key::Char('N') => {
-                        idx = if idx == 0 {
-                            occurrences.len() - 1
-                        } else {
-                            idx - 1
-                        };
-                        break 'keys;
-                    }
-                    Key::Esc | Key::Ctrl('c') => {
-                        controller.model.borrow_mut().status_msg =
-                            StatusMsg::Normal(String::from(""));
-                        return Ok(true);
-                    }
-                    _ => {}
-                }
+    pub fn process_input_search(&mut self, evt: Event) -> Result<bool, std::io::Error> {
+        let search = match &mut self.mode {
+            TerminalMode::Search(search) => search,
+            _ => return Ok(true),
+        };
+        let len = search.occurrences.len();
+
+        match evt {
+            // 'n' searches forward
+            Event::Key(Key::Char('n')) => {
+                search.idx = (search.idx + 1) % len;
+            }
+            // 'N' searches backward
+            Event::Key(Key::Char('N')) => {
+                search.idx = if search.idx == 0 {
+                    len - 1
+                } else {
+                    search.idx - 1
+                };
+            }
+            Event::Key(Key::Esc) | Event::Key(Key::Ctrl('c')) => {
+                let mut model = self.model.borrow_mut();
+                model.set_status_msg(StatusMsg::Normal(String::from("")));
+                model.search_term = None;
+                self.mode = TerminalMode::Normal;
+                return Ok(true);
             }
+            _ => return Ok(true),
         }
+        self.select_search_match();
+        Ok(true)
     }
 
     fn rename_callback<'r, 's>(
@@ -177,226 +316,1400 @@ impl<'a> TerminalController<'a> {
         Ok(true)
     }
 
-    pub fn process_input_normal(&mut self) -> Result<bool, std::io::Error> {
-        let stdin = stdin();
-        let mut stdout = MouseTerminal::from(stdout().into_raw_mode().unwrap());
+    /// Runs a command typed at the ':' prompt
+    fn command_callback<'r, 's>(
+        controller: &'r mut TerminalController<'s>,
+        line: &str,
+    ) -> Result<bool, std::io::Error> {
+        let (range, line) = match range::parse(line.trim(), &controller.range_context()) {
+            Ok(parsed) => parsed,
+            Err(msg) => {
+                controller.model.borrow_mut().set_status_msg(StatusMsg::Error(msg));
+                return Ok(true);
+            }
+        };
+        let line = line.trim_start();
+        // Names end at a space or at the `!` of commands like `:r !cmd`
+        let end = line.find(|c: char| c.is_whitespace() || c == '!').unwrap_or(line.len());
+        let (name, args) = (&line[..end], line[end..].trim());
+        if range.is_some() && !matches!(name, "" | "r" | "read") {
+            let msg = format!("{} doesn't take a range", name);
+            controller.model.borrow_mut().set_status_msg(StatusMsg::Error(msg));
+            return Ok(true);
+        }
+        match name {
+            "" if args.starts_with('!') => controller.filter(range, args[1..].trim()),
+            "" => {
+                if let Some(range) = range {
+                    controller.goto_line(range.last);
+                }
+            }
+            "r" | "read" => controller.read(range, args),
+            "colo" | "colorscheme" => controller.colorscheme(args),
+            "se" | "set" => controller.set(args),
+            "map" | "nmap" => controller.map(KeymapMode::Normal, args, true),
+            "noremap" | "nnoremap" => controller.map(KeymapMode::Normal, args, false),
+            "imap" => controller.map(KeymapMode::Insert, args, true),
+            "inoremap" => controller.map(KeymapMode::Insert, args, false),
+            "unmap" | "nunmap" => controller.unmap(KeymapMode::Normal, args),
+            "iunmap" => controller.unmap(KeymapMode::Insert, args),
+            "gr" | "grep" => controller.grep(args),
+            "mak" | "make" => controller.make(args),
+            "cn" | "cnext" => controller.step_quickfix(true),
+            "cp" | "cprev" | "cN" | "cNext" => controller.step_quickfix(false),
+            "cc" => controller.goto_quickfix_number(args),
+            "cope" | "copen" => match controller.quickfix {
+                Some(_) => controller.show_quickfix(true),
+                None => controller
+                    .model
+                    .borrow_mut()
+                    .set_status_msg(StatusMsg::Error(String::from("No quickfix list"))),
+            },
+            "ccl" | "cclose" => {
+                if let Some(id) = controller.quickfix_list.take() {
+                    controller.view.close_overlay(id);
+                }
+            }
+            _ => controller
+                .model
+                .borrow_mut()
+                .set_status_msg(StatusMsg::Error(format!("Not an editor command: {}", name))),
+        }
+        controller.apply_options();
+        Ok(true)
+    }
+
+    /// What the addresses of a range typed at the ':' prompt refer to
+    fn range_context(&self) -> range::Context {
+        let selection = match self.model.borrow().text_selected {
+            true => Some(self.target_rows()),
+            false => None,
+        };
+        let model = self.model.borrow();
+        range::Context {
+            current: (model.cy + 1).min(model.num_rows()),
+            last: model.num_rows(),
+            selection: selection.map(|(first, last)| (first + 1, last + 1)),
+        }
+    }
+
+    /// Moves the cursor to the first non-blank character of a line, counting
+    /// from 1, as `:N` does
+    fn goto_line(&mut self, line: usize) {
+        {
+            let mut model = self.model.borrow_mut();
+            let row = line.saturating_sub(1).min(model.num_rows().saturating_sub(1));
+            let start = match row < model.num_rows() {
+                true => indent::leading_whitespace(model.get_row_contents(row)).len(),
+                false => 0,
+            };
+            model.set_cursor(start, row);
+            model.text_selected = false;
+        }
+        self.scroll();
+    }
 
-        stdout.flush().unwrap();
-        for c in stdin.events() {
-            let evt = c.unwrap();
-            match evt {
-                Event::Key(key) => match key {
-                    Key::Ctrl('q') => {
-                        self.quit_times = self.quit();
-                        if self.quit_times == 0 {
-                            return Ok(false);
+    /// Runs a shell command typed after `!`. With a range, the lines of the
+    /// range are piped through the command and replaced with what it prints,
+    /// as one undo step, unless it prints to stderr. Without one, what it
+    /// prints is shown in a popup.
+    fn filter(&mut self, range: Option<Range>, command: &str) {
+        if command.is_empty() {
+            let msg = StatusMsg::Error(String::from("Missing shell command"));
+            return self.model.borrow_mut().set_status_msg(msg);
+        }
+        let (first, last) = match range {
+            Some(range) if range.first > 0 => (range.first - 1, range.last - 1),
+            Some(_) => {
+                let msg = StatusMsg::Error(String::from("Invalid range"));
+                return self.model.borrow_mut().set_status_msg(msg);
+            }
+            None => return self.show_command_output(command),
+        };
+        let input = self.rows_text((first, last));
+        let expected = input.clone();
+        self.run_shell(command, Some(input), move |controller, output| {
+            // The lines may have been edited while the command ran
+            if controller.rows_text((first, last)) != expected {
+                let msg = StatusMsg::Error(String::from("Lines changed while filtering"));
+                return controller.model.borrow_mut().set_status_msg(msg);
+            }
+            let text = match output.into_text() {
+                Ok(text) => text,
+                Err(msg) => {
+                    let msg = StatusMsg::Error(msg);
+                    return controller.model.borrow_mut().set_status_msg(msg);
+                }
+            };
+            controller.replace_rows((first, last), &text);
+            let count = last - first + 1;
+            let plural = if count == 1 { "" } else { "s" };
+            let msg = StatusMsg::Normal(format!("{} line{} filtered", count, plural));
+            controller.model.borrow_mut().set_status_msg(msg);
+            controller.scroll();
+        });
+    }
+
+    /// Shows what a shell command prints in a popup, closed with Esc
+    fn show_command_output(&mut self, command: &str) {
+        let command_name = command.to_string();
+        self.run_shell(command, None, move |controller, output| {
+            if output.text.is_empty() {
+                let msg = output.status(format!("{} printed nothing", command_name));
+                return controller.model.borrow_mut().set_status_msg(msg);
+            }
+            let lines = output.text.lines().map(String::from).collect();
+            let mut overlay = Overlay::new(lines, Placement::Center);
+            overlay.title = Some(format!(" !{} ", command_name));
+            controller.view.open_overlay(overlay);
+            let msg = output.status(String::new());
+            controller.model.borrow_mut().set_status_msg(msg);
+        });
+    }
+
+    /// Inserts what a shell command prints, as in `:r !cmd`, or the contents
+    /// of a file, as in `:r file`, below the last line of the range or the
+    /// cursor line. Line 0 inserts above the first line.
+    fn read(&mut self, range: Option<Range>, args: &str) {
+        let after = match range {
+            Some(range) => range.last,
+            None => (self.model.borrow().cy + 1).min(self.model.borrow().num_rows()),
+        };
+        let text = match args.strip_prefix('!').map(str::trim) {
+            Some("") => Err(String::from("Missing shell command")),
+            Some(command) => {
+                return self.run_shell(command, None, move |controller, output| {
+                    let msg = match output.into_text() {
+                        Ok(text) => {
+                            controller.read_text(after, &text);
+                            StatusMsg::Normal(String::new())
                         }
-                        return Ok(true);
-                    }
-                    Key::Ctrl('s') => {
-                        self.save();
-                        break;
-                    }
-                    Key::Ctrl('f') => {
-                        self.enter_prompt_mode(PromptType::Find);
-                        break;
-                    }
-                    Key::Ctrl('r') => {
-                        self.states.execute_redo(&mut self.model.borrow_mut());
-                        break; 
-                    }
-                    Key::Char('u') => {
-                        self.states.execute_undo(&mut self.model.borrow_mut());
-                        break; 
-                    }
-                    Key::Esc => {}
-                    Key::Left | Key::Right | Key::Up | Key::Down => {
-                        self.move_cursor(key);
-                        break;
-                    }
-                    Key::Backspace | Key::Delete | Key::Ctrl('h') | Key::Char('h') => {
-                        self.move_cursor(Key::Left);
-                        break;
-                    }
-                    Key::Char('j') => {
-                        self.move_cursor(Key::Down);
-                        break;
-                    }
-                    Key::Char('k') => {
-                        self.move_cursor(Key::Up);
-                        break;
-                    }
-                    Key::Char('l') => {
-                        self.move_cursor(Key::Right);
-                        break;
-                    }
-                    Key::Char('i') => {
-                        self.enter_insert_mode();
-                        break;
-                    }
-                    Key::Char('I') => {
-                        self.enter_insert_mode();
-                        self.goto_line_start();
-                        break;
-                    }
-                    Key::Char('a') => {
-                        self.enter_insert_mode();
-                        self.move_cursor(Key::Right);
-                        break;
-                    }
-                    Key::Char('A') => {
-                        self.enter_insert_mode();
-                        self.goto_line_end();
-                        break;
-                    }
-                    Key::Char('o') => {
-                        self.enter_insert_mode();
-                        self.goto_line_end();
-                        self.insert_newline();
-                        break;
-                    }
-                    Key::Char('O') => {
-                        self.enter_insert_mode();
-                        self.goto_line_start();
-                        self.insert_newline();
-                        self.move_cursor(Key::Up);
-                        break;
-                    }
-                    Key::PageDown => {
-                        self.page_down();
-                        break;
-                    }
-                    Key::PageUp => {
-                        self.page_up();
-                        break;
-                    }
-                    Key::Char('\r') | Key::Char('\n') => {
-                        self.move_cursor(Key::Down);
-                        break;
-                    }
+                        Err(msg) => StatusMsg::Error(msg),
+                    };
+                    controller.model.borrow_mut().set_status_msg(msg);
+                });
+            }
+            None if args.is_empty() => Err(String::from("Missing file name")),
+            None => fs::read_to_string(args).map_err(|err| format!("{}: {}", args, err)),
+        };
+        match text {
+            Ok(text) => self.read_text(after, &text),
+            Err(msg) => self.model.borrow_mut().set_status_msg(StatusMsg::Error(msg)),
+        }
+    }
 
-                    Key::Ctrl(_) | Key::Alt(_) => {}
-                    _ => {
-                        break;
-                    }
+    /// Inserts text read by `:r` after line `after`, or after the last line
+    /// if there are fewer lines by now
+    fn read_text(&mut self, after: usize, text: &str) {
+        if !text.is_empty() {
+            let after = after.min(self.model.borrow().num_rows());
+            self.insert_rows(after, text);
+            self.scroll();
+        }
+    }
+
+    /// Runs a shell command on a background thread, so that one that takes a
+    /// while or hangs doesn't hold up the editor, and hands what it printed to
+    /// `finished`. A command that fails leaves its error in the status bar.
+    fn run_shell<F>(&mut self, command: &str, input: Option<String>, finished: F)
+    where
+        F: FnOnce(&mut TerminalController, shell::Output) + Send + 'static,
+    {
+        let msg = StatusMsg::Normal(format!("Running {}…", command));
+        self.model.borrow_mut().set_status_msg(msg);
+        let command = command.to_string();
+        self.events.spawn_job(move || {
+            let result = shell::run(&command, input.as_deref());
+            Box::new(move |controller: &mut TerminalController| match result {
+                Ok(output) => finished(controller, output),
+                Err(msg) => controller.model.borrow_mut().set_status_msg(StatusMsg::Error(msg)),
+            })
+        });
+    }
+
+    /// The text of rows `first..=last`, each ending in a line break
+    fn rows_text(&self, (first, last): (usize, usize)) -> String {
+        let model = self.model.borrow();
+        if last >= model.num_rows() {
+            return String::new();
+        }
+        (first..=last).map(|row| format!("{}\n", model.get_row_contents(row))).collect()
+    }
+
+    /// Opens the command prompt with the range of rows filled in, ready for
+    /// the command to filter them through, as `!` does in vim
+    fn prompt_filter(&mut self, (first, last): (usize, usize)) {
+        self.model.borrow_mut().text_selected = false;
+        self.enter_prompt_mode(PromptType::Command);
+        let range = match last - first {
+            0 => String::from("."),
+            count => format!(".,.+{}", count),
+        };
+        if let Some((_, msg)) = &mut self.model.borrow_mut().prompt {
+            *msg = format!("{}!", range);
+        }
+    }
+
+    /// Switches to the named color theme, or shows the current one's name
+    fn colorscheme(&self, name: &str) {
+        if name.is_empty() {
+            let msg = StatusMsg::Normal(self.view.theme_name());
+            self.model.borrow_mut().set_status_msg(msg);
+            return;
+        }
+        // Load it here so that a bad name isn't kept as the theme option
+        let result = Theme::load(name).map_err(|err| err.to_string()).and_then(|theme| {
+            self.view.set_theme(theme);
+            self.model.borrow_mut().set_option("theme", name)
+        });
+        if let Err(err) = result {
+            self.model.borrow_mut().set_status_msg(StatusMsg::Error(err));
+        }
+    }
+
+    /// Binds the key bindings of the config files. Bad bindings are skipped,
+    /// and the first problem is shown.
+    fn load_key_bindings(&mut self) {
+        let leader = self.leader();
+        let mut error = None;
+        let model = self.model.borrow();
+        for (mode, keys, action) in model.config.key_bindings() {
+            let binding = KeymapMode::from_name(mode)
+                .ok_or_else(|| format!("Unknown keymap mode: {}", mode))
+                .and_then(|mode| Ok((mode, keymap::parse_keys(keys, &leader)?)))
+                .and_then(|(mode, keys)| match Action::from_name(action) {
+                    Some(action) => Ok((mode, keys, action)),
+                    None => Err(format!("Unknown action: {}", action)),
+                });
+            match binding {
+                Ok((mode, keys, action)) => self.keymaps.bind(mode, keys, Binding::Action(action)),
+                Err(err) => {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        drop(model);
+        if let Some(err) = error {
+            self.model.borrow_mut().set_status_msg(StatusMsg::Error(err));
+        }
+    }
+
+    /// Keys `<leader>` currently stands for
+    fn leader(&self) -> Vec<Key> {
+        let leader = self.model.borrow().options.leader.clone();
+        keymap::parse_keys(&leader, &[]).unwrap_or_else(|_| vec![Key::Char('\\')])
+    }
+
+    /// `:map lhs rhs` binds keys to other keys, `:map lhs` shows what keys
+    /// are bound to and `:map` lists every user binding of the mode
+    fn map(&mut self, mode: KeymapMode, args: &str, remap: bool) {
+        let leader = self.leader();
+        let (lhs, rhs) = match args.split_once(char::is_whitespace) {
+            Some((lhs, rhs)) => (lhs, rhs.trim_start()),
+            None => (args, ""),
+        };
+        let msg = if lhs.is_empty() {
+            let lines = self.keymaps.describe(mode);
+            match lines.is_empty() {
+                true => StatusMsg::Normal(String::from("No mappings")),
+                false => StatusMsg::Normal(lines.join(" | ")),
+            }
+        } else if rhs.is_empty() {
+            let shown = keymap::parse_keys(lhs, &leader).map(|keys| {
+                let prefix = keymap::format_keys(&keys);
+                let lines: Vec<String> = self
+                    .keymaps
+                    .describe(mode)
+                    .into_iter()
+                    .filter(|line| line.starts_with(&prefix))
+                    .collect();
+                lines.join(" | ")
+            });
+            match shown {
+                Ok(lines) if lines.is_empty() => StatusMsg::Normal(String::from("No mapping found")),
+                Ok(lines) => StatusMsg::Normal(lines),
+                Err(err) => StatusMsg::Error(err),
+            }
+        } else {
+            let parsed = keymap::parse_keys(lhs, &leader)
+                .and_then(|lhs| Ok((lhs, keymap::parse_keys(rhs, &leader)?)));
+            match parsed {
+                Ok((lhs, keys)) => {
+                    self.keymaps.bind(mode, lhs, Binding::Keys { keys, remap });
+                    return;
+                }
+                Err(err) => StatusMsg::Error(err),
+            }
+        };
+        self.model.borrow_mut().set_status_msg(msg);
+    }
+
+    fn unmap(&mut self, mode: KeymapMode, args: &str) {
+        let msg = match keymap::parse_keys(args.trim(), &self.leader()) {
+            Ok(keys) if self.keymaps.unbind(mode, &keys) => return,
+            Ok(_) => StatusMsg::Error(String::from("No such mapping")),
+            Err(err) => StatusMsg::Error(err),
+        };
+        self.model.borrow_mut().set_status_msg(msg);
+    }
+
+    /// Sets editor options, vim style: `name=value` sets an option,
+    /// `name` and `noname` switch one on and off, and `name?` shows its value.
+    /// A non-switch option given by name alone also shows its value.
+    fn set(&self, args: &str) {
+        let mut model = self.model.borrow_mut();
+        for arg in &split_set_args(args) {
+            let arg = arg.as_str();
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.trim_end_matches('?'), None),
+            };
+            let result = match (name, value) {
+                ("filetype" | "ft", None) => {
+                    let language = model.syntax_name().unwrap_or("Plaintext").to_string();
+                    Err(StatusMsg::Normal(format!("filetype={}", language)))
+                }
+                ("filetype" | "ft", Some(value)) => match model.set_filetype(value) {
+                    true => Ok(()),
+                    false => Err(StatusMsg::Error(format!("Unknown filetype: {}", value))),
                 },
-                // Mouse indices are 1-based so we subtract 1 to make 0-based
-                Event::Mouse(me) => match me {
-                    MouseEvent::Press(_, x, y) => {
-                        self.mouse_press(x - 1, y - 1);
-                        break;
+                (_, Some(value)) => model.set_option(name, value).map_err(StatusMsg::Error),
+                (_, None) => {
+                    let is_switch = |name: &str| {
+                        matches!(model.options.value(name).as_deref(), Some("true" | "false"))
+                    };
+                    if !arg.ends_with('?') && is_switch(name) {
+                        model.set_option(name, "true").map_err(StatusMsg::Error)
+                    } else if let Some(switch) = name.strip_prefix("no").filter(|n| is_switch(n)) {
+                        model.set_option(switch, "false").map_err(StatusMsg::Error)
+                    } else {
+                        match model.options.value(name) {
+                            Some(value) => Err(StatusMsg::Normal(format!("{}={}", name, value))),
+                            None => Err(StatusMsg::Error(format!("Unknown option: {}", name))),
+                        }
                     }
-                    MouseEvent::Hold(x, y) => {
-                        self.mouse_hold(x - 1, y - 1);
-                        break;
+                }
+            };
+            // Stop at the first error, or at an option whose value is shown
+            if let Err(msg) = result {
+                model.set_status_msg(msg);
+                return;
+            }
+        }
+    }
+
+    /// Queues a key typed in normal or insert mode and runs whatever the
+    /// queued keys are bound to
+    fn process_input_keymapped(&mut self, evt: Event) -> Result<bool, std::io::Error> {
+        match evt {
+            Event::Key(key) => {
+                self.pending_keys.push((key, true));
+                self.process_pending_keys(false)
+            }
+            Event::Mouse(me) => {
+                self.process_mouse(me);
+                self.finish_input();
+                Ok(true)
+            }
+            Event::Unsupported(_) => Ok(true),
+        }
+    }
+
+    /// Runs the bindings of the pending keys for as long as they resolve.
+    /// Keys that might still become part of a longer binding are kept until
+    /// the next key arrives or the keymap timeout runs out, which is when
+    /// `timed_out` is set. Keys replayed by a mapping are queued in front, and
+    /// once a mapping leaves normal and insert mode they go to the new mode.
+    fn process_pending_keys(&mut self, timed_out: bool) -> Result<bool, std::io::Error> {
+        let mut expansions = 0;
+        while let Some(&(key, remap)) = self.pending_keys.first() {
+            let mode = match self.mode {
+                TerminalMode::Normal => KeymapMode::Normal,
+                TerminalMode::Insert => KeymapMode::Insert,
+                _ => {
+                    self.pending_keys.remove(0);
+                    if !self.process_terminal_event(Event::Key(key))? {
+                        return Ok(false);
                     }
-                    MouseEvent::Release(_, _) => {
-                        self.mouse_release();
-                        break;
+                    continue;
+                }
+            };
+
+            // A sequence can't grow past keys that are remapped differently
+            let keys: Vec<Key> = self
+                .pending_keys
+                .iter()
+                .take_while(|(_, r)| *r == remap)
+                .map(|(key, _)| *key)
+                .collect();
+            let complete = timed_out || keys.len() < self.pending_keys.len();
+            match self.keymaps.resolve(mode, &keys, remap, complete) {
+                Resolution::Wait => {
+                    let timeout = self.model.borrow().options.keymap_timeout;
+                    self.events
+                        .schedule(Timer::KeySequence, Duration::from_millis(timeout), false);
+                    return Ok(true);
+                }
+                Resolution::Bound { binding, len } => {
+                    self.pending_keys.drain(..len);
+                    match binding {
+                        Binding::Action(action) => {
+                            if !self.run_action(action)? {
+                                return Ok(false);
+                            }
+                        }
+                        Binding::Keys { keys, remap } => {
+                            expansions += 1;
+                            if expansions > MAX_MAPPING_EXPANSIONS {
+                                self.pending_keys.clear();
+                                let msg = StatusMsg::Error(String::from("Recursive mapping"));
+                                self.model.borrow_mut().set_status_msg(msg);
+                                break;
+                            }
+                            let replay = keys.into_iter().map(|key| (key, remap));
+                            self.pending_keys.splice(0..0, replay);
+                        }
                     }
-                },
-                Event::Unsupported(_) => todo!(),
+                }
+                Resolution::Unbound => {
+                    self.pending_keys.remove(0);
+                    self.process_unbound_key(mode, key);
+                }
             }
-            stdout.flush().unwrap();
         }
-        stdout.flush().unwrap();
-        if self.quit_times != QUIT_TIMES {
-            self.abort_quit();
-            self.quit_times = QUIT_TIMES;
+        self.events.cancel(Timer::KeySequence);
+        Ok(true)
+    }
+
+    /// Runs an action. Returns false when the editor should exit.
+    fn run_action(&mut self, action: Action) -> Result<bool, std::io::Error> {
+        // Esc closes an overlay before it does anything else
+        if action == Action::NormalMode && self.view.top_overlay().is_some() {
+            self.dismiss_overlay();
+            self.finish_input();
+            return Ok(true);
+        }
+        if self.completion.is_some() && self.run_completion_action(action) {
+            self.finish_input();
+            return Ok(true);
+        }
+        if let Some(operator) = self.operator.take() {
+            return self.finish_operator(operator, action);
+        }
+        if let Some(operator) = Operator::for_action(action) {
+            self.start_operator(operator);
+            self.finish_input();
+            return Ok(true);
         }
+        match action {
+            Action::Quit => {
+                // Skips finish_input, which would cancel the quit
+                self.quit_times = self.quit();
+                return Ok(self.quit_times != 0);
+            }
+            Action::Save => self.save(),
+            Action::SaveAs => self.enter_prompt_mode(PromptType::Rename),
+            Action::Find => self.enter_prompt_mode(PromptType::Find),
+            Action::Undo => self.states.execute_undo(&mut self.model.borrow_mut()),
+            Action::Redo => self.states.execute_redo(&mut self.model.borrow_mut()),
+            Action::CommandPrompt => self.enter_prompt_mode(PromptType::Command),
+            Action::MoveLeft => self.move_cursor(Key::Left),
+            Action::MoveRight => self.move_cursor(Key::Right),
+            Action::MoveUp => self.move_cursor(Key::Up),
+            Action::MoveDown => self.move_cursor(Key::Down),
+            Action::MoveDisplayLineUp => self.move_display_line(false),
+            Action::MoveDisplayLineDown => self.move_display_line(true),
+            Action::LineStart => self.goto_line_start(),
+            Action::LineEnd => self.goto_line_end(),
+            Action::FirstLine => self.model.borrow_mut().set_cursor(0, 0),
+            Action::LastLine => {
+                let mut model = self.model.borrow_mut();
+                let last = model.num_rows().saturating_sub(1);
+                model.set_cursor(0, last);
+            }
+            Action::MatchingBracket => self.goto_matching_bracket(),
+            Action::PageUp => self.page_up(),
+            Action::PageDown => self.page_down(),
+            Action::Insert => self.enter_insert_mode(),
+            Action::InsertLineStart => {
+                self.enter_insert_mode();
+                self.goto_line_start();
+            }
+            Action::Append => {
+                self.enter_insert_mode();
+                self.move_cursor(Key::Right);
+            }
+            Action::AppendLineEnd => {
+                self.enter_insert_mode();
+                self.goto_line_end();
+            }
+            Action::OpenBelow => {
+                self.enter_insert_mode();
+                self.goto_line_end();
+                self.insert_newline();
+            }
+            Action::OpenAbove => {
+                self.enter_insert_mode();
+                self.open_line_above();
+            }
+            // Operators were started above
+            Action::Indent
+            | Action::Dedent
+            | Action::Reindent
+            | Action::Comment
+            | Action::BlockComment
+            | Action::Filter => {}
+            Action::CommentLine => self.apply_operator(Operator::Comment, self.target_rows()),
+            Action::BlockCommentLine => {
+                self.apply_operator(Operator::BlockComment, self.target_rows())
+            }
+            Action::NormalMode => self.enter_normal_mode(),
+            Action::DeleteBack => {
+                self.delete();
+                self.update_completion();
+            }
+            Action::Newline => self.insert_newline(),
+            Action::Tab => {
+                if self.model.borrow().options.expandtab {
+                    self.insert_tab_spaces();
+                } else {
+                    self.insert_char('\t');
+                }
+            }
+            Action::CompleteNext => self.start_completion(true),
+            Action::CompletePrev => self.start_completion(false),
+            Action::FindFile => self.open_finder(),
+            Action::QuickfixNext => self.step_quickfix(true),
+            Action::QuickfixPrev => self.step_quickfix(false),
+            Action::Nop => {}
+        }
+        self.finish_input();
+        Ok(true)
+    }
+
+    /// Handles an action while words are offered for completion: the
+    /// completion keys and Up and Down move through them, and Enter and Tab
+    /// take the selected one. Returns false when the action should run as
+    /// usual, which closes the popup unless it deletes back into the word
+    /// being completed.
+    fn run_completion_action(&mut self, action: Action) -> bool {
+        let (popup, _) = match &mut self.completion {
+            Some(completion) => completion,
+            None => return false,
+        };
+        match action {
+            Action::CompleteNext | Action::MoveDown => popup.select_next(),
+            Action::CompletePrev | Action::MoveUp => popup.select_prev(),
+            Action::Newline | Action::Tab => {
+                self.accept_completion();
+                return true;
+            }
+            Action::DeleteBack => return false,
+            _ => {
+                self.close_completion();
+                return false;
+            }
+        }
+        self.show_completion();
+        true
+    }
+
+    /// Opens the popup offering words from the buffer that complete the
+    /// word before the cursor, selecting the first of them or, going
+    /// backward, the last
+    fn start_completion(&mut self, forward: bool) {
+        let mut model = self.model.borrow_mut();
+        let (cx, cy) = (model.cx, model.cy);
+        let (start, typed, words) = {
+            let rows: Vec<&str> = (0..model.num_rows())
+                .map(|idx| model.get_row_contents(idx).as_str())
+                .collect();
+            let row = rows.get(cy).copied().unwrap_or("");
+            let start = completion::word_start(row, cx);
+            let words = completion::words_near(&rows, (cx, cy));
+            (start, row[start..cx].to_string(), words)
+        };
+        let mut popup = Completion::new((start, cy), words);
+        popup.filter(&typed);
+        if popup.matches.is_empty() {
+            let msg = format!("No completions for {:?}", typed);
+            model.set_status_msg(StatusMsg::Warn(msg));
+            return;
+        }
+        if !forward {
+            popup.select_prev();
+        }
+
+        let mut overlay = Overlay::new(vec![], Placement::Text(start, cy));
+        overlay.border = false;
+        let id = self.view.open_overlay(overlay);
+        self.completion = Some((popup, id));
+        drop(model);
+        self.show_completion();
+    }
+
+    /// Brings the popup up to date with the words offered
+    fn show_completion(&mut self) {
+        if let Some((popup, id)) = &self.completion {
+            self.view.update_overlay(*id, |overlay| {
+                // A space either side of each word
+                overlay.lines = popup.matches.iter().map(|word| format!(" {} ", word)).collect();
+                overlay.height = Some(popup.matches.len().min(COMPLETION_HEIGHT));
+                overlay.selected = Some(popup.selected);
+            });
+        }
+    }
+
+    fn close_completion(&mut self) {
+        if let Some((_, id)) = self.completion.take() {
+            self.view.close_overlay(id);
+        }
+    }
+
+    /// Narrows the words offered down to those completing what is typed
+    /// now, and closes the popup once the cursor leaves the word or no word
+    /// is left
+    fn update_completion(&mut self) {
+        let start = match &self.completion {
+            Some((popup, _)) => popup.start,
+            None => return,
+        };
+        let typed = {
+            let model = self.model.borrow();
+            let (cx, cy) = (model.cx, model.cy);
+            match cy == start.1 && cx >= start.0 && cy < model.num_rows() {
+                true => model.get_row_contents(cy)[start.0..cx].to_string(),
+                false => String::from(" "),
+            }
+        };
+        if let Some((popup, _)) = &mut self.completion {
+            if typed.chars().all(completion::is_word_char) {
+                popup.filter(&typed);
+            }
+            if popup.matches.is_empty() || !typed.chars().all(completion::is_word_char) {
+                self.close_completion();
+                return;
+            }
+        }
+        self.show_completion();
+    }
+
+    /// Types the rest of the selected word and closes the popup
+    fn accept_completion(&mut self) {
+        let popup = match self.completion.take() {
+            Some((popup, id)) => {
+                self.view.close_overlay(id);
+                popup
+            }
+            None => return,
+        };
+        if let Some(word) = popup.selected_word() {
+            let model = &mut self.model.borrow_mut();
+            let location = (model.cx, model.cy);
+            let contents = word[location.0 - popup.start.0..].to_string();
+            self.states.execute_command(Command::InsertString { location, contents }, model);
+        }
+    }
+
+    /// Closes the overlay on top of the others, and drops whatever goes
+    /// with it
+    fn dismiss_overlay(&mut self) {
+        if let Some(id) = self.view.top_overlay() {
+            self.close_overlay(id);
+        }
+    }
+
+    fn close_overlay(&mut self, id: OverlayId) {
+        if self.completion.as_ref().is_some_and(|(_, other)| *other == id) {
+            self.completion = None;
+        }
+        if let Some(picker) = &self.picker {
+            if picker.list == id || picker.preview == id {
+                self.close_finder();
+            }
+        }
+        if self.quickfix_list == Some(id) {
+            self.quickfix_list = None;
+        }
+        self.view.close_overlay(id);
+    }
+
+    /// Opens the file finder on the project the working directory is in,
+    /// and starts looking for its files
+    fn open_finder(&mut self) {
+        let root = finder::project_root(&env::current_dir().unwrap_or_default());
+        let finder = Finder::new(root.clone());
+        let cancel = finder.cancel_flag();
+        let sender = self.events.job_sender();
+        finder::walk(root, finder.cancel_flag(), move |files, done| {
+            let cancel = cancel.clone();
+            sender.send(Box::new(move |controller: &mut TerminalController| {
+                // Files may still arrive from a finder that was closed
+                if !cancel.load(Ordering::Relaxed) {
+                    controller.files_found(files, done);
+                }
+            }))
+        });
+
+        let list = self.view.open_overlay(Overlay::new(vec![], Placement::Screen(0, 0)));
+        let preview = self.view.open_overlay(Overlay::new(vec![], Placement::Screen(0, 0)));
+        self.picker = Some(FilePicker {
+            finder,
+            list,
+            preview,
+            previewed: None,
+        });
+        print!("{}", termion::cursor::BlinkingBar);
+        let mut model = self.model.borrow_mut();
+        model.mode = 'P';
+        model.prompt = Some((String::from("Open file:"), String::new()));
+        self.mode = TerminalMode::Finder;
+        drop(model);
+        self.show_finder();
+    }
+
+    fn files_found(&mut self, files: Vec<String>, done: bool) {
+        if let Some(picker) = &mut self.picker {
+            picker.finder.add_files(files);
+            picker.finder.done = done;
+            self.show_finder();
+        }
+    }
+
+    /// Handles a key typed into the file finder: typing narrows the files
+    /// down, the arrow keys, Tab and Ctrl-N/Ctrl-P move through them, Enter
+    /// opens the selected one and Esc closes the finder
+    fn process_input_finder(&mut self, evt: Event) -> Result<bool, std::io::Error> {
+        let key = match evt {
+            Event::Key(key) => key,
+            Event::Mouse(me) => {
+                self.process_mouse(me);
+                return Ok(true);
+            }
+            Event::Unsupported(_) => return Ok(true),
+        };
+        let finder = match &mut self.picker {
+            Some(picker) => &mut picker.finder,
+            None => return Ok(true),
+        };
+        match key {
+            Key::Esc | Key::Ctrl('c') => {
+                self.close_finder();
+                return Ok(true);
+            }
+            Key::Char('\n') | Key::Char('\r') => {
+                self.open_selected_file();
+                return Ok(true);
+            }
+            Key::Up | Key::Ctrl('p') | Key::BackTab => finder.select_prev(),
+            Key::Down | Key::Ctrl('n') | Key::Char('\t') => finder.select_next(),
+            Key::Backspace | Key::Delete | Key::Ctrl('h') => {
+                let mut query = finder.query().to_string();
+                query.pop();
+                finder.set_query(&query);
+            }
+            Key::Char(c) if !c.is_control() => {
+                let query = format!("{}{}", finder.query(), c);
+                finder.set_query(&query);
+            }
+            _ => {}
+        }
+        self.show_finder();
+        Ok(true)
+    }
+
+    /// Brings the finder's overlays and prompt up to date: the matches on
+    /// the left, with the characters the query matched picked out, and the
+    /// start of the selected file on the right
+    fn show_finder(&mut self) {
+        let picker = match &mut self.picker {
+            Some(picker) => picker,
+            None => return,
+        };
+        let finder = &picker.finder;
+        let cols = self.view.get_screen_cols();
+        let rows = self.view.get_screen_rows();
+        let list_width = (cols * 2 / 5).max(FINDER_MIN_WIDTH).min(cols);
+        let height = rows.saturating_sub(2);
+
+        let (lines, highlights) = finder
+            .top_matches(FINDER_LINES)
+            .into_iter()
+            .map(|(file, positions)| (file.to_string(), positions))
+            .unzip();
+        let searching = if finder.done { "" } else { "…" };
+        let title = format!(
+            " Files {}/{}{} ",
+            finder.num_matches(),
+            finder.num_files(),
+            searching
+        );
+        let selected = finder.selected_file().map(String::from);
+        self.view.update_overlay(picker.list, |overlay| {
+            overlay.lines = lines;
+            overlay.highlights = highlights;
+            overlay.title = Some(title);
+            overlay.width = Some(list_width.saturating_sub(2));
+            overlay.height = Some(height);
+            overlay.selected = selected.as_ref().map(|_| finder.selected);
+        });
+
+        if picker.previewed != selected {
+            let tab_width = self.model.borrow().options.tab_width;
+            let lines = match &selected {
+                Some(file) => finder::preview(&finder.root.join(file), height, tab_width),
+                None => vec![],
+            };
+            self.view.update_overlay(picker.preview, |overlay| {
+                overlay.lines = lines;
+                overlay.title = selected.as_ref().map(|file| format!(" {} ", file));
+                overlay.scroll = 0;
+            });
+            picker.previewed = selected;
+        }
+        self.view.update_overlay(picker.preview, |overlay| {
+            overlay.placement = Placement::Screen(list_width, 0);
+            overlay.width = Some(cols.saturating_sub(list_width + 2));
+            overlay.height = Some(height);
+        });
+
+        let query = finder.query().to_string();
+        if let Some((_, msg)) = &mut self.model.borrow_mut().prompt {
+            *msg = query;
+        }
+    }
+
+    fn close_finder(&mut self) {
+        if let Some(picker) = self.picker.take() {
+            self.view.close_overlay(picker.list);
+            self.view.close_overlay(picker.preview);
+            self.enter_normal_mode();
+        }
+    }
+
+    fn open_selected_file(&mut self) {
+        let path = self.picker.as_ref().and_then(|picker| {
+            let file = picker.finder.selected_file()?;
+            Some(picker.finder.root.join(file))
+        });
+        self.close_finder();
+        if let Some(path) = path {
+            self.edit_file(&path);
+        }
+    }
+
+    /// Edits another file in place of the current one, unless the current
+    /// one has unsaved changes. Options set with `:set` and on the command
+    /// line carry over. Returns whether the file was opened.
+    fn edit_file(&mut self, path: &Path) -> bool {
+        let mut model = self.model.borrow_mut();
+        if self.states.change_count != 0 {
+            let msg = format!("{} has unsaved changes", model.filename);
+            model.set_status_msg(StatusMsg::Error(msg));
+            return false;
+        }
+        // Keep paths inside the working directory short
+        let cwd = env::current_dir().unwrap_or_default();
+        let filename = path.strip_prefix(&cwd).unwrap_or(path).to_string_lossy().into_owned();
+
+        let mut config = std::mem::take(&mut model.config);
+        config.detected = OptionSet::default();
+        let config_error = config.load(Path::new(&filename)).err();
+        let mut new = Model::new();
+        new.config = config;
+        new.update_options();
+        new.set_status_msg(StatusMsg::Normal(format!("\"{}\"", filename)));
+        new.open_file(&filename);
+        if let Some(err) = config_error {
+            new.set_status_msg(StatusMsg::Error(err.to_string()));
+        }
+        *model = new;
+        drop(model);
+        self.states = CommandState::new();
+        self.update_signs();
+        self.apply_options();
         self.scroll();
+        true
+    }
+
+    /// Searches the files of the project, or those under the path given
+    /// after the pattern, for lines a regex matches, and lists them in the
+    /// quickfix list. The search runs the `grep_program` option's program if
+    /// there is one, and otherwise goes through the files itself.
+    fn grep(&mut self, args: &str) {
+        let (pattern, path) = match grep::parse_args(args) {
+            Ok(args) => args,
+            Err(msg) => return self.model.borrow_mut().set_status_msg(StatusMsg::Error(msg)),
+        };
+        let regex = Regex::new(&pattern);
+        let cwd = env::current_dir().unwrap_or_default();
+        let quickfix = Quickfix::new(format!("grep {}", args.trim()));
+        let program = self.model.borrow().options.grep_program.clone();
+
+        let mut started = false;
+        let mut warning = None;
+        if !program.trim().is_empty() {
+            let (dir, path) = match &path {
+                Some(path) => (cwd.clone(), path.as_str()),
+                None => (finder::project_root(&cwd), "."),
+            };
+            let found = self.quickfix_sender(quickfix.cancel_flag(), Self::grep_finished);
+            let regex = regex.as_ref().ok().cloned();
+            match grep::run(&program, (&pattern, path), dir, regex, quickfix.cancel_flag(), found) {
+                Ok(()) => started = true,
+                Err(err) => warning = Some(format!("Couldn't run {}: {}", program, err)),
+            }
+        }
+        if !started {
+            let regex = match regex {
+                Ok(regex) => regex,
+                Err(err) => {
+                    let msg = StatusMsg::Error(format!("Invalid pattern: {}", err));
+                    return self.model.borrow_mut().set_status_msg(msg);
+                }
+            };
+            let root = match &path {
+                Some(path) => cwd.join(path),
+                None => finder::project_root(&cwd),
+            };
+            if !root.exists() {
+                let msg = StatusMsg::Error(format!("Can't find {}", root.display()));
+                return self.model.borrow_mut().set_status_msg(msg);
+            }
+            let found = self.quickfix_sender(quickfix.cancel_flag(), Self::grep_finished);
+            grep::search(root, regex, quickfix.cancel_flag(), found);
+        }
+
+        self.set_quickfix(quickfix);
+        if let Some(warning) = warning {
+            self.model.borrow_mut().set_status_msg(StatusMsg::Warn(warning));
+        }
+    }
+
+    /// Says when a search found nothing or failed. The list's title counts
+    /// the matches otherwise.
+    fn grep_finished<'r, 's>(controller: &'r mut TerminalController<'s>, progress: Progress) {
+        let msg = match (progress, &controller.quickfix) {
+            (Progress::Failed(error), _) => StatusMsg::Error(error),
+            (_, Some(quickfix)) if quickfix.entries.is_empty() => {
+                StatusMsg::Warn(format!("No matches for {}", quickfix.title))
+            }
+            _ => return,
+        };
+        controller.model.borrow_mut().set_status_msg(msg);
+    }
+
+    /// Runs the `make_program` option's build command, with the arguments
+    /// given to `:make` added, and lists the compiler messages it prints in
+    /// the quickfix list
+    fn make(&mut self, args: &str) {
+        let (program, format) = {
+            let options = &self.model.borrow().options;
+            (options.make_program.clone(), options.error_format.clone())
+        };
+        let format = match ErrorFormat::parse(&format) {
+            Ok(format) => format,
+            Err(msg) => return self.model.borrow_mut().set_status_msg(StatusMsg::Error(msg)),
+        };
+        let command = format!("{} {}", program, args).trim().to_string();
+        let quickfix = Quickfix::new(command.clone());
+        let found = self.quickfix_sender(quickfix.cancel_flag(), Self::make_finished);
+        let dir = env::current_dir().unwrap_or_default();
+        if let Err(err) = make::run(&command, dir, format, quickfix.cancel_flag(), found) {
+            let msg = StatusMsg::Error(format!("Couldn't run {}: {}", command, err));
+            return self.model.borrow_mut().set_status_msg(msg);
+        }
+        self.set_quickfix(quickfix);
+        let msg = StatusMsg::Normal(format!("Running {}…", command));
+        self.model.borrow_mut().set_status_msg(msg);
+    }
+
+    /// Counts the errors and warnings of a build, or says how it went when
+    /// it printed none
+    fn make_finished<'r, 's>(controller: &'r mut TerminalController<'s>, progress: Progress) {
+        let quickfix = match &controller.quickfix {
+            Some(quickfix) => quickfix,
+            None => return,
+        };
+        let count = |kind| {
+            let count = quickfix.entries.iter().filter(|entry| entry.kind == Some(kind)).count();
+            (count, if count == 1 { "" } else { "s" })
+        };
+        let (errors, warnings) = (count(SignKind::Error), count(SignKind::Warning));
+        let summary = format!(
+            "{}: {} error{}, {} warning{}",
+            quickfix.title, errors.0, errors.1, warnings.0, warnings.1
+        );
+        let msg = match progress {
+            _ if errors.0 > 0 => StatusMsg::Error(summary),
+            _ if warnings.0 > 0 => StatusMsg::Warn(summary),
+            Progress::Failed(error) => StatusMsg::Error(error),
+            _ => StatusMsg::Normal(format!("{} succeeded", quickfix.title)),
+        };
+        controller.model.borrow_mut().set_status_msg(msg);
+    }
+
+    /// Replaces the quickfix list with a new one, which stops the command
+    /// filling the old one, and shows it
+    fn set_quickfix(&mut self, quickfix: Quickfix) {
+        self.quickfix = Some(quickfix);
+        self.update_signs();
+        self.show_quickfix(true);
+    }
+
+    /// Hands places found on another thread to the quickfix list they were
+    /// found for, unless another list has replaced it since, and calls
+    /// `finished` once the command filling it is done
+    fn quickfix_sender(
+        &self,
+        cancel: Arc<AtomicBool>,
+        finished: QuickfixCallback,
+    ) -> impl FnMut(Vec<Entry>, Progress) -> bool + Send + 'static {
+        let sender = self.events.job_sender();
+        move |entries, progress| {
+            let cancel = cancel.clone();
+            sender.send(Box::new(move |controller: &mut TerminalController| {
+                if !cancel.load(Ordering::Relaxed) {
+                    let done = !matches!(progress, Progress::Running);
+                    controller.quickfix_found(entries, done);
+                    if done {
+                        finished(controller, progress);
+                    }
+                }
+            }))
+        }
+    }
+
+    fn quickfix_found(&mut self, entries: Vec<Entry>, done: bool) {
+        let quickfix = match &mut self.quickfix {
+            Some(quickfix) => quickfix,
+            None => return,
+        };
+        quickfix.entries.extend(entries);
+        quickfix.done = done;
+        // There's nothing to list once the command is done without finding
+        // anything
+        if done && quickfix.entries.is_empty() {
+            if let Some(id) = self.quickfix_list.take() {
+                self.view.close_overlay(id);
+            }
+        }
+        self.update_signs();
+        self.show_quickfix(false);
+    }
+
+    /// Gives the rows of the current file that compiler messages in the
+    /// quickfix list are about a sign in the gutter. Where messages share a
+    /// row, the most severe one decides the sign.
+    fn update_signs(&self) {
+        let mut model = self.model.borrow_mut();
+        model.signs.clear();
+        let (quickfix, current) = match (&self.quickfix, fs::canonicalize(&model.path)) {
+            (Some(quickfix), Ok(current)) => (quickfix, current),
+            _ => return,
+        };
+        for entry in &quickfix.entries {
+            let kind = match entry.kind {
+                Some(kind) => kind,
+                None => continue,
+            };
+            if fs::canonicalize(&entry.path).ok().as_ref() != Some(&current) {
+                continue;
+            }
+            if model.signs.get(&entry.line).is_some_and(|sign| sign.kind <= kind) {
+                continue;
+            }
+            let symbol = match kind {
+                SignKind::Error => 'E',
+                SignKind::Warning => 'W',
+                SignKind::Info => 'I',
+            };
+            model.signs.insert(entry.line, Sign { symbol, kind });
+        }
+    }
+
+    /// Brings the overlay listing the quickfix list up to date, opening it
+    /// first if `open` is set. The list lies along the bottom of the text,
+    /// with the entry last jumped to selected.
+    fn show_quickfix(&mut self, open: bool) {
+        let quickfix = match &self.quickfix {
+            Some(quickfix) => quickfix,
+            None => return,
+        };
+        if open && self.quickfix_list.is_none() {
+            let overlay = Overlay::new(vec![], Placement::Screen(0, 0));
+            self.quickfix_list = Some(self.view.open_overlay(overlay));
+        }
+        let id = match self.quickfix_list {
+            Some(id) => id,
+            None => return,
+        };
+        let cwd = env::current_dir().unwrap_or_default();
+        let lines: Vec<String> = quickfix.entries.iter().map(|entry| entry.describe(&cwd)).collect();
+        let cols = self.view.get_screen_cols();
+        let rows = self.view.get_screen_rows();
+        let height = lines.len().clamp(1, QUICKFIX_HEIGHT).min(rows.saturating_sub(2));
+        let current = quickfix.current.map_or(String::new(), |idx| format!("{}/", idx + 1));
+        let searching = if quickfix.done { "" } else { "…" };
+        let title = format!(
+            " {} {}{}{} ",
+            quickfix.title,
+            current,
+            lines.len(),
+            searching
+        );
+        self.view.update_overlay(id, |overlay| {
+            overlay.lines = lines;
+            overlay.title = Some(title);
+            overlay.placement = Placement::Screen(0, rows.saturating_sub(height + 2));
+            overlay.width = Some(cols.saturating_sub(2));
+            overlay.height = Some(height);
+            overlay.selected = quickfix.current;
+        });
+    }
+
+    /// Jumps to the next or previous entry of the quickfix list
+    fn step_quickfix(&mut self, forward: bool) {
+        let next = match &self.quickfix {
+            None => Err("No quickfix list"),
+            Some(quickfix) if quickfix.entries.is_empty() => Err("The quickfix list is empty"),
+            Some(quickfix) if forward => quickfix.next().ok_or("At the last entry"),
+            Some(quickfix) => quickfix.prev().ok_or("At the first entry"),
+        };
+        match next {
+            Ok(idx) => self.goto_quickfix(idx),
+            Err(msg) => self.model.borrow_mut().set_status_msg(StatusMsg::Warn(msg.to_string())),
+        }
+    }
+
+    /// Jumps to the entry of the quickfix list numbered by `:cc`, counting
+    /// from 1, or to the current one without a number
+    fn goto_quickfix_number(&mut self, number: &str) {
+        let current = self.quickfix.as_ref().map(|quickfix| quickfix.current.unwrap_or(0));
+        let idx = match (current, number) {
+            (None, _) => Err(String::from("No quickfix list")),
+            (Some(current), "") => Ok(current),
+            (Some(_), number) => match number.parse::<usize>() {
+                Ok(number) if number > 0 => Ok(number - 1),
+                _ => Err(format!("Invalid entry number: {}", number)),
+            },
+        };
+        let len = self.quickfix.as_ref().map_or(0, |quickfix| quickfix.entries.len());
+        match idx {
+            Ok(idx) if idx < len => self.goto_quickfix(idx),
+            Ok(_) => {
+                let msg = StatusMsg::Error(format!("The quickfix list has {} entries", len));
+                self.model.borrow_mut().set_status_msg(msg);
+            }
+            Err(msg) => self.model.borrow_mut().set_status_msg(StatusMsg::Error(msg)),
+        }
+    }
+
+    /// Moves the cursor to an entry of the quickfix list, editing its file
+    /// first unless it is the current one, and shows the entry's text
+    fn goto_quickfix(&mut self, idx: usize) {
+        let (entry, len) = match &self.quickfix {
+            Some(quickfix) => match quickfix.entries.get(idx) {
+                Some(entry) => (entry.clone(), quickfix.entries.len()),
+                None => return,
+            },
+            None => return,
+        };
+        let current = fs::canonicalize(&self.model.borrow().path).ok();
+        let same_file = current.is_some() && current == fs::canonicalize(&entry.path).ok();
+        if !same_file && !self.edit_file(&entry.path) {
+            return;
+        }
+        if let Some(quickfix) = &mut self.quickfix {
+            quickfix.current = Some(idx);
+        }
+        {
+            let mut model = self.model.borrow_mut();
+            model.set_cursor(entry.col, entry.line);
+            let msg = format!("({} of {}) {}", idx + 1, len, entry.text);
+            model.set_status_msg(StatusMsg::Normal(msg));
+        }
+        self.scroll();
+        self.show_quickfix(false);
+
+        // Bring the entry out from under the list, halfway up the text above it
+        if let Some(area) = self.quickfix_list.and_then(|id| self.view.overlay_area(id)) {
+            let (_, y) = self.view.cursor_screen_position();
+            if y >= area.y {
+                let mut model = self.model.borrow_mut();
+                model.rowoff = (model.rowoff + y - area.y / 2).min(model.cy);
+            }
+        }
+    }
+
+    /// Runs an operator on the selected rows right away, or otherwise waits
+    /// for the motion giving it rows
+    fn start_operator(&mut self, operator: Operator) {
+        if self.model.borrow().text_selected {
+            self.apply_operator(operator, self.target_rows());
+        } else {
+            self.operator = Some(operator);
+        }
+    }
+
+    /// Handles the action following an operator. The same operator again
+    /// works on the cursor row, a motion works on the rows from the cursor
+    /// row to the one it moves to, and anything else cancels the operator.
+    fn finish_operator(
+        &mut self,
+        operator: Operator,
+        action: Action,
+    ) -> Result<bool, std::io::Error> {
+        if Operator::for_action(action) == Some(operator) {
+            self.apply_operator(operator, self.target_rows());
+        } else if action.is_motion() {
+            let from = self.model.borrow().cy;
+            self.run_action(action)?;
+            let to = self.model.borrow().cy;
+            self.apply_operator(operator, (from.min(to), from.max(to)));
+        }
+        self.finish_input();
         Ok(true)
     }
 
-    pub fn process_input_insert(&mut self) -> Result<bool, std::io::Error> {
-        let stdin = stdin();
-        let mut stdout = MouseTerminal::from(stdout().into_raw_mode().unwrap());
+    /// Runs an operator on the rows `first..=last`, leaving the cursor on
+    /// the first of them
+    fn apply_operator(&mut self, operator: Operator, (first, last): (usize, usize)) {
+        self.model.borrow_mut().cy = first;
+        match operator {
+            Operator::Indent => self.shift_rows((first, last), true),
+            Operator::Dedent => self.shift_rows((first, last), false),
+            Operator::Reindent => self.reindent_rows((first, last)),
+            Operator::Comment => self.toggle_comment((first, last), false),
+            Operator::BlockComment => self.toggle_comment((first, last), true),
+            Operator::Filter => self.prompt_filter((first, last)),
+        }
+    }
+
+    /// Handles a key no binding covers: insert mode inserts characters, and
+    /// everything else is ignored
+    fn process_unbound_key(&mut self, mode: KeymapMode, key: Key) {
+        self.operator = None;
+        if let (KeymapMode::Insert, Key::Char(c)) = (mode, key) {
+            self.insert_char(c);
+            self.update_completion();
+            self.finish_input();
+        }
+    }
 
-        stdout.flush().unwrap();
-        for c in stdin.events() {
-            let evt = c.unwrap();
-            match evt {
-                Event::Key(key) => match key {
-                    Key::Ctrl('q') => {
-                        self.quit_times = self.quit();
-                        if self.quit_times == 0 {
-                            return Ok(false);
+    // Mouse indices are 1-based so we subtract 1 to make 0-based
+    fn process_mouse(&mut self, me: MouseEvent) {
+        match me {
+            MouseEvent::Press(button, x, y) => {
+                match self.view.overlay_at((x - 1) as usize, (y - 1) as usize) {
+                    Some((id, line)) => self.overlay_press(id, button, line),
+                    None => {
+                        // Clicking anywhere else closes the overlays
+                        while let Some(id) = self.view.top_overlay() {
+                            self.close_overlay(id);
                         }
-                        return Ok(true);
-                    }
-                    Key::Ctrl('S') => {
-                        self.enter_prompt_mode(PromptType::Rename);
-                        break; 
-                    }
-                    Key::Ctrl('s') => {
-                        self.save();
-                        break;
-                    }
-                    Key::Esc | Key::Ctrl('c') => {
-                        self.enter_normal_mode();
-                        break;
-                    }
-                    Key::Ctrl('f') => {
-                        self.enter_prompt_mode(PromptType::Find);
-                        break;
-                    }
-                    Key::Left | Key::Right | Key::Up | Key::Down => {
-                        self.move_cursor(key);
-                        break;
-                    }
-                    Key::Backspace | Key::Delete | Key::Ctrl('h') => {
-                        self.delete();
-                        break;
-                    }
-                    Key::PageDown => {
-                        self.page_down();
-                        break;
-                    }
-                    Key::PageUp => {
-                        self.page_up();
-                        break;
-                    }
-                    Key::Char('\r') | Key::Char('\n') => {
-                        self.insert_newline();
-                        break;
-                    }
-                    Key::Char(c) => {
-                        self.insert_char(c);
-                        break;
-                    }
-                    Key::Ctrl(_) | Key::Alt(_) => {}
-                    _ => {
-                        break;
-                    }
-                },
-                // Mouse indices are 1-based so we subtract 1 to make 0-based
-                Event::Mouse(me) => match me {
-                    MouseEvent::Press(_, x, y) => {
                         self.mouse_press(x - 1, y - 1);
-                        break;
-                    }
-                    MouseEvent::Hold(x, y) => {
-                        self.mouse_hold(x - 1, y - 1);
-                        break;
-                    }
-                    MouseEvent::Release(_, _) => {
-                        self.mouse_release();
-                        break;
                     }
-                },
-                Event::Unsupported(_) => todo!(),
+                }
+            }
+            // A drag starting on an overlay doesn't select text
+            MouseEvent::Hold(_, _) if self.mouse_on_overlay => {}
+            MouseEvent::Hold(x, y) => {
+                self.mouse_hold(x - 1, y - 1);
+            }
+            MouseEvent::Release(_, _) => {
+                self.mouse_release();
             }
-            stdout.flush().unwrap();
         }
-        stdout.flush().unwrap();
-        if self.quit_times != QUIT_TIMES {
+    }
+
+    /// Common bookkeeping after a key or mouse event was handled in normal or insert mode
+    fn finish_input(&mut self) {
+        let quit_times = self.model.borrow().options.quit_times;
+        if self.quit_times != quit_times {
             self.abort_quit();
-            self.quit_times = QUIT_TIMES;
+            self.quit_times = quit_times;
         }
         self.scroll();
+    }
+
+    fn process_timer(&mut self, timer: Timer) -> Result<bool, std::io::Error> {
+        match timer {
+            Timer::KeySequence => return self.process_pending_keys(true),
+            Timer::StatusExpiry => {
+                let mut model = self.model.borrow_mut();
+                if model.status_time.elapsed() >= STATUS_MSG_TIMEOUT {
+                    model.status_msg = StatusMsg::Normal(String::from(""));
+                }
+            }
+            Timer::Autosave => {
+                let model = self.model.borrow();
+                if model.dirty && !model.filename.is_empty() {
+                    drop(model);
+                    self.save();
+                }
+            }
+        }
         Ok(true)
     }
 
+    /// Brings the parts of the editor outside the model in line with the
+    /// options after they may have changed: the theme and the autosave timer
+    fn apply_options(&mut self) {
+        let (theme, autosave) = {
+            let options = &self.model.borrow().options;
+            (options.theme.clone(), options.autosave)
+        };
+        if theme != self.view.theme_name() {
+            match Theme::load(&theme) {
+                Ok(theme) => self.view.set_theme(theme),
+                Err(err) => self
+                    .model
+                    .borrow_mut()
+                    .set_status_msg(StatusMsg::Error(err.to_string())),
+            }
+        }
+        if autosave != self.autosave {
+            self.autosave = autosave;
+            if autosave == 0 {
+                self.events.cancel(Timer::Autosave);
+            } else {
+                let interval = Duration::from_secs(autosave);
+                self.events.schedule(Timer::Autosave, interval, true);
+            }
+        }
+    }
+
+    fn process_terminal_event(&mut self, evt: Event) -> Result<bool, std::io::Error> {
+        // Process input based on the mode we are in
+        match &self.mode {
+            TerminalMode::Normal | TerminalMode::Insert => self.process_input_keymapped(evt),
+            TerminalMode::Search(_) => self.process_input_search(evt),
+            TerminalMode::Finder => self.process_input_finder(evt),
+            TerminalMode::Prompt(p) => match p {
+                PromptType::Find => {
+                    self.process_input_prompt(evt, TerminalController::find_callback)
+                }
+                PromptType::Rename => {
+                    self.process_input_prompt(evt, TerminalController::rename_callback)
+                }
+                PromptType::Command => {
+                    self.process_input_prompt(evt, TerminalController::command_callback)
+                }
+            },
+        }
+    }
+
+    /// Arms the expiry timer whenever a new status message has been set
+    fn schedule_status_expiry(&mut self) {
+        let status_time = self.model.borrow().status_time;
+        if self.status_time != Some(status_time) {
+            self.status_time = Some(status_time);
+            let remaining = STATUS_MSG_TIMEOUT.saturating_sub(status_time.elapsed());
+            self.events.schedule(Timer::StatusExpiry, remaining, false);
+        }
+    }
+
     fn enter_insert_mode(&mut self) {
         print!("{}", termion::cursor::BlinkingBar);
         self.model.borrow_mut().mode = 'I';
@@ -405,13 +1718,23 @@ impl<'a> TerminalController<'a> {
 
     fn enter_prompt_mode(&mut self, prompt: PromptType) {
         print!("{}", termion::cursor::BlinkingBar);
-        self.model.borrow_mut().mode = 'P';
+        let label = match prompt {
+            PromptType::Find => "Find:",
+            PromptType::Rename => "Name file:",
+            PromptType::Command => ":",
+        };
+        let mut model = self.model.borrow_mut();
+        model.mode = 'P';
+        model.prompt = Some((String::from(label), String::new()));
         self.mode = TerminalMode::Prompt(prompt);
     }
 
     fn enter_normal_mode(&mut self) {
         print!("{}", termion::cursor::SteadyBlock);
-        self.model.borrow_mut().mode = 'N';
+        self.close_completion();
+        let mut model = self.model.borrow_mut();
+        model.mode = 'N';
+        model.prompt = None;
         self.mode = TerminalMode::Normal;
     }
 
@@ -421,9 +1744,23 @@ impl<'a> TerminalController<'a> {
         model.save_file();
     }
 
+    /// Picks up the new terminal dimensions and clamps the scroll offsets so
+    /// the cursor stays on screen. The caller is responsible for redrawing.
+    fn handle_resize(&self) {
+        self.view.update_size();
+        self.scroll();
+    }
+
     fn scroll(&self) {
+        if self.model.borrow().options.wrap {
+            self.scroll_wrapped();
+            return;
+        }
+
+        // The view reads the model to size the gutter, so ask before borrowing it mutably
+        let screenrows = self.view.get_screen_rows().max(1);
+        let screencols = self.view.get_text_cols().max(1);
         let model = &mut self.model.borrow_mut();
-        let screenrows = TerminalView::get_screen_rows();
 
         // If our cursor went above the view, scroll up
         if model.cy < model.rowoff {
@@ -438,11 +1775,74 @@ impl<'a> TerminalController<'a> {
             model.coloff = model.cx;
         }
         // If cursor is off-screen to the right, scroll right
-        if model.cx >= model.coloff + screenrows {
-            model.coloff = model.cx - screenrows + 1;
+        if model.cx >= model.coloff + screencols {
+            model.coloff = model.cx - screencols + 1;
         }
     }
 
+    /// Scrolling when rows are wrapped. Whole rows are scrolled vertically until
+    /// the cursor's screen line is visible, and there is no horizontal scrolling.
+    fn scroll_wrapped(&self) {
+        let screenrows = self.view.get_screen_rows().max(1);
+        {
+            let model = &mut self.model.borrow_mut();
+            model.coloff = 0;
+            if model.cy < model.rowoff {
+                model.rowoff = model.cy;
+            }
+        }
+        loop {
+            let (_, y) = self.view.cursor_screen_position();
+            let model = &mut self.model.borrow_mut();
+            if y < screenrows || model.rowoff >= model.cy {
+                break;
+            }
+            model.rowoff += 1;
+        }
+    }
+
+    /// Moves the cursor up or down by one screen line rather than one row,
+    /// keeping its screen column. Only differs from j/k on wrapped rows.
+    fn move_display_line(&self, down: bool) {
+        let (cx, cy, num_rows) = {
+            let model = self.model.borrow();
+            (model.cx, model.cy, model.num_rows())
+        };
+        if !self.model.borrow().options.wrap || cy >= num_rows {
+            self.move_cursor(if down { Key::Down } else { Key::Up });
+            return;
+        }
+
+        let segments = self.view.row_segments(cy);
+        let seg_idx = TerminalView::segment_of(&segments, cx);
+        let col = self.view.column_in_segment(cy, &segments, seg_idx, cx);
+
+        let (target_row, target_seg) = if down {
+            if seg_idx + 1 < segments.len() {
+                (cy, seg_idx + 1)
+            } else if cy + 1 < num_rows {
+                (cy + 1, 0)
+            } else {
+                self.move_cursor(Key::Down);
+                return;
+            }
+        } else if seg_idx > 0 {
+            (cy, seg_idx - 1)
+        } else if cy > 0 {
+            (cy - 1, self.view.row_segments(cy - 1).len() - 1)
+        } else {
+            return;
+        };
+
+        let segments = self.view.row_segments(target_row);
+        let x = self.view.byte_in_segment(target_row, &segments, target_seg, col);
+
+        let model = &mut self.model.borrow_mut();
+        model.text_selected = false;
+        model.cy = target_row;
+        model.cx = x;
+    }
+
     fn move_cursor(&self, key: termion::event::Key) {
         let model = &mut self.model.borrow_mut();
 
@@ -493,6 +1893,18 @@ impl<'a> TerminalController<'a> {
         model.cx = len;
     }
 
+    /// Jumps from the first bracket at or after the cursor on its row to the
+    /// bracket matching it, as `%` does
+    fn goto_matching_bracket(&self) {
+        let mut model = self.model.borrow_mut();
+        let (cx, cy) = (model.cx, model.cy);
+        let code = model.row_code(cy);
+        let bracket = code.bytes().skip(cx).position(|b| b"()[]{}".contains(&b));
+        if let Some((x, y)) = bracket.and_then(|idx| model.matching_bracket(cx + idx, cy)) {
+            model.set_cursor(x, y);
+        }
+    }
+
     fn goto_line_start(&self) {
         let mut model = self.model.borrow_mut();
         model.cx = 0;
@@ -503,6 +1915,13 @@ impl<'a> TerminalController<'a> {
         if model.text_selected {
             let (anchor_start, anchor_end) = model.get_anchors();
             self.states.execute_command(Command::DeleteString{start: anchor_start, end: anchor_end}, model);
+        } else if Self::inside_empty_pair(model) {
+            let (cx, cy) = (model.cx, model.cy);
+            let mut cmds = vec![
+                Command::DeleteChar{ location: (cx + 1, cy) },
+                Command::DeleteChar{ location: (cx, cy) },
+            ];
+            self.states.execute_command_group(&mut cmds, model);
         } else {
             self.states.execute_command(Command::DeleteChar{ location: (model.cx, model.cy) }, model);
         }
@@ -514,18 +1933,398 @@ impl<'a> TerminalController<'a> {
     fn page_up(&self) {}
 
     fn insert_char(&mut self, c: char) {
+        let model = &mut self.model.borrow_mut();
+        let replacing = model.text_selected;
+        if replacing {
+            let (anchor_start, anchor_end) = model.get_anchors();
+            self.states.execute_command(Command::DeleteString{start: anchor_start, end: anchor_end}, model);
+        }
+        model.text_selected = false;
+        let location = (model.cx, model.cy);
+        match Self::auto_pair(model, c) {
+            Some(Pairing::StepOver) => {
+                model.cx += c.len_utf8();
+                return;
+            }
+            Some(Pairing::Close(close)) => {
+                // The closing character goes in the same undo step
+                let after = location.0 + c.len_utf8();
+                let mut cmds = vec![
+                    Command::InsertChar{ location, c },
+                    Command::InsertChar{ location: (after, location.1), c: close },
+                ];
+                self.states.execute_command_group(&mut cmds, model);
+                model.cx = after;
+            }
+            None => match Self::electric_indent(model, c) {
+                Some((old_len, new)) => {
+                    // Typing the character and fixing the indentation undo together
+                    let cx = location.0 + c.len_utf8() - old_len + new.len();
+                    let mut cmds = vec![Command::InsertChar{ location, c }];
+                    cmds.extend(Self::replace_indent(location.1, old_len, new));
+                    self.states.execute_command_group(&mut cmds, model);
+                    model.cx = cx;
+                }
+                None => self.states.execute_command(Command::InsertChar{ location, c }, model),
+            },
+        }
+        // The selection the character replaced comes back with it on undo
+        if replacing {
+            self.states.join_last_steps();
+        }
+    }
+
+    /// Whether typing `c` should type its closing character too, or step over
+    /// the same closing character after the cursor. Opening characters are
+    /// only paired in front of whitespace, a closing character or the end of
+    /// the row. Quotes aren't paired after a word, as in "don't", nor after
+    /// or inside a string or comment.
+    fn auto_pair(model: &Model, c: char) -> Option<Pairing> {
+        let (cx, cy) = (model.cx, model.cy);
+        if !model.options.autopairs || cy >= model.num_rows() {
+            return None;
+        }
+        let contents = model.get_row_contents(cy);
+        let prev = contents[..cx].chars().next_back();
+        let next = contents[cx..].chars().next();
+        let pairs = model.pairs();
+        let is_close = |c: char| pairs.iter().any(|(_, close)| *close == c);
+
+        if next == Some(c) && is_close(c) {
+            return Some(Pairing::StepOver);
+        }
+        let close = pairs.iter().find(|(open, _)| *open == c)?.1;
+        if next.is_some_and(|next| !next.is_whitespace() && !is_close(next)) {
+            return None;
+        }
+        if close == c {
+            let after_word = |prev: char| prev.is_alphanumeric() || "_\\".contains(prev);
+            if prev.is_some_and(|prev| after_word(prev) || prev == c) {
+                return None;
+            }
+            let code = model.row_code(cy);
+            if cx > 0 && code.as_bytes()[cx - 1] != contents.as_bytes()[cx - 1] {
+                return None;
+            }
+        }
+        Some(Pairing::Close(close))
+    }
+
+    /// Whether the cursor sits between an opening character and its closing
+    /// one, which backspace deletes together
+    fn inside_empty_pair(model: &Model) -> bool {
+        let (cx, cy) = (model.cx, model.cy);
+        if !model.options.autopairs || cy >= model.num_rows() {
+            return false;
+        }
+        let contents = model.get_row_contents(cy);
+        match (contents[..cx].chars().next_back(), contents[cx..].chars().next()) {
+            (Some(prev), Some(next)) => model.pairs().contains(&(prev, next)),
+            _ => false,
+        }
+    }
+
+    /// When typing `c` turns the cursor row into one indented a level less,
+    /// such as a line starting with a closing brace, returns the length of
+    /// the row's indentation and what it should be replaced with
+    fn electric_indent(model: &Model, c: char) -> Option<(usize, String)> {
+        let (cx, cy) = (model.cx, model.cy);
+        if !model.options.autoindent || cy >= model.num_rows() {
+            return None;
+        }
+        let contents = model.get_row_contents(cy);
+        let code = model.row_code(cy);
+        // Nothing typed inside a comment or string counts
+        if cx > code.len() || (cx > 0 && code.as_bytes()[cx - 1] != contents.as_bytes()[cx - 1]) {
+            return None;
+        }
+        let typed = format!("{}{}{}", &code[..cx], c, &code[cx..]);
+        let rules = model.indent_rules();
+        if rules.decreases(&code) || !rules.decreases(&typed) {
+            return None;
+        }
+        let options = &model.options;
+        let width = model.expected_indent(cy, &typed);
+        let old_len = indent::leading_whitespace(contents).len();
+        if cx < old_len || width == indent::indent_width(contents, options.tab_width) {
+            return None;
+        }
+        Some((old_len, indent::indent_string(width, options.tab_width, options.expandtab)))
+    }
+
+    /// Commands replacing the first `old_len` bytes of a row's indentation
+    /// with `new`
+    fn replace_indent(row: usize, old_len: usize, new: String) -> Vec<Command> {
+        let mut cmds = vec![];
+        if old_len > 0 {
+            cmds.push(Command::DeleteString{ start: (0, row), end: (old_len, row) });
+        }
+        if !new.is_empty() {
+            cmds.push(Command::InsertString{ location: (0, row), contents: new });
+        }
+        cmds
+    }
+
+    /// Inserts spaces up to the next tab stop, in place of a tab character
+    fn insert_tab_spaces(&mut self) {
         let model = &mut self.model.borrow_mut();
         if model.text_selected {
             let (anchor_start, anchor_end) = model.get_anchors();
             self.states.execute_command(Command::DeleteString{start: anchor_start, end: anchor_end}, model);
         }
-        self.states.execute_command(Command::InsertChar{ location: (model.cx, model.cy), c }, model);
+        let tab_width = model.options.tab_width;
+        let column = match model.cy < model.num_rows() {
+            true => model.get_row_contents(model.cy)[..model.cx].chars().count(),
+            false => 0,
+        };
+        let contents = " ".repeat(tab_width - column % tab_width);
+        self.states.execute_command(Command::InsertString{ location: (model.cx, model.cy), contents }, model);
         model.text_selected = false;
     }
 
+    /// Splits the row at the cursor. With autoindent, the new row is indented
+    /// to suit the code above it, in the same undo step as the newline.
+    /// Splitting between an opening and a closing brace leaves the cursor on
+    /// an indented row of its own between them.
     fn insert_newline(&mut self) {
         let model = &mut self.model.borrow_mut();
-        self.states.execute_command(Command::InsertNewline{ location: (model.cx, model.cy)}, model)
+        let (cx, cy) = (model.cx, model.cy);
+        if !model.options.autoindent || cy >= model.num_rows() {
+            self.states.execute_command(Command::InsertNewline{ location: (cx, cy)}, model);
+            return;
+        }
+
+        let contents = model.get_row_contents(cy).clone();
+        if contents[..cx].trim().is_empty() {
+            // The row keeps its own indentation and moves down along with the cursor
+            self.states.execute_command(Command::InsertNewline{ location: (0, cy)}, model);
+            model.cx = cx;
+            return;
+        }
+
+        let code = model.row_code(cy);
+        let (options, rules) = (&model.options, model.indent_rules());
+        let indent = |width| indent::indent_string(width, options.tab_width, options.expandtab);
+        let rest = indent::leading_whitespace(&contents[cx..]).len();
+        let prev = Some((&contents[..cx], &code[..cx]));
+        let rest_code = &code[cx + rest..];
+        let width = rules.indent_after(prev, rest_code, options.tab_width);
+
+        let mut cmds = vec![Command::InsertNewline{ location: (cx, cy) }];
+        if rest > 0 {
+            cmds.push(Command::DeleteString{ start: (0, cy + 1), end: (rest, cy + 1) });
+        }
+        let mut cursor = None;
+        let mut new_indent = indent(width);
+        if rules.increases(&code[..cx]) && rules.decreases(rest_code) {
+            let inner = indent(rules.indent_after(prev, "", options.tab_width));
+            cursor = Some((inner.len(), cy + 1));
+            new_indent = format!("{}\n{}", inner, new_indent);
+        }
+        if !new_indent.is_empty() {
+            cmds.push(Command::InsertString{ location: (0, cy + 1), contents: new_indent });
+        }
+        self.states.execute_command_group(&mut cmds, model);
+        if let Some((x, y)) = cursor {
+            model.set_cursor(x, y);
+        }
+    }
+
+    /// Opens a row above the cursor row, indented like the code above it
+    fn open_line_above(&mut self) {
+        let model = &mut self.model.borrow_mut();
+        let cy = model.cy;
+        if cy >= model.num_rows() {
+            self.states.execute_command(Command::InsertNewline{ location: (0, cy)}, model);
+            return;
+        }
+        let mut cmds = vec![Command::InsertNewline{ location: (0, cy) }];
+        let options = &model.options;
+        if options.autoindent {
+            let width = model.expected_indent(cy, "");
+            let new_indent = indent::indent_string(width, options.tab_width, options.expandtab);
+            cmds.extend(Self::replace_indent(cy, 0, new_indent));
+        }
+        self.states.execute_command_group(&mut cmds, model);
+        let len = model.row_len(cy);
+        model.set_cursor(len, cy);
+    }
+
+    /// Rows an operator works on without a motion: the selected ones, or the
+    /// cursor row when nothing is selected
+    fn target_rows(&self) -> (usize, usize) {
+        let model = self.model.borrow();
+        let last = model.num_rows().saturating_sub(1);
+        if model.text_selected {
+            let (start, end) = model.get_anchors();
+            // A selection ending at the start of a row doesn't take that row in
+            let end_row = match end.0 == 0 && end.1 > start.1 {
+                true => end.1 - 1,
+                false => end.1,
+            };
+            return (start.1, end_row.min(last));
+        }
+        (model.cy.min(last), model.cy.min(last))
+    }
+
+    /// Moves rows a level to the right or left, as `>>` and `<<` do. Blank
+    /// rows are left alone.
+    fn shift_rows(&mut self, (first, last): (usize, usize), right: bool) {
+        let model = &mut self.model.borrow_mut();
+        let options = &model.options;
+        let mut cmds = vec![];
+        for row in first..(last + 1).min(model.num_rows()) {
+            let contents = model.get_row_contents(row);
+            if contents.trim().is_empty() {
+                continue;
+            }
+            let width = indent::indent_width(contents, options.tab_width);
+            let width = match right {
+                true => width + options.tab_width,
+                false => width.saturating_sub(options.tab_width),
+            };
+            let old_len = indent::leading_whitespace(contents).len();
+            let new = indent::indent_string(width, options.tab_width, options.expandtab);
+            if contents[..old_len] != new {
+                cmds.extend(Self::replace_indent(row, old_len, new));
+            }
+        }
+        Self::finish_line_edit(&mut self.states, model, cmds);
+    }
+
+    /// Indents rows the way autoindent would have, as `=` does. Rows that
+    /// start inside a comment or string keep their indentation, and blank
+    /// rows lose theirs.
+    fn reindent_rows(&mut self, (first, last): (usize, usize)) {
+        let model = &mut self.model.borrow_mut();
+        let options = &model.options;
+        let rules = model.indent_rules();
+        // Rows already reindented haven't changed yet, so the row above is
+        // tracked as it will end up
+        let mut prev = model
+            .indent_base_row(first)
+            .map(|idx| (model.get_row_contents(idx).clone(), model.row_code(idx)));
+        let mut cmds = vec![];
+        for row in first..(last + 1).min(model.num_rows()) {
+            let contents = model.get_row_contents(row);
+            let old_len = indent::leading_whitespace(contents).len();
+            if contents.trim().is_empty() {
+                cmds.extend(Self::replace_indent(row, old_len, String::new()));
+                continue;
+            }
+            if model.row_continues_token(row) {
+                continue;
+            }
+            let code = model.row_code(row);
+            let above = prev.as_ref().map(|(contents, code)| (contents.as_str(), code.as_str()));
+            let width = rules.indent_after(above, &code, options.tab_width);
+            let new = indent::indent_string(width, options.tab_width, options.expandtab);
+            prev = Some((format!("{}{}", new, &contents[old_len..]), code));
+            if contents[..old_len] != new {
+                cmds.extend(Self::replace_indent(row, old_len, new));
+            }
+        }
+        Self::finish_line_edit(&mut self.states, model, cmds);
+    }
+
+    /// Comments rows out, or back in when they are all commented already, as
+    /// a single undo step. `block` wraps them in one block comment instead of
+    /// commenting each row.
+    fn toggle_comment(&mut self, (first, last): (usize, usize), block: bool) {
+        let model = &mut self.model.borrow_mut();
+        let tokens = model.comment_tokens().cloned().unwrap_or_default();
+        let rows: Vec<&str> = (first..(last + 1).min(model.num_rows()))
+            .map(|idx| model.get_row_contents(idx).as_str())
+            .collect();
+        let cmds = match block {
+            true => comment::toggle_block(&rows, first, &tokens),
+            false => comment::toggle_lines(&rows, first, &tokens),
+        };
+        match cmds {
+            Some(cmds) => Self::finish_line_edit(&mut self.states, model, cmds),
+            None => {
+                let kind = if block { "block comments" } else { "comments" };
+                let language = model.syntax_name().unwrap_or("Plaintext").to_string();
+                let msg = format!("No {} known for {}", kind, language);
+                model.set_status_msg(StatusMsg::Error(msg));
+            }
+        }
+    }
+
+    /// Replaces the rows `first..=last` with the lines of `text` as one undo
+    /// step. Empty text takes the rows out altogether.
+    fn replace_rows(&mut self, (first, last): (usize, usize), text: &str) {
+        let model = &mut self.model.borrow_mut();
+        let lines = text.strip_suffix('\n').unwrap_or(text);
+        let mut cmds = vec![];
+        model.cy = first;
+        if last + 1 < model.num_rows() {
+            cmds.push(Command::DeleteString {
+                start: (0, first),
+                end: (0, last + 1),
+            });
+            if !text.is_empty() {
+                let contents = format!("{}\n", lines);
+                cmds.push(Command::InsertString { location: (0, first), contents });
+            }
+        } else {
+            // The rows run to the end of the buffer, so taking them out takes
+            // the line break before them too
+            let start = match (text.is_empty(), first) {
+                (true, 1..) => {
+                    model.cy = first - 1;
+                    (model.row_len(first - 1), first - 1)
+                }
+                _ => (0, first),
+            };
+            let end = (model.row_len(last), last);
+            if start != end {
+                cmds.push(Command::DeleteString { start, end });
+            }
+            if !lines.is_empty() {
+                let contents = lines.to_string();
+                cmds.push(Command::InsertString { location: (0, first), contents });
+            }
+        }
+        Self::finish_line_edit(&mut self.states, model, cmds);
+    }
+
+    /// Inserts the lines of `text` after line `after`, counting from 1, or
+    /// above the first line when it is 0, as one undo step
+    fn insert_rows(&mut self, after: usize, text: &str) {
+        let model = &mut self.model.borrow_mut();
+        let lines = text.strip_suffix('\n').unwrap_or(text);
+        let cmd = match (model.num_rows(), after) {
+            (0, _) => Command::InsertString {
+                location: (0, 0),
+                contents: lines.to_string(),
+            },
+            (_, 0) => Command::InsertString {
+                location: (0, 0),
+                contents: format!("{}\n", lines),
+            },
+            (_, after) => Command::InsertString {
+                location: (model.row_len(after - 1), after - 1),
+                contents: format!("\n{}", lines),
+            },
+        };
+        model.cy = after;
+        Self::finish_line_edit(&mut self.states, model, vec![cmd]);
+    }
+
+    /// Runs the commands of a change to whole rows as one undo step, leaving
+    /// the cursor on the first non-blank character of its row
+    fn finish_line_edit(states: &mut CommandState, model: &mut Model, mut cmds: Vec<Command>) {
+        let (cx, cy) = (model.cx, model.cy);
+        if !cmds.is_empty() {
+            states.execute_command_group(&mut cmds, model);
+        }
+        let start = match cy < model.num_rows() {
+            true => indent::leading_whitespace(model.get_row_contents(cy)).len(),
+            false => cx,
+        };
+        model.set_cursor(start, cy);
+        model.text_selected = false;
     }
 
     fn quit(&self) -> u8 {
@@ -536,17 +2335,17 @@ impl<'a> TerminalController<'a> {
             self.quit_times - 1
         };
         if quit_times > 0 {
-            model.status_msg = StatusMsg::Warn(format!(
+            model.set_status_msg(StatusMsg::Warn(format!(
                 "File has unsaved changes! Quit {} more times to force-quit.",
                 quit_times
-            ));
+            )));
         }
         quit_times
     }
 
     fn abort_quit(&self) {
         let mut model = self.model.borrow_mut();
-        model.status_msg = StatusMsg::Normal(String::from(""));
+        model.set_status_msg(StatusMsg::Normal(String::from("")));
     }
 
     fn mouse_press(&mut self, x: u16, y: u16) {
@@ -566,19 +2365,51 @@ impl<'a> TerminalController<'a> {
 
         model.anchor_end = (cx, cy);
 
-        if cx != model.anchor_start.0 || cy != model.anchor_start.1 {
-            model.text_selected = true;
-        } else {
-            model.text_selected = false;
-        }
+        model.text_selected = cx != model.anchor_start.0 || cy != model.anchor_start.1;
 
         model.cx = cx;
         model.cy = cy;
     }
 
-    fn mouse_release(&mut self) {}
+    fn mouse_release(&mut self) {
+        self.mouse_on_overlay = false;
+    }
+
+    /// Handles a press on an overlay: the wheel scrolls it, and a click
+    /// raises it and, in a list, picks the line clicked
+    fn overlay_press(&mut self, id: OverlayId, button: MouseButton, line: Option<usize>) {
+        match button {
+            MouseButton::WheelUp => self.view.scroll_overlay(id, -OVERLAY_WHEEL_LINES),
+            MouseButton::WheelDown => self.view.scroll_overlay(id, OVERLAY_WHEEL_LINES),
+            _ => {
+                self.mouse_on_overlay = true;
+                self.view.raise_overlay(id);
+                if let (Some((popup, other)), Some(line)) = (&mut self.completion, line) {
+                    if *other == id {
+                        popup.selected = line;
+                        self.accept_completion();
+                    }
+                }
+                if let (Some(picker), Some(line)) = (&mut self.picker, line) {
+                    if picker.list == id {
+                        picker.finder.selected = line;
+                        self.open_selected_file();
+                    }
+                }
+                if let Some(line) = line.filter(|_| self.quickfix_list == Some(id)) {
+                    self.goto_quickfix(line);
+                }
+            }
+        }
+    }
 
     fn screen_to_model_coords(&self, x: u16, y: u16) -> (usize, usize) {
+        // Clicks inside the gutter land at the start of the row
+        let x = (x as usize).saturating_sub(self.view.gutter_width()) as u16;
+        if self.model.borrow().options.wrap {
+            return self.wrapped_screen_to_model_coords(x as usize, y as usize);
+        }
+
         let model = self.model.borrow();
         let mut cx = model.coloff + (x as usize);
         let mut cy = model.rowoff + (y as usize);
@@ -599,28 +2430,583 @@ impl<'a> TerminalController<'a> {
         }
         (cx, cy)
     }
+
+    fn wrapped_screen_to_model_coords(&self, x: usize, y: usize) -> (usize, usize) {
+        let (rowoff, num_rows) = {
+            let model = self.model.borrow();
+            (model.rowoff, model.num_rows())
+        };
+
+        // Walk the screen lines from the top until we reach the clicked one
+        let mut line = 0;
+        for row_idx in rowoff..num_rows {
+            let segments = self.view.row_segments(row_idx);
+            if y < line + segments.len() {
+                let cx = self.view.byte_in_segment(row_idx, &segments, y - line, x);
+                return (cx, row_idx);
+            }
+            line += segments.len();
+        }
+        (0, num_rows)
+    }
 }
 
 impl<'a> InputHandler for TerminalController<'a> {
-    fn process_input(&mut self) -> Result<bool, std::io::Error> {
+    fn process_input(&mut self, evt: EditorEvent) -> Result<bool, std::io::Error> {
+        let result = match evt {
+            EditorEvent::Input(evt) => self.process_terminal_event(evt),
+            EditorEvent::Resize => {
+                self.handle_resize();
+                self.show_finder();
+                self.show_quickfix(false);
+                Ok(true)
+            }
+            EditorEvent::Timer(timer) => self.process_timer(timer),
+            EditorEvent::Job(completion) => {
+                completion(self);
+                Ok(true)
+            }
+        };
+
         // Model is 'dirty' if we have unsaved changes
         self.model.borrow_mut().dirty = self.states.change_count != 0;
+        self.schedule_status_expiry();
+        result
+    }
+}
 
-        // Process input based on the mode we are in
-        match &self.mode {
-            TerminalMode::Normal => self.process_input_normal(),
-            TerminalMode::Insert => self.process_input_insert(),
-            TerminalMode::Prompt(p) => match p {
-                PromptType::Find => self.process_input_prompt(
-                    String::from("Find:"),
-                    TerminalController::find_callback,
-                ),
-                PromptType::Command => self.process_input_prompt(
-                    String::from("Name file:"),
-                    TerminalController::rename_callback,
-                ),
-                PromptType::Rename => todo!()
+/// Splits the arguments of `:set` at whitespace, except where a backslash
+/// escapes it, so that values can hold spaces
+fn split_set_args(args: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next.is_whitespace() => word.push(next),
+                Some(next) => {
+                    word.push(c);
+                    word.push(next);
+                }
+                None => word.push(c),
             },
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::HeadlessBackend;
+
+    /// Runs `test` on a controller editing `text`, drawn on a headless
+    /// terminal of the given size
+    fn with_editor<F>(text: &str, (cols, rows): (usize, usize), test: F)
+    where
+        F: FnOnce(&mut TerminalController, &HeadlessBackend),
+    {
+        let model = Rc::new(RefCell::new(Model::new()));
+        model.borrow_mut().insert_string(text);
+        model.borrow_mut().set_cursor(0, 0);
+        let backend = Rc::new(HeadlessBackend::new(cols, rows));
+        let view = TerminalView::new(Rc::clone(&model), backend.clone());
+        view.update_size();
+        let mut controller = TerminalController::new(model, &view, Rc::new(EventLoop::headless()));
+        test(&mut controller, &backend);
+    }
+
+    fn press(controller: &mut TerminalController, key: Key) {
+        controller.process_input_keymapped(Event::Key(key)).unwrap();
+    }
+
+    fn long_lines(count: usize, len: usize) -> String {
+        vec!["x".repeat(len); count].join("\n")
+    }
+
+    #[test]
+    fn shrinking_scrolls_the_cursor_back_into_view() {
+        with_editor(&long_lines(100, 200), (80, 24), |controller, backend| {
+            controller.model.borrow_mut().set_cursor(150, 90);
+            controller.scroll();
+            assert_eq!(controller.model.borrow().rowoff, 69);
+            assert_eq!(controller.model.borrow().coloff, 71);
+
+            backend.resize(40, 10);
+            controller.handle_resize();
+            let model = controller.model.borrow();
+            assert_eq!((model.rowoff, model.coloff), (83, 111));
+        });
+    }
+
+    #[test]
+    fn growing_keeps_the_offsets_while_the_cursor_is_in_view() {
+        with_editor(&long_lines(100, 200), (40, 10), |controller, backend| {
+            controller.model.borrow_mut().set_cursor(150, 90);
+            controller.scroll();
+
+            backend.resize(120, 40);
+            controller.handle_resize();
+            let model = controller.model.borrow();
+            assert_eq!((model.rowoff, model.coloff), (83, 111));
+        });
+    }
+
+    #[test]
+    fn a_terminal_too_small_for_text_still_scrolls_to_the_cursor() {
+        with_editor(&long_lines(10, 20), (80, 24), |controller, backend| {
+            controller.model.borrow_mut().set_cursor(5, 5);
+            backend.resize(1, 1);
+            controller.handle_resize();
+            let model = controller.model.borrow();
+            assert_eq!((model.rowoff, model.coloff), (5, 5));
+        });
+    }
+
+    fn wrap(controller: &TerminalController) {
+        let mut model = controller.model.borrow_mut();
+        model.options.wrap = true;
+        model.options.wrap_at_words = false;
+    }
+
+    fn cursor(controller: &TerminalController) -> (usize, usize) {
+        let model = controller.model.borrow();
+        (model.cx, model.cy)
+    }
+
+    fn click(controller: &mut TerminalController, x: u16, y: u16) {
+        let event = Event::Mouse(MouseEvent::Press(MouseButton::Left, x + 1, y + 1));
+        controller.process_input_keymapped(event).unwrap();
+    }
+
+    #[test]
+    fn rows_wrap_at_the_display_width_of_their_characters() {
+        with_editor("éééééééééééé\n日本語の文章です", (10, 24), |controller, _| {
+            wrap(controller);
+            // Later segments leave room for the two columns of "↪ "
+            assert_eq!(controller.view.row_segments(0), vec![(0, 20), (20, 24)]);
+            assert_eq!(controller.view.row_segments(1), vec![(0, 15), (15, 24)]);
+        });
+    }
+
+    #[test]
+    fn characters_wider_than_the_text_area_get_a_line_each() {
+        with_editor("日本\né", (1, 24), |controller, _| {
+            wrap(controller);
+            assert_eq!(controller.view.row_segments(0), vec![(0, 3), (3, 6)]);
+            assert_eq!(controller.view.row_segments(1), vec![(0, 2)]);
+        });
+        with_editor("éaé", (2, 24), |controller, _| {
+            wrap(controller);
+            controller.model.borrow_mut().options.wrap_indicator = String::from("→");
+            assert_eq!(controller.view.row_segments(0), vec![(0, 3), (3, 5)]);
+        });
+    }
+
+    #[test]
+    fn display_line_motions_keep_the_screen_column() {
+        with_editor("abcdefghijklmnop\n日本語の文章です\nxy", (10, 24), |controller, _| {
+            wrap(controller);
+            controller.model.borrow_mut().set_cursor(3, 0);
+            press(controller, Key::Char('g'));
+            press(controller, Key::Char('j'));
+            assert_eq!(cursor(controller), (11, 0));
+
+            // Column 3 falls on the second half of 本, which moves to its start
+            press(controller, Key::Char('g'));
+            press(controller, Key::Char('j'));
+            assert_eq!(cursor(controller), (3, 1));
+
+            controller.model.borrow_mut().set_cursor(9, 1);
+            press(controller, Key::Char('g'));
+            press(controller, Key::Char('j'));
+            assert_eq!(cursor(controller), (21, 1));
+            press(controller, Key::Char('g'));
+            press(controller, Key::Char('k'));
+            assert_eq!(cursor(controller), (9, 1));
+        });
+    }
+
+    #[test]
+    fn clicks_on_wrapped_rows_land_on_the_character_under_them() {
+        with_editor("abcdefghijklmnop\n日本語の文章です\nabcdefghi日", (10, 24), |controller, _| {
+            wrap(controller);
+            click(controller, 4, 1);
+            assert_eq!(cursor(controller), (12, 0));
+            click(controller, 9, 1);
+            assert_eq!(cursor(controller), (16, 0));
+            click(controller, 5, 2);
+            assert_eq!(cursor(controller), (6, 1));
+            click(controller, 9, 2);
+            assert_eq!(cursor(controller), (12, 1));
+            click(controller, 0, 3);
+            assert_eq!(cursor(controller), (15, 1));
+            // 日 didn't fit on the first line, so a click after i stays on i
+            click(controller, 9, 4);
+            assert_eq!(cursor(controller), (8, 2));
+        });
+    }
+
+    #[test]
+    fn moving_down_scrolls_until_the_cursor_line_shows() {
+        let text = format!("{}\nb\nc\nd", "x".repeat(25));
+        with_editor(&text, (10, 6), |controller, _| {
+            wrap(controller);
+            for _ in 0..3 {
+                press(controller, Key::Char('j'));
+            }
+            assert_eq!(cursor(controller), (0, 3));
+            assert_eq!(controller.model.borrow().rowoff, 1);
+            assert_eq!(controller.view.cursor_screen_position(), (0, 2));
+
+            press(controller, Key::Char('g'));
+            press(controller, Key::Char('g'));
+            assert_eq!(controller.model.borrow().rowoff, 0);
+            press(controller, Key::Char('$'));
+            assert_eq!(controller.view.cursor_screen_position(), (9, 2));
+        });
+    }
+
+    #[test]
+    fn esc_in_normal_mode_closes_the_top_overlay() {
+        with_editor("text", (80, 24), |controller, _| {
+            let lines = vec![String::from("first")];
+            controller.view.open_overlay(Overlay::new(lines, Placement::Center));
+            let lines = vec![String::from("second")];
+            let top = controller.view.open_overlay(Overlay::new(lines, Placement::Center));
+            assert!(matches!(controller.mode, TerminalMode::Normal));
+
+            press(controller, Key::Esc);
+            assert!(controller.view.top_overlay().is_some_and(|id| id != top));
+            press(controller, Key::Esc);
+            assert_eq!(controller.view.top_overlay(), None);
+        });
+    }
+
+    #[test]
+    fn clicking_an_overlay_raises_it_above_the_one_esc_closes() {
+        with_editor("text", (80, 24), |controller, _| {
+            let lines = vec![String::from("a wide overlay underneath"); 5];
+            let below = controller.view.open_overlay(Overlay::new(lines, Placement::Center));
+            let lines = vec![String::from("top")];
+            let above = controller.view.open_overlay(Overlay::new(lines, Placement::Center));
+            // Overlays are placed when they are drawn
+            controller.view.update_overlay(below, |_| {});
+            controller.view.update_overlay(above, |_| {});
+            let area = controller.view.overlay_area(below).unwrap();
+            assert!(!controller.view.overlay_area(above).unwrap().contains(area.x, area.y));
+
+            click(controller, area.x as u16, area.y as u16);
+            assert_eq!(controller.view.top_overlay(), Some(below));
+            press(controller, Key::Esc);
+            assert_eq!(controller.view.top_overlay(), Some(above));
+
+            // A click outside every overlay closes what is left
+            click(controller, 0, 0);
+            assert_eq!(controller.view.top_overlay(), None);
+        });
+    }
+
+    #[test]
+    fn esc_closes_the_quickfix_list() {
+        with_editor("text", (80, 24), |controller, _| {
+            controller.set_quickfix(Quickfix::new(String::from("grep x")));
+            assert!(controller.quickfix_list.is_some());
+
+            press(controller, Key::Esc);
+            assert_eq!(controller.quickfix_list, None);
+            assert_eq!(controller.view.top_overlay(), None);
+        });
+    }
+
+    fn select(controller: &mut TerminalController, start: (usize, usize), end: (usize, usize)) {
+        let mut model = controller.model.borrow_mut();
+        model.anchor_start = start;
+        model.anchor_end = end;
+        model.text_selected = true;
+    }
+
+    fn row(controller: &TerminalController, idx: usize) -> String {
+        controller.model.borrow().get_row_contents(idx).to_string()
+    }
+
+    fn undo(controller: &mut TerminalController) {
+        controller.states.execute_undo(&mut controller.model.borrow_mut());
+    }
+
+    #[test]
+    fn an_opener_typed_over_a_selection_undoes_in_one_step() {
+        with_editor("ab cd", (80, 24), |controller, _| {
+            select(controller, (0, 0), (2, 0));
+            controller.insert_char('(');
+            assert_eq!(row(controller, 0), "() cd");
+            assert_eq!(controller.model.borrow().cx, 1);
+
+            undo(controller);
+            assert_eq!(row(controller, 0), "ab cd");
+        });
+    }
+
+    #[test]
+    fn a_character_typed_over_a_selection_undoes_in_one_step() {
+        with_editor("ab cd", (80, 24), |controller, _| {
+            select(controller, (3, 0), (5, 0));
+            controller.insert_char('x');
+            assert_eq!(row(controller, 0), "ab x");
+
+            undo(controller);
+            assert_eq!(row(controller, 0), "ab cd");
+        });
+    }
+
+    /// Waits for the background job started last and hands its result to
+    /// the controller
+    fn finish_job(controller: &mut TerminalController) {
+        loop {
+            if let EditorEvent::Job(completion) = controller.events.next_event().unwrap() {
+                return completion(controller);
+            }
+        }
+    }
+
+    fn text(controller: &TerminalController) -> String {
+        let model = controller.model.borrow();
+        let rows: Vec<&str> = (0..model.num_rows()).map(|idx| model.get_row_contents(idx).as_str()).collect();
+        rows.join("\n")
+    }
+
+    /// The message in the status bar, with whether it is an error
+    fn status(controller: &TerminalController) -> (bool, String) {
+        match &controller.model.borrow().status_msg {
+            StatusMsg::Error(msg) => (true, msg.clone()),
+            StatusMsg::Normal(msg) | StatusMsg::Warn(msg) => (false, msg.clone()),
+        }
+    }
+
+    #[test]
+    fn filtering_replaces_the_lines_in_one_undo_step() {
+        with_editor("top\nc\na\nb\nend", (80, 24), |controller, _| {
+            controller.filter(Some(Range { first: 2, last: 4 }), "sort");
+            finish_job(controller);
+            assert_eq!(text(controller), "top\na\nb\nc\nend");
+            assert_eq!(status(controller), (false, String::from("3 lines filtered")));
+
+            undo(controller);
+            assert_eq!(text(controller), "top\nc\na\nb\nend");
+        });
+    }
+
+    #[test]
+    fn filtering_the_last_lines_into_nothing_takes_them_out() {
+        with_editor("a\nb\nc", (80, 24), |controller, _| {
+            controller.filter(Some(Range { first: 2, last: 3 }), "true");
+            finish_job(controller);
+            assert_eq!(text(controller), "a");
+
+            undo(controller);
+            assert_eq!(text(controller), "a\nb\nc");
+        });
+    }
+
+    #[test]
+    fn a_filter_that_complains_leaves_the_lines_alone() {
+        with_editor("b\na", (80, 24), |controller, _| {
+            controller.filter(Some(Range { first: 1, last: 2 }), "echo 'bad input' >&2; sort");
+            finish_job(controller);
+            assert_eq!(text(controller), "b\na");
+            assert_eq!(status(controller), (true, String::from("bad input")));
+
+            controller.filter(Some(Range { first: 1, last: 2 }), "exit 1");
+            finish_job(controller);
+            assert_eq!(text(controller), "b\na");
+            assert_eq!(status(controller), (true, String::from("exit 1 failed: exit status: 1")));
+        });
+    }
+
+    #[test]
+    fn lines_edited_while_filtering_are_kept() {
+        with_editor("b\na", (80, 24), |controller, _| {
+            controller.filter(Some(Range { first: 1, last: 2 }), "sort");
+            controller.insert_char('x');
+            finish_job(controller);
+            assert_eq!(text(controller), "xb\na");
+            assert_eq!(status(controller), (true, String::from("Lines changed while filtering")));
+        });
+    }
+
+    #[test]
+    fn read_inserts_below_the_line_in_one_undo_step() {
+        with_editor("a\nd", (80, 24), |controller, _| {
+            controller.read(None, "!printf 'b\\nc\\n'");
+            finish_job(controller);
+            assert_eq!(text(controller), "a\nb\nc\nd");
+            undo(controller);
+            assert_eq!(text(controller), "a\nd");
+
+            controller.read(Some(Range { first: 0, last: 0 }), "!echo top");
+            finish_job(controller);
+            assert_eq!(text(controller), "top\na\nd");
+            controller.read(Some(Range { first: 3, last: 3 }), "!echo end");
+            finish_job(controller);
+            assert_eq!(text(controller), "top\na\nd\nend");
+        });
+    }
+
+    /// Presses the keys for each character of `text`
+    fn type_keys(controller: &mut TerminalController, text: &str) {
+        for c in text.chars() {
+            press(controller, Key::Char(c));
         }
     }
+
+    #[test]
+    fn a_newline_and_its_indentation_undo_together() {
+        with_editor("fn main() {", (80, 24), |controller, _| {
+            type_keys(controller, "A\n");
+            assert_eq!(text(controller), "fn main() {\n\t");
+            assert_eq!(cursor(controller), (1, 1));
+            type_keys(controller, "x\n}");
+            assert_eq!(text(controller), "fn main() {\n\tx\n}");
+            press(controller, Key::Esc);
+
+            // Undoing the brace takes it out along with the level it took away
+            press(controller, Key::Char('u'));
+            assert_eq!(text(controller), "fn main() {\n\tx\n\t");
+            press(controller, Key::Char('u'));
+            assert_eq!(text(controller), "fn main() {\n\tx");
+        });
+    }
+
+    #[test]
+    fn a_newline_between_braces_opens_an_indented_row() {
+        with_editor("    if x {}", (80, 24), |controller, _| {
+            controller.model.borrow_mut().options.expandtab = true;
+            controller.model.borrow_mut().set_cursor(10, 0);
+            controller.insert_newline();
+            assert_eq!(text(controller), "    if x {\n        \n    }");
+            assert_eq!(cursor(controller), (8, 1));
+
+            undo(controller);
+            assert_eq!(text(controller), "    if x {}");
+        });
+    }
+
+    #[test]
+    fn shifting_rows_moves_them_a_level() {
+        with_editor("a\n\n\tb", (80, 24), |controller, _| {
+            type_keys(controller, ">G");
+            assert_eq!(text(controller), "\ta\n\n\t\tb");
+            type_keys(controller, "G<<");
+            assert_eq!(text(controller), "\ta\n\n\tb");
+            type_keys(controller, "<<");
+            assert_eq!(text(controller), "\ta\n\nb");
+
+            type_keys(controller, "u");
+            assert_eq!(text(controller), "\ta\n\n\tb");
+            type_keys(controller, "u");
+            assert_eq!(text(controller), "\ta\n\n\t\tb");
+            type_keys(controller, "u");
+            assert_eq!(text(controller), "a\n\n\tb");
+        });
+    }
+
+    #[test]
+    fn reindenting_follows_the_braces() {
+        with_editor("fn f() {\n  if x {\ny();\n     }\n  \n}", (80, 24), |controller, _| {
+            type_keys(controller, "=G");
+            assert_eq!(text(controller), "fn f() {\n\tif x {\n\t\ty();\n\t}\n\n}");
+            type_keys(controller, "u");
+            assert_eq!(text(controller), "fn f() {\n  if x {\ny();\n     }\n  \n}");
+        });
+    }
+
+    #[test]
+    fn commenting_rows_undoes_in_one_step() {
+        with_editor("int x;\n\nint y;\nint z;", (80, 24), |controller, _| {
+            assert!(controller.model.borrow_mut().set_filetype("c"));
+            type_keys(controller, "gcj");
+            type_keys(controller, "Ggcc");
+            assert_eq!(text(controller), "// int x;\n\nint y;\n// int z;");
+            select(controller, (0, 0), (6, 3));
+            // gc also starts gcc, so it only runs once the wait for more keys is over
+            type_keys(controller, "gc");
+            controller.process_timer(Timer::KeySequence).unwrap();
+            assert_eq!(text(controller), "// // int x;\n\n// int y;\n// // int z;");
+
+            type_keys(controller, "u");
+            assert_eq!(text(controller), "// int x;\n\nint y;\n// int z;");
+            type_keys(controller, "uu");
+            assert_eq!(text(controller), "int x;\n\nint y;\nint z;");
+        });
+    }
+
+    fn map(controller: &mut TerminalController, from: &str, to: &str, remap: bool) {
+        let (from, keys) = (keymap::parse_keys(from, &[]), keymap::parse_keys(to, &[]));
+        let binding = Binding::Keys { keys: keys.unwrap(), remap };
+        controller.keymaps.bind(KeymapMode::Normal, from.unwrap(), binding);
+    }
+
+    fn cursor_row(controller: &TerminalController) -> usize {
+        controller.model.borrow().cy
+    }
+
+    #[test]
+    fn mappings_expand_through_other_mappings() {
+        with_editor("a\nb\nc", (80, 24), |controller, _| {
+            controller.model.borrow_mut().set_cursor(0, 1);
+            map(controller, "x", "j", true);
+            map(controller, "j", "k", false);
+            press(controller, Key::Char('x'));
+            assert_eq!(cursor_row(controller), 0);
+        });
+    }
+
+    #[test]
+    fn noremap_mappings_skip_other_mappings() {
+        with_editor("a\nb\nc", (80, 24), |controller, _| {
+            controller.model.borrow_mut().set_cursor(0, 1);
+            map(controller, "x", "j", false);
+            map(controller, "j", "k", false);
+            press(controller, Key::Char('x'));
+            assert_eq!(cursor_row(controller), 2);
+        });
+    }
+
+    #[test]
+    fn endlessly_recursive_mappings_are_stopped() {
+        with_editor("text", (80, 24), |controller, _| {
+            map(controller, "x", "y", true);
+            map(controller, "y", "x", true);
+            press(controller, Key::Char('x'));
+            assert!(controller.pending_keys.is_empty());
+            let model = controller.model.borrow();
+            assert!(matches!(&model.status_msg, StatusMsg::Error(msg) if msg == "Recursive mapping"));
+        });
+    }
+
+    #[test]
+    fn a_prefix_runs_its_binding_once_the_timeout_passes() {
+        with_editor("a\nb\nc", (80, 24), |controller, _| {
+            controller.model.borrow_mut().set_cursor(0, 2);
+            map(controller, "k", "k", false);
+            map(controller, "kk", "gg", false);
+            press(controller, Key::Char('k'));
+            assert_eq!(cursor_row(controller), 2);
+            assert_eq!(controller.pending_keys.len(), 1);
+
+            controller.process_timer(Timer::KeySequence).unwrap();
+            assert_eq!(cursor_row(controller), 1);
+            assert!(controller.pending_keys.is_empty());
+        });
+    }
 }
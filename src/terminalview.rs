@@ -1,13 +1,18 @@
-use crate::model::{Model, StatusMsg};
+use crate::backend::Backend;
+use crate::model::{BracketPair, Model, SignKind, StatusMsg};
+use crate::options::LineNumbers;
+use crate::overlay::{Overlay, OverlayId, Placement, Rect};
+use crate::syntax::syntaxengine::HLMode;
+use crate::theme::{ColorDepth, Style, Theme, ThemeColor};
 use crate::{View, GINKGO_VERSION};
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::io::{stdout, Write};
-#[allow(unused_imports)]
 use std::rc::Rc;
 use termion::color;
-use termion::raw::{IntoRawMode, RawTerminal};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
+#[derive(Clone, Copy)]
 struct TerminalSize {
     screenrows: usize,
     screencols: usize,
@@ -15,37 +20,124 @@ struct TerminalSize {
 
 pub struct TerminalView {
     model: Rc<RefCell<Model>>,
-    _stdout: RawTerminal<std::io::Stdout>,
+    backend: Rc<dyn Backend>,
+    // Cached terminal size. Only refreshed by update_size so every part of a
+    // frame is laid out against the same dimensions
+    size: Cell<TerminalSize>,
+    theme: RefCell<Theme>,
+    // Colors the theme asks for are brought down to what the terminal can show
+    color_depth: ColorDepth,
+    // The bracket at the cursor and its partner, found once per frame
+    brackets: Cell<Option<BracketPair>>,
+    // Floating windows drawn over the text, bottom one first, each with the
+    // part of the screen it was last drawn to
+    overlays: RefCell<Vec<(OverlayId, Overlay, Rect)>>,
+    next_overlay: Cell<OverlayId>,
 }
 
 impl TerminalView {
-    pub fn new(model: Rc<RefCell<Model>>) -> TerminalView {
+    pub fn new(model: Rc<RefCell<Model>>, backend: Rc<dyn Backend>) -> TerminalView {
         // Initialize cursor to a block
         print!("{}", termion::cursor::SteadyBlock);
-        TerminalView {
+        let color_depth = backend.color_depth();
+        let view = TerminalView {
             model,
-            _stdout: stdout().into_raw_mode().unwrap(),
-        }
+            backend,
+            size: Cell::new(TerminalSize {
+                screenrows: 0,
+                screencols: 0,
+            }),
+            theme: RefCell::new(Theme::default()),
+            color_depth,
+            brackets: Cell::new(None),
+            overlays: RefCell::new(vec![]),
+            next_overlay: Cell::new(0),
+        };
+        view.update_size();
+        view
     }
 
     pub fn clear_widow() {
         print!("{}", termion::clear::All);
     }
 
-    fn get_window_size() -> TerminalSize {
-        let size = termion::terminal_size().unwrap();
-        TerminalSize {
-            screencols: size.0 as usize,
-            screenrows: size.1 as usize,
+    /// Re-queries the backend for the terminal size. Called at startup and
+    /// whenever the terminal reports that it was resized.
+    pub fn update_size(&self) {
+        let (screencols, screenrows) = self.backend.size();
+        self.size.set(TerminalSize {
+            screenrows,
+            screencols,
+        });
+    }
+
+    /// Switches to another color theme, used from the next redraw on
+    pub fn set_theme(&self, theme: Theme) {
+        self.theme.replace(theme);
+    }
+
+    pub fn theme_name(&self) -> String {
+        self.theme.borrow().name.clone()
+    }
+
+    fn fg(&self, color: ThemeColor) -> String {
+        color.fg(self.color_depth)
+    }
+
+    fn bg(&self, color: ThemeColor) -> String {
+        color.bg(self.color_depth)
+    }
+
+    /// Escape sequences switching to both colors of a style
+    fn style(&self, style: Style) -> String {
+        format!("{}{}", self.fg(style.fg), self.bg(style.bg))
+    }
+
+    fn reset_style(&self) -> String {
+        format!("{}{}", color::Fg(color::Reset), color::Bg(color::Reset))
+    }
+
+    fn get_window_size(&self) -> TerminalSize {
+        self.size.get()
+    }
+
+    /// Number of rows available for text, which excludes the status and message bars
+    pub fn get_screen_rows(&self) -> usize {
+        self.get_window_size().screenrows.saturating_sub(2)
+    }
+
+    pub fn get_screen_cols(&self) -> usize {
+        self.get_window_size().screencols
+    }
+
+    /// Number of columns available for text, which excludes the gutter
+    pub fn get_text_cols(&self) -> usize {
+        self.get_screen_cols().saturating_sub(self.gutter_width())
+    }
+
+    fn sign_column_width(&self) -> usize {
+        let model = self.model.borrow();
+        if model.options.sign_column || !model.signs.is_empty() {
+            2
+        } else {
+            0
         }
     }
 
-    pub fn get_screen_rows() -> usize {
-        TerminalView::get_window_size().screenrows - 2
+    /// Width of the line number column, including its trailing space. It
+    /// grows with the number of rows so every number fits.
+    fn number_column_width(&self) -> usize {
+        let model = self.model.borrow();
+        if model.options.line_numbers == LineNumbers::Off {
+            return 0;
+        }
+        let digits = model.num_rows().max(1).to_string().len();
+        digits.max(3) + 1
     }
 
-    pub fn get_screen_cols() -> usize {
-        TerminalView::get_window_size().screencols
+    /// Total width of the gutter drawn to the left of the text
+    pub fn gutter_width(&self) -> usize {
+        self.sign_column_width() + self.number_column_width()
     }
 
     /// This is the main public function for redrawing only the screen rows
@@ -53,7 +145,7 @@ impl TerminalView {
     /// but it will redraw the on-screen cursor based on its current location
     pub fn refresh_rows(&self) {
         print!("{}", termion::cursor::Goto(1, 1));
-        let size = TerminalView::get_window_size();
+        let size = self.get_window_size();
         let screenrows = size.screenrows;
         let screencols = size.screencols;
         self.draw_rows(screenrows, screencols);
@@ -61,15 +153,391 @@ impl TerminalView {
         stdout().flush().unwrap();
     }
 
+    /// Splits a row into the byte ranges that are drawn on separate screen lines.
+    /// Without wrapping this is always the whole row as a single segment.
+    pub fn row_segments(&self, row_idx: usize) -> Vec<(usize, usize)> {
+        let model = self.model.borrow();
+        let len = model.row_len(row_idx);
+        if !model.options.wrap || row_idx >= model.num_rows() {
+            return vec![(0, len)];
+        }
+
+        let contents = model.get_row_contents(row_idx);
+        let screencols = self.get_text_cols().max(1);
+        let mut segments = vec![];
+        let mut start = 0;
+        loop {
+            let width = screencols
+                .saturating_sub(self.segment_prefix_width(segments.len()))
+                .max(1);
+            let mut end = start + fitting_len(&contents[start..], width);
+            if end == len {
+                segments.push((start, len));
+                return segments;
+            }
+
+            // Prefer to break just after the last space that still fits
+            if model.options.wrap_at_words {
+                if let Some(space) = contents[start..end].rfind(' ') {
+                    if space > 0 {
+                        end = start + space + 1;
+                    }
+                }
+            }
+            segments.push((start, end));
+            start = end;
+        }
+    }
+
+    /// Width of the wrap indicator drawn before the segment with index `seg_idx`
+    fn segment_prefix_width(&self, seg_idx: usize) -> usize {
+        if seg_idx == 0 {
+            0
+        } else {
+            self.model.borrow().options.wrap_indicator.width()
+        }
+    }
+
+    /// Screen column byte `x` of a row is drawn at within segment `seg_idx`,
+    /// counting the wrap indicator before it
+    pub fn column_in_segment(
+        &self,
+        row_idx: usize,
+        segments: &[(usize, usize)],
+        seg_idx: usize,
+        x: usize,
+    ) -> usize {
+        let model = self.model.borrow();
+        let contents = model.get_row_contents(row_idx);
+        let start = segments[seg_idx].0;
+        let mut x = x.clamp(start, contents.len());
+        while !contents.is_char_boundary(x) {
+            x -= 1;
+        }
+        contents[start..x].width() + self.segment_prefix_width(seg_idx)
+    }
+
+    /// Byte of a row drawn at screen column `col` of segment `seg_idx`. Past
+    /// the end of the segment this is its last character, or the end of the
+    /// row for the last segment, as the end of the others is the start of
+    /// the next one.
+    pub fn byte_in_segment(
+        &self,
+        row_idx: usize,
+        segments: &[(usize, usize)],
+        seg_idx: usize,
+        col: usize,
+    ) -> usize {
+        let model = self.model.borrow();
+        let (start, end) = segments[seg_idx];
+        let text = &model.get_row_contents(row_idx)[start..end];
+        let col = col.saturating_sub(self.segment_prefix_width(seg_idx));
+        let mut width = 0;
+        for (idx, c) in text.char_indices() {
+            width += c.width().unwrap_or(0);
+            if width > col {
+                return start + idx;
+            }
+        }
+        if seg_idx + 1 == segments.len() {
+            end
+        } else {
+            start + text.char_indices().last().map_or(0, |(idx, _)| idx)
+        }
+    }
+
+    /// Index of the segment containing byte offset `cx`. An offset on the
+    /// boundary between two segments belongs to the later one.
+    pub fn segment_of(segments: &[(usize, usize)], cx: usize) -> usize {
+        segments.iter().rposition(|seg| seg.0 <= cx).unwrap_or(0)
+    }
+
+    /// Returns the cursor's position on screen, relative to the top-left of the text area
+    pub fn cursor_screen_position(&self) -> (usize, usize) {
+        let (cx, cy) = {
+            let model = self.model.borrow();
+            (model.cx, model.cy)
+        };
+        self.text_screen_position(cx, cy)
+    }
+
+    /// Returns the screen position of byte `x` of row `y`, relative to the
+    /// top-left of the text area. The row is assumed to be scrolled into view.
+    pub fn text_screen_position(&self, x: usize, y: usize) -> (usize, usize) {
+        let model = self.model.borrow();
+        if !model.options.wrap {
+            return (x.saturating_sub(model.coloff), y.saturating_sub(model.rowoff));
+        }
+
+        let mut line = 0;
+        for row_idx in model.rowoff..y.min(model.num_rows()) {
+            line += self.row_segments(row_idx).len();
+        }
+        if y >= model.num_rows() {
+            return (0, line);
+        }
+        let segments = self.row_segments(y);
+        let seg_idx = TerminalView::segment_of(&segments, x);
+        (self.column_in_segment(y, &segments, seg_idx, x), line + seg_idx)
+    }
+
+    /// Puts an overlay on top of the others, to be drawn from the next
+    /// redraw on until it is closed
+    pub fn open_overlay(&self, overlay: Overlay) -> OverlayId {
+        let id = self.next_overlay.get();
+        self.next_overlay.set(id + 1);
+        self.overlays
+            .borrow_mut()
+            .push((id, overlay, Rect::default()));
+        id
+    }
+
+    /// Changes an open overlay. Returns false if it was closed already.
+    pub fn update_overlay(&self, id: OverlayId, update: impl FnOnce(&mut Overlay)) -> bool {
+        let mut overlays = self.overlays.borrow_mut();
+        match overlays.iter_mut().find(|(other, _, _)| *other == id) {
+            Some((_, overlay, rect)) => {
+                update(overlay);
+                // Bring a newly selected line into view of the new size
+                *rect = self.overlay_rect(overlay).unwrap_or_default();
+                overlay.scroll_to_selected(rect.height.saturating_sub(2 * overlay.border as usize));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn close_overlay(&self, id: OverlayId) {
+        self.overlays.borrow_mut().retain(|(other, _, _)| *other != id);
+    }
+
+    /// The overlay drawn on top of the others
+    pub fn top_overlay(&self) -> Option<OverlayId> {
+        self.overlays.borrow().last().map(|(id, _, _)| *id)
+    }
+
+    /// Moves an overlay on top of the others
+    pub fn raise_overlay(&self, id: OverlayId) {
+        let mut overlays = self.overlays.borrow_mut();
+        if let Some(idx) = overlays.iter().position(|(other, _, _)| *other == id) {
+            let overlay = overlays.remove(idx);
+            overlays.push(overlay);
+        }
+    }
+
+    /// The topmost overlay drawn at a screen position, counted from 0, along
+    /// with the index of the line of its contents there, if any
+    pub fn overlay_at(&self, x: usize, y: usize) -> Option<(OverlayId, Option<usize>)> {
+        let overlays = self.overlays.borrow();
+        let (id, overlay, rect) = overlays.iter().rev().find(|(_, _, rect)| rect.contains(x, y))?;
+        let border = overlay.border as usize;
+        let line = (y - rect.y)
+            .checked_sub(border)
+            .filter(|line| *line + 2 * border < rect.height)
+            .map(|line| line + overlay.scroll)
+            .filter(|line| *line < overlay.lines.len());
+        Some((*id, line))
+    }
+
+    /// The part of the screen an overlay takes up
+    pub fn overlay_area(&self, id: OverlayId) -> Option<Rect> {
+        let overlays = self.overlays.borrow();
+        overlays.iter().find(|(other, _, _)| *other == id).map(|(_, _, rect)| *rect)
+    }
+
+    /// Scrolls an overlay by `delta` lines within the height it was drawn at
+    pub fn scroll_overlay(&self, id: OverlayId, delta: isize) {
+        let mut overlays = self.overlays.borrow_mut();
+        if let Some((_, overlay, rect)) = overlays.iter_mut().find(|(other, _, _)| *other == id) {
+            let height = rect.height.saturating_sub(2 * overlay.border as usize);
+            overlay.scroll_by(delta, height);
+        }
+    }
+
+    /// Works out where an overlay goes on screen and how big it is, keeping
+    /// it within the text area. None when even its border doesn't fit.
+    fn overlay_rect(&self, overlay: &Overlay) -> Option<Rect> {
+        let screencols = self.get_screen_cols();
+        let textrows = self.get_screen_rows();
+        let border = 2 * overlay.border as usize;
+        let longest = overlay
+            .lines
+            .iter()
+            .chain(&overlay.title)
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        let width = (overlay.width.unwrap_or(longest) + border).min(screencols);
+        let height = (overlay.height.unwrap_or(overlay.lines.len()) + border).min(textrows);
+        if width < border || height < border || width == 0 || height == 0 {
+            return None;
+        }
+
+        let (x, y) = match overlay.placement {
+            Placement::Cursor | Placement::Text(..) => {
+                let (x, y) = match overlay.placement {
+                    Placement::Text(x, y) => self.text_screen_position(x, y),
+                    _ => self.cursor_screen_position(),
+                };
+                // The contents line up with the position, not the border
+                let x = (self.gutter_width() + x).saturating_sub(border / 2);
+                let y = match y + 1 + height <= textrows {
+                    true => y + 1,
+                    false => y.saturating_sub(height),
+                };
+                (x, y)
+            }
+            Placement::Center => ((screencols - width) / 2, (textrows - height) / 2),
+            Placement::Screen(x, y) => (x, y),
+        };
+        Some(Rect {
+            x: x.min(screencols - width),
+            y: y.min(textrows - height),
+            width,
+            height,
+        })
+    }
+
+    /// Draws the overlays over whatever is on screen, bottom one first. One
+    /// that doesn't fit is left out, and takes up no room until it does.
+    fn draw_overlays(&self) {
+        let mut overlays = self.overlays.borrow_mut();
+        for (_, overlay, rect) in overlays.iter_mut() {
+            *rect = self.overlay_rect(overlay).unwrap_or_default();
+            if rect.width > 0 {
+                self.draw_overlay(overlay, *rect);
+            }
+        }
+    }
+
+    fn draw_overlay(&self, overlay: &mut Overlay, rect: Rect) {
+        let theme = self.theme.borrow();
+        let border = overlay.border as usize;
+        let inner = rect.width.saturating_sub(2 * border);
+        let height = rect.height.saturating_sub(2 * border);
+        // Lines taken away may leave the view scrolled past the end
+        overlay.scroll_by(0, height);
+        let goto =
+            |line: usize| termion::cursor::Goto((rect.x + 1) as u16, (rect.y + line + 1) as u16);
+
+        if border == 1 {
+            let title = overlay.title.as_deref().unwrap_or("");
+            let title: String = title.chars().take(inner).collect();
+            let rule = "─".repeat(inner - title.chars().count());
+            print!("{}{}┌{}{}┐", goto(0), self.style(theme.popup_border), title, rule);
+            print!("{}└{}┘{}", goto(rect.height - 1), "─".repeat(inner), self.reset_style());
+        }
+        for line in 0..height {
+            let idx = overlay.scroll + line;
+            let text = overlay.lines.get(idx).map_or("", String::as_str);
+            let style = match overlay.selected == Some(idx) {
+                true => theme.popup_selected,
+                false => theme.popup,
+            };
+            let highlights = overlay.highlights.get(idx).map_or(&[][..], Vec::as_slice);
+            print!("{}", goto(line + border));
+            if border == 1 {
+                print!("{}│", self.style(theme.popup_border));
+            }
+            print!("{}", self.style(style));
+            let mut width = 0;
+            for (col, c) in text.chars().take(inner).enumerate() {
+                // Tabs and other control characters would throw the border out of line
+                let c = if c.is_control() { ' ' } else { c };
+                match highlights.contains(&col) {
+                    true => print!("{}{}{}", self.fg(theme.popup_match.fg), c, self.style(style)),
+                    false => print!("{}", c),
+                }
+                width += 1;
+            }
+            print!("{}", " ".repeat(inner - width));
+            if border == 1 {
+                print!("{}│", self.style(theme.popup_border));
+            }
+            print!("{}", self.reset_style());
+        }
+    }
+
+    /// Draws the gutter for one screen line. Only the first screen line of a
+    /// row gets its sign and number; continuation lines are left blank.
+    fn draw_gutter(&self, row_idx: usize, first_line: bool) {
+        let model = self.model.borrow();
+        let theme = self.theme.borrow();
+
+        if self.sign_column_width() > 0 {
+            match model.signs.get(&row_idx) {
+                Some(sign) if first_line => {
+                    let style = match sign.kind {
+                        SignKind::Error => theme.sign_error,
+                        SignKind::Warning => theme.sign_warning,
+                        SignKind::Info => theme.sign_info,
+                    };
+                    print!("{}{} {}", self.style(style), sign.symbol, self.reset_style());
+                }
+                _ => print!("  "),
+            }
+        }
+
+        let width = self.number_column_width();
+        if width == 0 {
+            return;
+        }
+        if !first_line {
+            print!("{}{}{}", self.style(theme.gutter), " ".repeat(width), self.reset_style());
+            return;
+        }
+        let number = match model.options.line_numbers {
+            LineNumbers::Relative => row_idx.abs_diff(model.cy),
+            LineNumbers::Hybrid if row_idx != model.cy => row_idx.abs_diff(model.cy),
+            _ => row_idx + 1,
+        };
+        if row_idx == model.cy {
+            print!("{}", self.style(theme.gutter_current));
+        } else {
+            print!("{}", self.style(theme.gutter));
+        }
+        print!("{:>w$} {}", number, self.reset_style(), w = width - 1);
+    }
+
     fn draw_rows(&self, screenrows: usize, screencols: usize) {
         let model = self.model.borrow();
+        let textrows = screenrows.saturating_sub(2);
+        let textcols = self.get_text_cols();
+        self.brackets.set(model.bracket_pair_at_cursor());
 
-        for r in 0..(screenrows - 2) {
-            let row_idx = r + model.rowoff;
+        let mut row_idx = model.rowoff;
+        let mut r = 0;
+        while r < textrows {
             print!("{}", termion::clear::CurrentLine);
             if row_idx < model.num_rows() {
-                // Print a standard row
-                self.draw_row(row_idx, screencols);
+                // Print a standard row, which may take up several lines when wrapped
+                let segments = if model.options.wrap {
+                    self.row_segments(row_idx)
+                } else {
+                    let len = model.row_len(row_idx);
+                    vec![(model.coloff.min(len), (model.coloff + textcols).min(len))]
+                };
+                for (seg_idx, seg) in segments.iter().enumerate() {
+                    if r >= textrows {
+                        break;
+                    }
+                    if seg_idx > 0 {
+                        print!("{}", termion::clear::CurrentLine);
+                    }
+                    self.draw_gutter(row_idx, seg_idx == 0);
+                    if seg_idx > 0 {
+                        print!(
+                            "{}{}{}",
+                            self.style(self.theme.borrow().wrap_indicator),
+                            model.options.wrap_indicator,
+                            self.reset_style()
+                        );
+                    }
+                    self.draw_row(row_idx, seg.0, seg.1);
+                    r += 1;
+                }
+                row_idx += 1;
+                continue;
             } else if model.num_rows() == 0 && r == screenrows / 3 {
                 // Print a welcome message
                 self.draw_welcome(screencols);
@@ -77,75 +545,148 @@ impl TerminalView {
                 // Print a row placeholder
                 println!("~\r");
             }
+            r += 1;
         }
     }
 
-    fn draw_row(&self, row_idx: usize, screencols: usize) {
+    /// Draws the bytes `start..end` of a row with syntax highlighting, and
+    /// with a background behind any selected text or search match
+    fn draw_row(&self, row_idx: usize, start: usize, end: usize) {
         let model = self.model.borrow();
-        let mut contents = &model.get_row_contents(row_idx)[..];
+        let theme = self.theme.borrow();
+        let contents = &model.get_row_contents(row_idx)[..];
+        let spans = model.get_row_highlight(row_idx);
+        let selection = self.selection_in_row(row_idx, start, end);
+        let matches = self.search_matches_in_row(row_idx, start, end);
+        let cursorline = model.options.cursorline && row_idx == model.cy;
+        // Bytes of the row holding the bracket at the cursor or its partner
+        let brackets: Vec<usize> = self
+            .brackets
+            .get()
+            .iter()
+            .flat_map(|(bracket, partner)| [*bracket, *partner])
+            .filter(|(x, y)| *y == row_idx && start <= *x && *x < end)
+            .map(|(x, _)| x)
+            .collect();
+        // Where text past the line length limit starts, if it does
+        let overlength = match model.options.max_line_length {
+            0 => None,
+            max => contents.char_indices().nth(max).map(|(idx, _)| idx),
+        };
 
-        // Shrink contents slice down to fit in our screen
-        if contents.len() > screencols {
-            contents = &contents[..screencols];
+        // Split the range wherever the highlight, the selection, a match, a
+        // bracket or the line length limit changes
+        let mut bounds = vec![start, end];
+        for x in brackets.iter() {
+            bounds.push(*x);
+            bounds.push(*x + 1);
+        }
+        if let Some(limit) = overlength {
+            bounds.push(limit.clamp(start, end));
         }
+        for span in spans.iter() {
+            bounds.push(span.start.clamp(start, end));
+            bounds.push(span.end.clamp(start, end));
+        }
+        if let Some((sel_start, sel_end)) = selection {
+            bounds.push(sel_start);
+            bounds.push(sel_end);
+        }
+        for (match_start, match_end) in matches.iter() {
+            bounds.push(*match_start);
+            bounds.push(*match_end);
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
 
-        if model.text_selected && self.draw_selection(contents, row_idx) {
-            return;
+        let base_bg = if cursorline {
+            theme.cursorline.bg
         } else {
-            println!("{}\r", contents);
+            ThemeColor::Reset
+        };
+        let mut line = self.bg(base_bg);
+        let mut span_idx = 0;
+        for pair in bounds.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            while span_idx < spans.len() && spans[span_idx].end <= a {
+                span_idx += 1;
+            }
+            let span = spans.get(span_idx).filter(|span| span.start <= a);
+            let selected = matches!(selection, Some((s, e)) if s <= a && b <= e);
+            let matched = matches.iter().any(|(s, e)| *s <= a && b <= *e);
+
+            // The selection wins over a search match, then come matching
+            // brackets, the line length mark and finally highlighting
+            let overlay = if selected {
+                Some(theme.selection)
+            } else if matched {
+                Some(theme.search_match)
+            } else if brackets.contains(&a) {
+                Some(theme.matching_bracket)
+            } else if overlength.is_some_and(|limit| a >= limit) {
+                Some(theme.overlength)
+            } else {
+                None
+            };
+            let fg = match (overlay, span.and_then(|span| span.rgb)) {
+                (Some(style), _) if style.fg != ThemeColor::Reset => self.fg(style.fg),
+                (_, Some((r, g, b))) => format!("{}", color::Fg(color::Rgb(r, g, b))),
+                _ => self.fg(theme.syntax_color(span.map_or(HLMode::Normal, |span| span.mode))),
+            };
+            line.push_str(&fg);
+            if let Some(style) = overlay {
+                line.push_str(&self.bg(style.bg));
+            }
+            line.push_str(&contents[a..b]);
+            if overlay.is_some() {
+                line.push_str(&self.bg(base_bg));
+            }
         }
+        if cursorline {
+            // Carry the background to the edge of the screen
+            line.push_str(&format!("{}", termion::clear::UntilNewline));
+        }
+        println!("{}{}\r", line, self.reset_style());
+    }
+
+    /// Returns the parts of `start..end` in the given row that match the
+    /// current search term
+    fn search_matches_in_row(&self, row_idx: usize, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let model = self.model.borrow();
+        let term = match &model.search_term {
+            Some(term) if !term.is_empty() => term,
+            _ => return vec![],
+        };
+        model
+            .get_row_contents(row_idx)
+            .match_indices(term.as_str())
+            .map(|(idx, m)| (idx.clamp(start, end), (idx + m.len()).clamp(start, end)))
+            .filter(|(s, e)| s < e)
+            .collect()
     }
 
-    /// Returns true if we drew a selection
-    fn draw_selection(&self, render: &str, row_idx: usize) -> bool {
+    /// Returns the part of `start..end` in the given row that is selected, if any
+    fn selection_in_row(&self, row_idx: usize, start: usize, end: usize) -> Option<(usize, usize)> {
         let model = self.model.borrow();
+        if !model.text_selected {
+            return None;
+        }
         let (anchor_start, anchor_end) = model.get_anchors();
 
         if row_idx < anchor_start.1 || row_idx > anchor_end.1 {
-            return false;
-        }
-
-        // Selection on same line
-        if row_idx == anchor_start.1 && row_idx == anchor_end.1 {
-            println!(
-                "{}{}{}{}{}\r",
-                &render[..(anchor_start.0)],
-                color::Bg(color::LightBlue),
-                &render[(anchor_start.0)..(anchor_end.0)],
-                color::Bg(color::Reset),
-                &render[(anchor_end.0)..]
-            );
-        }
-        // Draw start of a selection
-        else if row_idx == anchor_start.1 {
-            println!(
-                "{}{}{}{}\r",
-                &render[..(anchor_start.0)],
-                color::Bg(color::LightBlue),
-                &render[(anchor_start.0)..],
-                color::Bg(color::Reset)
-            );
-        }
-        // Draw end of a selection
-        else if row_idx == anchor_end.1 {
-            println!(
-                "{}{}{}{}\r",
-                color::Bg(color::LightBlue),
-                &render[..(anchor_end.0)],
-                color::Bg(color::Reset),
-                &render[(anchor_end.0)..]
-            );
-        }
-        // Draw full line
-        else {
-            println!(
-                "{}{}{}\r",
-                color::Bg(color::LightBlue),
-                render,
-                color::Bg(color::Reset),
-            );
-        }
-        true
+            return None;
+        }
+
+        // Selections that start or end on another row cover this row up to its edge
+        let sel_start = if row_idx == anchor_start.1 { anchor_start.0 } else { 0 };
+        let sel_end = if row_idx == anchor_end.1 {
+            anchor_end.0
+        } else {
+            model.row_len(row_idx)
+        };
+        let sel_start = sel_start.clamp(start, end);
+        let sel_end = sel_end.clamp(sel_start, end);
+        Some((sel_start, sel_end))
     }
 
     fn draw_welcome(&self, screencols: usize) {
@@ -178,74 +719,51 @@ impl TerminalView {
             }
         };
 
-        let extension = {
-            if model.ext.is_empty() {
-                String::from("Plaintext")
-            } else {
-                model.ext.clone()
-            }
+        let language = model.syntax_name().unwrap_or("Plaintext");
+        let indentation = match model.options.expandtab {
+            true => format!("Spaces: {}", model.options.tab_width),
+            false => format!("Tabs: {}", model.options.tab_width),
         };
 
         let lines = model.num_rows();
 
         let lstatus = format!("{} - {} lines {}", filename, lines, modified);
         let rstatus = format!(
-            "<{}> {} | {}/{} ",
+            "<{}> {} | {} | {}/{} ",
             model.mode,
-            extension,
+            language,
+            indentation,
             model.cy + 1,
             lines
         );
         let padding = screencols.saturating_sub(lstatus.len() + rstatus.len());
         print!("{}", termion::clear::CurrentLine);
         println!(
-            "{}{}{}{}{}{}\r",
-            color::Bg(color::White),
-            color::Fg(color::Black),
+            "{}{}{}{}{}\r",
+            self.style(self.theme.borrow().status_bar),
             lstatus,
             " ".repeat(padding),
             rstatus,
-            color::Bg(color::Reset)
+            self.reset_style()
         );
     }
 
     fn draw_message_bar(&self, screencols: usize) {
         let model = self.model.borrow();
-        let mut message = match &model.status_msg {
-            StatusMsg::Normal(msg) => {
-                format!(
-                    "{}{}{}",
-                    color::Fg(color::White),
-                    msg,
-                    color::Fg(color::Reset)
-                )
-            }
-            StatusMsg::Warn(msg) => {
-                format!(
-                    "{}{}{}",
-                    color::Fg(color::Yellow),
-                    msg,
-                    color::Fg(color::Reset)
-                )
-            }
-            StatusMsg::Error(msg) => {
-                format!(
-                    "{}{}{}",
-                    color::Fg(color::Red),
-                    msg,
-                    color::Fg(color::Reset)
-                )
-            }
+        let theme = self.theme.borrow();
+        let (style, msg) = match &model.status_msg {
+            StatusMsg::Normal(msg) => (theme.message, msg),
+            StatusMsg::Warn(msg) => (theme.warning, msg),
+            StatusMsg::Error(msg) => (theme.error, msg),
         };
-        message.truncate(screencols);
+        let msg: String = msg.chars().take(screencols).collect();
         print!("{}", termion::clear::CurrentLine);
-        print!("{}", message);
+        print!("{}{}{}", self.style(style), msg, self.reset_style());
     }
 
     fn draw_cursor(&self) {
-        let model = self.model.borrow();
-        let y = model.cy.saturating_sub(model.rowoff);
-        let x = model.cx.saturating_sub(model.coloff);
+        let (x, y) = self.cursor_screen_position();
+        let x = x + self.gutter_width();
 
         print!("{}", termion::cursor::Hide);
         print!("{}", termion::cursor::Goto((x + 1) as u16, (y + 1) as u16));
@@ -257,14 +775,14 @@ impl TerminalView {
     }
 
     pub fn draw_prompt(&self, prompt: &str, msg: &str) {
-        let size = TerminalView::get_window_size();
-        let screencols = size.screencols;
-        print!("{}", termion::cursor::Goto(1, screencols as u16));
+        let size = self.get_window_size();
+        let screenrows = size.screenrows;
+        print!("{}", termion::cursor::Goto(1, screenrows as u16));
         print!("{}", termion::clear::CurrentLine);
         print!("{} {}", prompt, msg);
         print!(
             "{}",
-            termion::cursor::Goto((prompt.len() + msg.len() + 2) as u16, screencols as u16)
+            termion::cursor::Goto((prompt.len() + msg.len() + 2) as u16, screenrows as u16)
         );
         stdout().flush().unwrap();
     }
@@ -273,13 +791,68 @@ impl TerminalView {
 impl View for TerminalView {
     fn draw(&self) {
         print!("{}", termion::cursor::Goto(1, 1));
-        let size = TerminalView::get_window_size();
+        let size = self.get_window_size();
         let screenrows = size.screenrows;
         let screencols = size.screencols;
         self.draw_rows(screenrows, screencols);
         self.draw_status_bar(screencols);
         self.draw_message_bar(screencols);
+        self.draw_overlays();
         self.draw_cursor();
+        // An open prompt replaces the message bar and takes the cursor with it
+        if let Some((prompt, msg)) = &self.model.borrow().prompt {
+            self.draw_prompt(prompt, msg);
+        }
         stdout().flush().unwrap();
     }
 }
+
+/// Length in bytes of the longest start of `text` that fits in `width`
+/// screen columns. This is always at least one character, so that a
+/// character wider than the space left still moves wrapping along.
+fn fitting_len(text: &str, width: usize) -> usize {
+    let mut used = 0;
+    for (idx, c) in text.char_indices() {
+        used += c.width().unwrap_or(0);
+        if used > width && idx > 0 {
+            return idx;
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::HeadlessBackend;
+
+    #[test]
+    fn overlays_are_skipped_on_a_terminal_too_small_for_their_border() {
+        let model = Rc::new(RefCell::new(Model::new()));
+        let backend = Rc::new(HeadlessBackend::new(80, 24));
+        let view = TerminalView::new(model, backend.clone());
+        view.update_size();
+        let lines = vec![String::from("popup")];
+        let id = view.open_overlay(Overlay::new(lines, Placement::Center));
+        for (cols, rows) in [(80, 2), (80, 3), (1, 24), (0, 0)] {
+            backend.resize(cols, rows);
+            view.update_size();
+            view.draw_overlays();
+            let overlays = view.overlays.borrow();
+            assert_eq!(view.overlay_rect(&overlays[0].1), None, "{}x{}", cols, rows);
+            drop(overlays);
+            assert_eq!(view.overlay_at(0, 0), None, "{}x{}", cols, rows);
+            assert_eq!(view.overlay_area(id), Some(Rect::default()));
+        }
+
+        // Once there is room again it is drawn and found where it was drawn
+        backend.resize(80, 24);
+        view.update_size();
+        view.draw_overlays();
+        let area = view.overlay_area(id).unwrap();
+        assert_eq!((area.width, area.height), (7, 3));
+        assert_eq!(view.overlay_at(area.x, area.y), Some((id, None)));
+        assert_eq!(view.overlay_at(area.x + 1, area.y + 1), Some((id, Some(0))));
+        assert_eq!(view.overlay_at(area.x + 7, area.y + 1), None);
+    }
+}
@@ -0,0 +1,429 @@
+use crate::syntax::syntaxengine::HLMode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use termion::color;
+
+const THEME_PATH: &str = "src/themes/";
+
+// Name of the theme built into the editor, which needs no theme file
+pub const DEFAULT_THEME: &str = "default";
+
+/// How many colors the terminal can display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorDepth {
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    /// Guesses the terminal's capability from the environment the way most
+    /// terminal programs do, since terminals can't be asked directly
+    pub fn detect() -> ColorDepth {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return ColorDepth::TrueColor;
+        }
+        let term = std::env::var("TERM").unwrap_or_default();
+        if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+}
+
+/// A color as written in a theme file: a color name, an index into the
+/// 256-color palette, or a "#rrggbb" hex value
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(try_from = "RawColor")]
+pub enum ThemeColor {
+    // The terminal's own foreground or background
+    #[default]
+    Reset,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawColor {
+    Index(u8),
+    Name(String),
+}
+
+// Names of the 16 basic colors, in palette order
+const COLOR_NAMES: [&str; 16] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    "lightblack", "lightred", "lightgreen", "lightyellow",
+    "lightblue", "lightmagenta", "lightcyan", "lightwhite",
+];
+
+// The usual xterm values of the 16 basic colors
+const BASIC_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+// Levels of each channel in the 6x6x6 color cube of the 256-color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+impl TryFrom<RawColor> for ThemeColor {
+    type Error = String;
+
+    fn try_from(raw: RawColor) -> Result<ThemeColor, String> {
+        let name = match raw {
+            RawColor::Index(idx) => return Ok(ThemeColor::Indexed(idx)),
+            RawColor::Name(name) => name.to_lowercase(),
+        };
+        if name == "default" || name == "reset" {
+            return Ok(ThemeColor::Reset);
+        }
+        if let Some(idx) = COLOR_NAMES.iter().position(|n| *n == name) {
+            return Ok(ThemeColor::Indexed(idx as u8));
+        }
+        if let Some(hex) = name.strip_prefix('#') {
+            if hex.len() == 6 && hex.is_ascii() {
+                let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+                if let (Ok(r), Ok(g), Ok(b)) = (channel(0), channel(2), channel(4)) {
+                    return Ok(ThemeColor::Rgb(r, g, b));
+                }
+            }
+        }
+        Err(format!("unknown color '{}'", name))
+    }
+}
+
+impl ThemeColor {
+    /// The closest color a terminal with the given depth can show
+    fn downgrade(self, depth: ColorDepth) -> ThemeColor {
+        match (self, depth) {
+            (ThemeColor::Rgb(r, g, b), ColorDepth::Ansi256) => {
+                ThemeColor::Indexed(nearest_256(r, g, b))
+            }
+            (ThemeColor::Rgb(r, g, b), ColorDepth::Ansi16) => {
+                ThemeColor::Indexed(nearest_16(r, g, b))
+            }
+            (ThemeColor::Indexed(idx), ColorDepth::Ansi16) if idx >= 16 => {
+                let (r, g, b) = palette_rgb(idx);
+                ThemeColor::Indexed(nearest_16(r, g, b))
+            }
+            (color, _) => color,
+        }
+    }
+
+    /// Escape sequence setting this as the foreground color
+    pub fn fg(self, depth: ColorDepth) -> String {
+        match self.downgrade(depth) {
+            ThemeColor::Reset => format!("{}", color::Fg(color::Reset)),
+            // Plain 16-color terminals may not understand palette indices
+            ThemeColor::Indexed(idx) if depth == ColorDepth::Ansi16 => sgr_16(idx, 30),
+            ThemeColor::Indexed(idx) => format!("{}", color::Fg(color::AnsiValue(idx))),
+            ThemeColor::Rgb(r, g, b) => format!("{}", color::Fg(color::Rgb(r, g, b))),
+        }
+    }
+
+    /// Escape sequence setting this as the background color
+    pub fn bg(self, depth: ColorDepth) -> String {
+        match self.downgrade(depth) {
+            ThemeColor::Reset => format!("{}", color::Bg(color::Reset)),
+            ThemeColor::Indexed(idx) if depth == ColorDepth::Ansi16 => sgr_16(idx, 40),
+            ThemeColor::Indexed(idx) => format!("{}", color::Bg(color::AnsiValue(idx))),
+            ThemeColor::Rgb(r, g, b) => format!("{}", color::Bg(color::Rgb(r, g, b))),
+        }
+    }
+}
+
+/// SGR sequence for one of the 16 basic colors, where `base` is 30 for the
+/// foreground and 40 for the background. Bright colors live 60 codes higher.
+fn sgr_16(idx: u8, base: u8) -> String {
+    let code = if idx < 8 { base + idx } else { base + 60 + idx - 8 };
+    format!("\x1b[{}m", code)
+}
+
+/// Approximate RGB value of a 256-color palette entry
+fn palette_rgb(idx: u8) -> (u8, u8, u8) {
+    match idx {
+        0..=15 => BASIC_RGB[idx as usize],
+        16..=231 => {
+            let i = idx as usize - 16;
+            (CUBE_LEVELS[i / 36], CUBE_LEVELS[(i / 6) % 6], CUBE_LEVELS[i % 6])
+        }
+        _ => {
+            let level = 8 + 10 * (idx - 232);
+            (level, level, level)
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// Nearest entry of the color cube or grayscale ramp. The 16 basic colors
+/// are skipped since terminals are free to change them.
+fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| {
+        CUBE_LEVELS
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, l)| (**l as i32 - v as i32).abs())
+            .map_or(0, |(i, _)| i as u8)
+    };
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let gray_level = ((r as u32 + g as u32 + b as u32) / 3).clamp(8, 238);
+    let gray = 232 + ((gray_level - 8) / 10) as u8;
+
+    if distance(palette_rgb(gray), (r, g, b)) < distance(palette_rgb(cube), (r, g, b)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+fn nearest_16(r: u8, g: u8, b: u8) -> u8 {
+    (0..16u8)
+        .min_by_key(|idx| distance(BASIC_RGB[*idx as usize], (r, g, b)))
+        .unwrap_or(0)
+}
+
+/// Foreground and background of a UI element. A color left out of the theme
+/// file is the terminal's default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub struct Style {
+    #[serde(default)]
+    pub fg: ThemeColor,
+    #[serde(default)]
+    pub bg: ThemeColor,
+}
+
+impl Style {
+    fn new(fg: ThemeColor, bg: ThemeColor) -> Style {
+        Style { fg, bg }
+    }
+}
+
+/// Colors for syntax highlighting and every part of the editor's UI. Elements
+/// a theme file leaves out keep the colors of the default theme.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    #[serde(skip)]
+    pub name: String,
+    syntax: HashMap<HLMode, ThemeColor>,
+    pub selection: Style,
+    pub search_match: Style,
+    // Background of the row the cursor is on, when enabled
+    pub cursorline: Style,
+    pub status_bar: Style,
+    pub message: Style,
+    pub warning: Style,
+    pub error: Style,
+    // Line numbers, and the number of the cursor row
+    pub gutter: Style,
+    pub gutter_current: Style,
+    pub sign_error: Style,
+    pub sign_warning: Style,
+    pub sign_info: Style,
+    pub wrap_indicator: Style,
+    // Text past the max_line_length column
+    pub overlength: Style,
+    // The bracket matching the one at the cursor, and that one too
+    pub matching_bracket: Style,
+    // Popup lists, and the entry selected in one
+    pub popup: Style,
+    pub popup_selected: Style,
+    pub popup_border: Style,
+    // Characters of a popup's entries that match what was searched for
+    pub popup_match: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        use ThemeColor::{Indexed, Reset};
+
+        let syntax = [
+            (HLMode::Normal, Reset),
+            (HLMode::Symbol, Indexed(7)),
+            (HLMode::Number, Indexed(13)),
+            (HLMode::String, Indexed(2)),
+            (HLMode::Comment, Indexed(8)),
+            (HLMode::Type, Indexed(3)),
+            (HLMode::Call, Indexed(12)),
+            (HLMode::Keyword, Indexed(5)),
+            (HLMode::Special, Indexed(6)),
+            (HLMode::Reserved, Indexed(1)),
+        ];
+        Theme {
+            name: String::from(DEFAULT_THEME),
+            syntax: syntax.iter().copied().collect(),
+            selection: Style::new(Reset, Indexed(12)),
+            search_match: Style::new(Indexed(0), Indexed(3)),
+            cursorline: Style::new(Reset, Indexed(236)),
+            status_bar: Style::new(Indexed(0), Indexed(7)),
+            message: Style::new(Indexed(7), Reset),
+            warning: Style::new(Indexed(3), Reset),
+            error: Style::new(Indexed(1), Reset),
+            gutter: Style::new(Indexed(8), Reset),
+            gutter_current: Style::new(Indexed(3), Reset),
+            sign_error: Style::new(Indexed(1), Reset),
+            sign_warning: Style::new(Indexed(3), Reset),
+            sign_info: Style::new(Indexed(6), Reset),
+            wrap_indicator: Style::new(Indexed(8), Reset),
+            overlength: Style::new(Reset, Indexed(88)),
+            matching_bracket: Style::new(Indexed(0), Indexed(6)),
+            popup: Style::new(Indexed(15), Indexed(238)),
+            popup_selected: Style::new(Indexed(0), Indexed(12)),
+            popup_border: Style::new(Indexed(8), Indexed(238)),
+            popup_match: Style::new(Indexed(11), Reset),
+        }
+    }
+}
+
+impl Theme {
+    /// Loads a theme by name from the themes directory
+    pub fn load(name: &str) -> io::Result<Theme> {
+        if name == DEFAULT_THEME {
+            return Ok(Theme::default());
+        }
+        let path = themes_dir().join(format!("{}.yaml", name));
+        if !path.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown colorscheme '{}'", name),
+            ));
+        }
+        let mut theme = read_theme_file(&path)?;
+        theme.name = name.to_string();
+        // A partial syntax table only overrides the modes it lists
+        for (mode, color) in Theme::default().syntax {
+            theme.syntax.entry(mode).or_insert(color);
+        }
+        Ok(theme)
+    }
+
+    /// Color of text highlighted with the given mode
+    pub fn syntax_color(&self, mode: HLMode) -> ThemeColor {
+        self.syntax.get(&mode).copied().unwrap_or_default()
+    }
+}
+
+/// Directory the bundled themes are read from
+pub fn themes_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(THEME_PATH)
+}
+
+pub fn read_theme_file(filename: &Path) -> io::Result<Theme> {
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    serde_yaml::from_reader(reader).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", filename.display(), err),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ThemeColor::{Indexed, Reset, Rgb};
+
+    #[test]
+    fn truecolor_terminals_get_colors_as_they_are() {
+        let depth = ColorDepth::TrueColor;
+        assert_eq!(Rgb(255, 128, 0).fg(depth), "\x1b[38;2;255;128;0m");
+        assert_eq!(Rgb(1, 2, 3).bg(depth), "\x1b[48;2;1;2;3m");
+        assert_eq!(Indexed(200).fg(depth), "\x1b[38;5;200m");
+        assert_eq!(Indexed(3).bg(depth), "\x1b[48;5;3m");
+        assert_eq!(Reset.fg(depth), "\x1b[39m");
+        assert_eq!(Reset.bg(depth), "\x1b[49m");
+    }
+
+    #[test]
+    fn rgb_colors_come_down_to_the_256_color_palette() {
+        let depth = ColorDepth::Ansi256;
+        assert_eq!(Rgb(255, 0, 0).fg(depth), "\x1b[38;5;196m");
+        assert_eq!(Rgb(0, 95, 135).bg(depth), "\x1b[48;5;24m");
+        // Grays go to the grayscale ramp rather than the nearest cube color
+        assert_eq!(Rgb(128, 128, 128).fg(depth), "\x1b[38;5;244m");
+        assert_eq!(Rgb(0, 0, 0).fg(depth), "\x1b[38;5;16m");
+        // Palette colors are left alone
+        assert_eq!(Indexed(200).fg(depth), "\x1b[38;5;200m");
+        assert_eq!(Indexed(9).bg(depth), "\x1b[48;5;9m");
+        assert_eq!(Reset.bg(depth), "\x1b[49m");
+    }
+
+    #[test]
+    fn everything_comes_down_to_the_16_basic_colors() {
+        let depth = ColorDepth::Ansi16;
+        assert_eq!(Rgb(250, 5, 5).fg(depth), "\x1b[91m");
+        assert_eq!(Rgb(0, 0, 230).bg(depth), "\x1b[44m");
+        assert_eq!(Rgb(0, 0, 0).fg(depth), "\x1b[30m");
+        assert_eq!(Indexed(196).fg(depth), "\x1b[91m");
+        assert_eq!(Indexed(244).bg(depth), "\x1b[100m");
+        assert_eq!(Indexed(7).fg(depth), "\x1b[37m");
+        assert_eq!(Indexed(15).bg(depth), "\x1b[107m");
+        assert_eq!(Reset.fg(depth), "\x1b[39m");
+    }
+
+    fn color(yaml: &str) -> Result<ThemeColor, String> {
+        serde_yaml::from_str(yaml).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn colors_are_names_indices_or_hex() {
+        assert_eq!(color("red"), Ok(Indexed(1)));
+        assert_eq!(color("LightBlue"), Ok(Indexed(12)));
+        assert_eq!(color("208"), Ok(Indexed(208)));
+        assert_eq!(color("'#FF8000'"), Ok(Rgb(255, 128, 0)));
+        assert_eq!(color("default"), Ok(Reset));
+        assert!(color("purple").unwrap_err().contains("unknown color 'purple'"));
+        assert!(color("'#ff80'").is_err());
+        assert!(color("'#ggg000'").is_err());
+    }
+
+    #[test]
+    fn theme_files_override_the_elements_they_list() {
+        let path = std::env::temp_dir().join(format!("ginkgo-theme-{}.yaml", std::process::id()));
+        let text = "syntax:\n  Comment: '#808080'\nselection: { bg: blue }\npopup: { fg: 231 }\n";
+        std::fs::write(&path, text).unwrap();
+        let theme = read_theme_file(&path);
+        std::fs::write(&path, "selection: { fg: nope }\n").unwrap();
+        let bad = read_theme_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let theme = theme.unwrap();
+        let default = Theme::default();
+        assert_eq!(theme.syntax_color(HLMode::Comment), Rgb(128, 128, 128));
+        assert_eq!(theme.selection, Style::new(Reset, Indexed(4)));
+        assert_eq!(theme.popup, Style::new(Indexed(231), Reset));
+        assert_eq!(theme.status_bar, default.status_bar);
+        let err = bad.err().unwrap().to_string();
+        assert!(err.starts_with(&path.display().to_string()), "{}", err);
+        assert!(err.contains("unknown color 'nope'"), "{}", err);
+    }
+
+    #[test]
+    fn bundled_themes_load() {
+        for entry in std::fs::read_dir(themes_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let theme = Theme::load(name).unwrap();
+            // Modes a theme leaves out keep the default colors
+            for mode in [HLMode::Normal, HLMode::Comment, HLMode::Reserved] {
+                assert!(theme.syntax.contains_key(&mode), "{} has no {:?}", name, mode);
+            }
+        }
+        assert_eq!(Theme::load("default").unwrap().name, "default");
+        let err = Theme::load("no-such-theme").err().unwrap();
+        assert_eq!(err.to_string(), "unknown colorscheme 'no-such-theme'");
+    }
+}
@@ -0,0 +1,40 @@
+// Fixture for the C++ syntax definition
+#include <iostream>
+#include <vector>
+#include "config.h"
+
+namespace ginkgo {
+
+/* A block comment
+   spanning lines */
+template <typename T>
+class Buffer : public Base {
+public:
+    explicit Buffer(std::size_t capacity) : data_(capacity) {}
+    virtual ~Buffer() override = default;
+
+    constexpr bool empty() const noexcept { return size_ == 0; }
+
+    void push(const T &value) {
+        if (size_ >= data_.size()) {
+            throw std::out_of_range("buffer is full\n");
+        }
+        data_[size_++] = value;
+    }
+
+private:
+    std::vector<T> data_;
+    unsigned long size_ = 0x1F'FFul;
+};
+
+} // namespace ginkgo
+
+int main(int argc, char **argv) {
+    auto raw = R"(no \escapes "here")";
+    char c = '\'';
+    auto *buf = new ginkgo::Buffer<int>(16);
+    buf->push(static_cast<int>(3.14f));
+    std::cout << raw << c << std::endl;
+    delete buf;
+    return nullptr == argv ? 1 : 0;
+}
@@ -0,0 +1,34 @@
+#!/usr/bin/env python3
+# Fixture for the Python syntax definition
+import os
+from collections import defaultdict
+
+
+@dataclass
+class Counter(object):
+    """Counts words.
+
+    The docstring spans several lines.
+    """
+
+    def __init__(self, name: str) -> None:
+        self.name = name
+        self.counts = defaultdict(int)
+
+    def add(self, word):
+        if word is not None and len(word) > 0:
+            self.counts[word] += 1
+        return self.counts.get(word, 0x0)
+
+
+def main():
+    counter = Counter('words')
+    for word in f"a b\tc {os.sep}".split():
+        counter.add(word)
+    print(counter.counts, 3.5e-2, True)
+    raw = r'C:\path'
+    lambda x: x ** 2
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,40 @@
+// Fixture for the Rust syntax definition
+use std::collections::HashMap;
+
+/* Block comments /* nest */
+   and span lines */
+#[derive(Clone, Debug)]
+pub struct Counter<'a> {
+    name: &'a str,
+    counts: HashMap<String, usize>,
+}
+
+impl<'a> Counter<'a> {
+    pub fn new(name: &'a str) -> Counter<'a> {
+        Counter {
+            name,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Adds one to the count of `word`
+    fn add(&mut self, word: &str) -> Option<usize> {
+        let count = self.counts.entry(word.to_string()).or_insert(0);
+        *count += 1_000;
+        if *count > 0xFF { None } else { Some(*count) }
+    }
+}
+
+fn main() {
+    let mut counter = Counter::new("words");
+    let text = "a multi-line
+string with an \"escape\"";
+    let raw = r#"raw "string""#;
+    let c = '\n';
+    for word in text.split_whitespace() {
+        counter.add(word);
+    }
+    println!("{} {} {} {:?}", counter.name, raw, c, 2.5e3f64);
+    let parsed = "42".parse::<u32>();
+    assert!(matches!(parsed, Ok(42)));
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# Fixture for the shell syntax definition
+set -eu
+
+greet() {
+    local name="${1:-world}"
+    echo "Hello, $name! (#$#)"
+}
+
+for file in *.txt; do
+    if [ -f "$file" ] && [ "$(wc -l < "$file")" -gt 10 ]; then
+        printf '%s is long\n' "$file"
+    fi
+done
+
+case "$1" in
+    start) greet "$USER" ;;
+    *) exit 1 ;;
+esac
+
+message="a string
+spanning lines"
+echo $message | grep --count 'lines' # trailing comment